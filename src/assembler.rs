@@ -0,0 +1,670 @@
+// Assembler for small SM83 programs in RGBDS syntax, mostly to write test programs.
+// Supports one instruction per line, `label:` definitions, `db` / `dw` directives and
+// `;` comments. Numbers are decimal, $hex, 0xhex or %binary, and can be added to or
+// subtracted from labels, e.g. `jr nz, .loop` or `ld hl, table + 2`. A `.local` label
+// belongs to the global label before it, and can be named `global.local` elsewhere.
+//
+// Instructions are parsed into the same `Instruction` the CPU decodes, and the opcode is
+// the byte `Instruction::from_byte` decodes into it, so both always agree
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cpu::instructions::{
+    ADDHLTarget, ArithmeticTarget, BitPosition, IncDecTarget, Indirect, Instruction, JumpTest, LoadByteSource,
+    LoadByteTarget, LoadType, LoadWordTarget, PrefixTarget, RSTLocation, StackTarget,
+};
+
+// Assemble a program, panicking with the error on invalid source. For tests
+#[macro_export]
+macro_rules! asm {
+    ($source:expr) => {
+        $crate::assembler::assemble($source).unwrap_or_else(|error| panic!("{}", error))
+    };
+    ($source:expr, $origin:expr) => {
+        $crate::assembler::assemble_at($source, $origin).unwrap_or_else(|error| panic!("{}", error))
+    };
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    // Line of the source, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// How the operand of an instruction is encoded after the opcode
+enum Immediate {
+    None,
+    Byte(String),
+    // Signed offset, `add sp, e8` and `ld hl, sp+e8`
+    Signed(String),
+    // Address in 0xFF00 - 0xFFFF of `ldh`, only the low byte is stored
+    High(String),
+    // Target of `jr`, stored as the offset from the next instruction
+    Relative(String),
+    Word(String),
+}
+
+enum Item {
+    Instruction(Instruction, Immediate),
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+impl Item {
+    fn length(&self) -> usize {
+        match self {
+            Item::Instruction(instruction, _) => instruction.length() as usize,
+            Item::Bytes(values) => values.len(),
+            Item::Words(values) => values.len() * 2,
+        }
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_at(source, 0)
+}
+
+// Assemble a program that is placed at `origin`, which is where its labels point
+pub fn assemble_at(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    // First pass, parse the lines and give every label its address
+    let mut items = Vec::new();
+    let mut labels = HashMap::new();
+    // The last global label, which the local labels after it belong to
+    let mut scope = String::new();
+    let mut address = origin as usize;
+    for (number, line) in source.lines().enumerate() {
+        let error = |message: String| AsmError { line: number + 1, message };
+        let mut line = line.split(';').next().unwrap_or_default().trim();
+
+        if let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                return Err(error(format!("invalid label '{}'", label)));
+            }
+            let label = if label.starts_with('.') {
+                format!("{}{}", scope, label)
+            } else {
+                scope = label.to_string();
+                scope.clone()
+            };
+            if labels.insert(label.clone(), address).is_some() {
+                return Err(error(format!("label '{}' defined twice", label)));
+            }
+            line = rest.trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let item = parse_line(line).map_err(error)?;
+        address += item.length();
+        items.push((number + 1, scope.clone(), item));
+    }
+
+    // Second pass, encode with the labels known
+    let mut bytes = Vec::new();
+    for (line, scope, item) in items {
+        let error = |message: String| AsmError { line, message };
+        let address = origin as usize + bytes.len();
+        let value = |expression: &str| evaluate(expression, &labels, &scope).map_err(error);
+
+        match item {
+            Item::Bytes(values) => {
+                for expression in values {
+                    bytes.push(byte(value(&expression)?).map_err(error)?);
+                }
+            }
+            Item::Words(values) => {
+                for expression in values {
+                    bytes.extend_from_slice(&word(value(&expression)?).map_err(error)?.to_le_bytes());
+                }
+            }
+            Item::Instruction(instruction, immediate) => {
+                let (prefixed, opcode) = opcode(&instruction).ok_or_else(|| error("invalid operands".to_string()))?;
+                if prefixed {
+                    bytes.push(0xCB);
+                }
+                bytes.push(opcode);
+
+                match immediate {
+                    Immediate::None => {}
+                    Immediate::Byte(expression) => bytes.push(byte(value(&expression)?).map_err(error)?),
+                    Immediate::Signed(expression) => bytes.push(signed(value(&expression)?).map_err(error)?),
+                    Immediate::High(expression) => {
+                        let target = value(&expression)?;
+                        match target {
+                            0x00..=0xFF | 0xFF00..=0xFFFF => bytes.push(target as u8),
+                            _ => return Err(error(format!("${:x} is outside of $ff00 - $ffff", target))),
+                        }
+                    }
+                    Immediate::Relative(expression) => {
+                        let offset = value(&expression)? - (address as i64 + 2);
+                        let too_far = |_| error(format!("jump of {} bytes is too far for jr", offset));
+                        bytes.push(signed(offset).map_err(too_far)?);
+                    }
+                    Immediate::Word(expression) => {
+                        bytes.extend_from_slice(&word(value(&expression)?).map_err(error)?.to_le_bytes())
+                    }
+                }
+                // STOP is followed by a padding byte
+                if instruction == Instruction::STOP {
+                    bytes.push(0x00);
+                }
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+// Opcode the CPU decodes into the instruction, and whether it has the 0xCB prefix
+fn opcode(instruction: &Instruction) -> Option<(bool, u8)> {
+    [false, true].into_iter().find_map(|prefixed| {
+        (0..=0xFF)
+            .find(|&byte| Instruction::from_byte(byte, prefixed) == Some(*instruction))
+            .map(|byte| (prefixed, byte))
+    })
+}
+
+fn byte(value: i64) -> Result<u8, String> {
+    match value {
+        -0x80..=0xFF => Ok(value as u8),
+        _ => Err(format!("{} doesn't fit in a byte", value)),
+    }
+}
+
+fn signed(value: i64) -> Result<u8, String> {
+    match value {
+        -0x80..=0x7F => Ok(value as i8 as u8),
+        _ => Err(format!("{} doesn't fit in a signed byte", value)),
+    }
+}
+
+fn word(value: i64) -> Result<u16, String> {
+    match value {
+        -0x8000..=0xFFFF => Ok(value as u16),
+        _ => Err(format!("{} doesn't fit in a word", value)),
+    }
+}
+
+// Sum of numbers and labels, like `table + $10` or `end - start`
+fn evaluate(expression: &str, labels: &HashMap<String, usize>, scope: &str) -> Result<i64, String> {
+    let mut total = 0;
+    let mut sign = 1;
+    let mut term = String::new();
+    let mut terms = Vec::new();
+    for character in expression.chars().chain(std::iter::once('\0')) {
+        match character {
+            '+' | '-' | '\0' if !term.trim().is_empty() || character == '\0' => {
+                terms.push((sign, std::mem::take(&mut term)));
+                sign = if character == '-' { -1 } else { 1 };
+            }
+            '-' => sign = -sign,
+            '+' => {}
+            _ => term.push(character),
+        }
+    }
+
+    for (sign, term) in terms {
+        let term = term.trim();
+        let value = match number(term) {
+            Some(value) => value,
+            None => {
+                let label = if term.starts_with('.') { format!("{}{}", scope, term) } else { term.to_string() };
+                *labels.get(&label).ok_or_else(|| format!("unknown label or number '{}'", term))? as i64
+            }
+        };
+        total += sign * value;
+    }
+    Ok(total)
+}
+
+fn number(term: &str) -> Option<i64> {
+    if let Some(hex) = term.strip_prefix('$').or_else(|| term.strip_prefix("0x")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = term.strip_prefix('%') {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        term.parse().ok()
+    }
+}
+
+fn parse_line(line: &str) -> Result<Item, String> {
+    let (mnemonic, operands) = match line.split_once(char::is_whitespace) {
+        Some((mnemonic, operands)) => (mnemonic, operands.trim()),
+        None => (line, ""),
+    };
+    let mnemonic = mnemonic.to_ascii_lowercase();
+    let operands: Vec<String> = if operands.is_empty() {
+        Vec::new()
+    } else {
+        operands.split(',').map(|operand| operand.trim().to_string()).collect()
+    };
+
+    match mnemonic.as_str() {
+        "db" => return Ok(Item::Bytes(operands)),
+        "dw" => return Ok(Item::Words(operands)),
+        _ => {}
+    }
+
+    let operands: Vec<Operand> = operands.iter().map(|operand| Operand::parse(operand)).collect();
+    let (instruction, immediate) = parse_instruction(&mnemonic, &operands)?;
+    Ok(Item::Instruction(instruction, immediate))
+}
+
+// An operand as written, before knowing which instruction it belongs to
+#[derive(Clone, PartialEq)]
+enum Operand {
+    Register(String),
+    // `[hl]`, `[bc]`, `[hl+]`, `[c]`...
+    Pointer(String),
+    // `[expression]`
+    Address(String),
+    // `sp+e8` or `sp-e8`
+    SpOffset(String),
+    Value(String),
+}
+
+const REGISTERS: [&str; 14] = ["a", "b", "c", "d", "e", "h", "l", "af", "bc", "de", "hl", "sp", "nz", "nc"];
+
+impl Operand {
+    fn parse(text: &str) -> Operand {
+        let lower: String = text.to_ascii_lowercase().chars().filter(|c| !c.is_whitespace()).collect();
+        if REGISTERS.contains(&lower.as_str()) || lower == "z" {
+            return Operand::Register(lower);
+        }
+        if let Some(inner) = lower.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+            let inner = match inner {
+                "hli" => "hl+",
+                "hld" => "hl-",
+                "$ff00+c" | "0xff00+c" => "c",
+                inner => inner,
+            };
+            if ["hl", "bc", "de", "hl+", "hl-", "c"].contains(&inner) {
+                return Operand::Pointer(inner.to_string());
+            }
+            let original = text.trim();
+            return Operand::Address(original[1..original.len() - 1].trim().to_string());
+        }
+        if let Some(offset) = lower.strip_prefix("sp") {
+            if offset.starts_with('+') || offset.starts_with('-') {
+                return Operand::SpOffset(offset.to_string());
+            }
+        }
+        Operand::Value(text.trim().to_string())
+    }
+}
+
+fn parse_instruction(mnemonic: &str, operands: &[Operand]) -> Result<(Instruction, Immediate), String> {
+    use Operand::*;
+    let invalid = || format!("invalid operands for {}", mnemonic);
+    let plain = |instruction| Ok((instruction, Immediate::None));
+
+    match (mnemonic, operands) {
+        ("nop", []) => plain(Instruction::NOP),
+        ("halt", []) => plain(Instruction::HALT),
+        ("stop", []) => plain(Instruction::STOP),
+        ("di", []) => plain(Instruction::DI),
+        ("ei", []) => plain(Instruction::EI),
+        ("daa", []) => plain(Instruction::DAA),
+        ("cpl", []) => plain(Instruction::CPL),
+        ("ccf", []) => plain(Instruction::CCF),
+        ("scf", []) => plain(Instruction::SCF),
+        ("rra", []) => plain(Instruction::RRA),
+        ("rla", []) => plain(Instruction::RLA),
+        ("rrca", []) => plain(Instruction::RRCA),
+        ("rlca", []) => plain(Instruction::RLCA),
+        ("reti", []) => plain(Instruction::RETI),
+
+        ("add", [Register(hl), source]) if hl == "hl" => {
+            let target = match source {
+                Register(name) if name == "bc" => ADDHLTarget::BC,
+                Register(name) if name == "de" => ADDHLTarget::DE,
+                Register(name) if name == "hl" => ADDHLTarget::HL,
+                Register(name) if name == "sp" => ADDHLTarget::SP,
+                _ => return Err(invalid()),
+            };
+            plain(Instruction::ADDHL(target))
+        }
+        ("add", [Register(sp), Value(offset)]) if sp == "sp" => {
+            Ok((Instruction::ADDSP, Immediate::Signed(offset.clone())))
+        }
+        // `a` can be left out, `add b` is `add a, b`
+        ("add" | "adc" | "sub" | "sbc" | "and" | "xor" | "or" | "cp", [_] | [Register(_), _]) => {
+            let source = match operands {
+                [source] => source,
+                [Register(a), source] if a == "a" => source,
+                _ => return Err(invalid()),
+            };
+            let (target, immediate) = arithmetic_target(source).ok_or_else(invalid)?;
+            let instruction = match mnemonic {
+                "add" => Instruction::ADD(target),
+                "adc" => Instruction::ADC(target),
+                "sub" => Instruction::SUB(target),
+                "sbc" => Instruction::SBC(target),
+                "and" => Instruction::AND(target),
+                "xor" => Instruction::XOR(target),
+                "or" => Instruction::OR(target),
+                _ => Instruction::CP(target),
+            };
+            Ok((instruction, immediate))
+        }
+        ("inc" | "dec", [operand]) => {
+            let target = match operand {
+                Register(name) => match name.as_str() {
+                    "a" => IncDecTarget::A,
+                    "b" => IncDecTarget::B,
+                    "c" => IncDecTarget::C,
+                    "d" => IncDecTarget::D,
+                    "e" => IncDecTarget::E,
+                    "h" => IncDecTarget::H,
+                    "l" => IncDecTarget::L,
+                    "bc" => IncDecTarget::BC,
+                    "de" => IncDecTarget::DE,
+                    "hl" => IncDecTarget::HL,
+                    "sp" => IncDecTarget::SP,
+                    _ => return Err(invalid()),
+                },
+                Pointer(name) if name == "hl" => IncDecTarget::HLI,
+                _ => return Err(invalid()),
+            };
+            plain(if mnemonic == "inc" { Instruction::INC(target) } else { Instruction::DEC(target) })
+        }
+
+        ("ld", [target, source]) => parse_load(target, source).ok_or_else(invalid),
+        ("ldh", [Register(a), Address(address)]) if a == "a" => {
+            Ok((Instruction::LD(LoadType::AFromByteAddress), Immediate::High(address.clone())))
+        }
+        ("ldh", [Address(address), Register(a)]) if a == "a" => {
+            Ok((Instruction::LD(LoadType::ByteAddressFromA), Immediate::High(address.clone())))
+        }
+        ("ldh", [Register(a), Pointer(c)]) if a == "a" && c == "c" => {
+            plain(Instruction::LD(LoadType::AFromIndirect(Indirect::LastByteIndirect)))
+        }
+        ("ldh", [Pointer(c), Register(a)]) if a == "a" && c == "c" => {
+            plain(Instruction::LD(LoadType::IndirectFromA(Indirect::LastByteIndirect)))
+        }
+
+        ("jp", [Register(hl)] | [Pointer(hl)]) if hl == "hl" => plain(Instruction::JPI),
+        ("jp" | "call" | "jr", [Value(target)]) => {
+            Ok((jump(mnemonic, JumpTest::Always), jump_immediate(mnemonic, target)))
+        }
+        ("jp" | "call" | "jr", [Register(condition), Value(target)]) => {
+            let test = jump_test(condition).ok_or_else(invalid)?;
+            Ok((jump(mnemonic, test), jump_immediate(mnemonic, target)))
+        }
+        ("ret", []) => plain(Instruction::RET(JumpTest::Always)),
+        ("ret", [Register(condition)]) => plain(Instruction::RET(jump_test(condition).ok_or_else(invalid)?)),
+        ("rst", [Value(vector)]) => {
+            let location = match number(vector) {
+                Some(0x00) => RSTLocation::X00,
+                Some(0x08) => RSTLocation::X08,
+                Some(0x10) => RSTLocation::X10,
+                Some(0x18) => RSTLocation::X18,
+                Some(0x20) => RSTLocation::X20,
+                Some(0x28) => RSTLocation::X28,
+                Some(0x30) => RSTLocation::X30,
+                Some(0x38) => RSTLocation::X38,
+                _ => return Err(format!("rst to {}, only $00, $08 ... $38 exist", vector)),
+            };
+            plain(Instruction::RST(location))
+        }
+        ("push" | "pop", [Register(name)]) => {
+            let target = match name.as_str() {
+                "af" => StackTarget::AF,
+                "bc" => StackTarget::BC,
+                "de" => StackTarget::DE,
+                "hl" => StackTarget::HL,
+                _ => return Err(invalid()),
+            };
+            plain(if mnemonic == "push" { Instruction::PUSH(target) } else { Instruction::POP(target) })
+        }
+
+        ("bit" | "res" | "set", [Value(bit), operand]) => {
+            let position = match number(bit) {
+                Some(0) => BitPosition::B0,
+                Some(1) => BitPosition::B1,
+                Some(2) => BitPosition::B2,
+                Some(3) => BitPosition::B3,
+                Some(4) => BitPosition::B4,
+                Some(5) => BitPosition::B5,
+                Some(6) => BitPosition::B6,
+                Some(7) => BitPosition::B7,
+                _ => return Err(format!("bit {} doesn't exist, bits go from 0 to 7", bit)),
+            };
+            let target = prefix_target(operand).ok_or_else(invalid)?;
+            plain(match mnemonic {
+                "bit" => Instruction::BIT(target, position),
+                "res" => Instruction::RES(target, position),
+                _ => Instruction::SET(target, position),
+            })
+        }
+        ("srl" | "rr" | "rl" | "rrc" | "rlc" | "sra" | "sla" | "swap", [operand]) => {
+            let target = prefix_target(operand).ok_or_else(invalid)?;
+            plain(match mnemonic {
+                "srl" => Instruction::SRL(target),
+                "rr" => Instruction::RR(target),
+                "rl" => Instruction::RL(target),
+                "rrc" => Instruction::RRC(target),
+                "rlc" => Instruction::RLC(target),
+                "sra" => Instruction::SRA(target),
+                "sla" => Instruction::SLA(target),
+                _ => Instruction::SWAP(target),
+            })
+        }
+
+        (
+            "nop" | "halt" | "stop" | "di" | "ei" | "daa" | "cpl" | "ccf" | "scf" | "rra" | "rla" | "rrca" | "rlca"
+            | "reti" | "add" | "adc" | "sub" | "sbc" | "and" | "xor" | "or" | "cp" | "inc" | "dec" | "ld" | "ldh"
+            | "jp" | "jr" | "call" | "ret" | "rst" | "push" | "pop" | "bit" | "res" | "set" | "srl" | "rr" | "rl"
+            | "rrc" | "rlc" | "sra" | "sla" | "swap",
+            _,
+        ) => Err(invalid()),
+        _ => Err(format!("unknown instruction '{}'", mnemonic)),
+    }
+}
+
+fn parse_load(target: &Operand, source: &Operand) -> Option<(Instruction, Immediate)> {
+    use Operand::*;
+    let load = |load_type| Some((Instruction::LD(load_type), Immediate::None));
+
+    match (target, source) {
+        (Register(sp), Register(hl)) if sp == "sp" && hl == "hl" => load(LoadType::SPFromHL),
+        (Register(hl), SpOffset(offset)) if hl == "hl" => {
+            Some((Instruction::LD(LoadType::HLFromSPN), Immediate::Signed(offset.clone())))
+        }
+        (Address(address), Register(sp)) if sp == "sp" => {
+            Some((Instruction::LD(LoadType::IndirectFromSP), Immediate::Word(address.clone())))
+        }
+        (Register(name), Value(value)) if ["bc", "de", "hl", "sp"].contains(&name.as_str()) => {
+            let target = match name.as_str() {
+                "bc" => LoadWordTarget::BC,
+                "de" => LoadWordTarget::DE,
+                "hl" => LoadWordTarget::HL,
+                _ => LoadWordTarget::SP,
+            };
+            Some((Instruction::LD(LoadType::Word(target)), Immediate::Word(value.clone())))
+        }
+        (Register(a), Pointer(name)) if a == "a" && name != "hl" => load(LoadType::AFromIndirect(indirect(name)?)),
+        (Pointer(name), Register(a)) if a == "a" && name != "hl" => load(LoadType::IndirectFromA(indirect(name)?)),
+        (Register(a), Address(address)) if a == "a" => Some((
+            Instruction::LD(LoadType::AFromIndirect(Indirect::WordIndirect)),
+            Immediate::Word(address.clone()),
+        )),
+        (Address(address), Register(a)) if a == "a" => Some((
+            Instruction::LD(LoadType::IndirectFromA(Indirect::WordIndirect)),
+            Immediate::Word(address.clone()),
+        )),
+        _ => {
+            let target = match target {
+                Register(name) => match name.as_str() {
+                    "a" => LoadByteTarget::A,
+                    "b" => LoadByteTarget::B,
+                    "c" => LoadByteTarget::C,
+                    "d" => LoadByteTarget::D,
+                    "e" => LoadByteTarget::E,
+                    "h" => LoadByteTarget::H,
+                    "l" => LoadByteTarget::L,
+                    _ => return None,
+                },
+                Pointer(name) if name == "hl" => LoadByteTarget::HLI,
+                _ => return None,
+            };
+            let (source, immediate) = match source {
+                Register(name) => (
+                    match name.as_str() {
+                        "a" => LoadByteSource::A,
+                        "b" => LoadByteSource::B,
+                        "c" => LoadByteSource::C,
+                        "d" => LoadByteSource::D,
+                        "e" => LoadByteSource::E,
+                        "h" => LoadByteSource::H,
+                        "l" => LoadByteSource::L,
+                        _ => return None,
+                    },
+                    Immediate::None,
+                ),
+                Pointer(name) if name == "hl" => (LoadByteSource::HLI, Immediate::None),
+                Value(value) => (LoadByteSource::D8, Immediate::Byte(value.clone())),
+                _ => return None,
+            };
+            Some((Instruction::LD(LoadType::Byte(target, source)), immediate))
+        }
+    }
+}
+
+fn indirect(name: &str) -> Option<Indirect> {
+    match name {
+        "bc" => Some(Indirect::BCIndirect),
+        "de" => Some(Indirect::DEIndirect),
+        "hl+" => Some(Indirect::HLIndirectPlus),
+        "hl-" => Some(Indirect::HLIndirectMinus),
+        "c" => Some(Indirect::LastByteIndirect),
+        _ => None,
+    }
+}
+
+fn arithmetic_target(operand: &Operand) -> Option<(ArithmeticTarget, Immediate)> {
+    let target = match operand {
+        Operand::Register(name) => match name.as_str() {
+            "a" => ArithmeticTarget::A,
+            "b" => ArithmeticTarget::B,
+            "c" => ArithmeticTarget::C,
+            "d" => ArithmeticTarget::D,
+            "e" => ArithmeticTarget::E,
+            "h" => ArithmeticTarget::H,
+            "l" => ArithmeticTarget::L,
+            _ => return None,
+        },
+        Operand::Pointer(name) if name == "hl" => ArithmeticTarget::HLI,
+        Operand::Value(value) => return Some((ArithmeticTarget::D8, Immediate::Byte(value.clone()))),
+        _ => return None,
+    };
+    Some((target, Immediate::None))
+}
+
+fn prefix_target(operand: &Operand) -> Option<PrefixTarget> {
+    match operand {
+        Operand::Register(name) => match name.as_str() {
+            "a" => Some(PrefixTarget::A),
+            "b" => Some(PrefixTarget::B),
+            "c" => Some(PrefixTarget::C),
+            "d" => Some(PrefixTarget::D),
+            "e" => Some(PrefixTarget::E),
+            "h" => Some(PrefixTarget::H),
+            "l" => Some(PrefixTarget::L),
+            _ => None,
+        },
+        Operand::Pointer(name) if name == "hl" => Some(PrefixTarget::HLI),
+        _ => None,
+    }
+}
+
+fn jump_test(condition: &str) -> Option<JumpTest> {
+    match condition {
+        "nz" => Some(JumpTest::NotZero),
+        "z" => Some(JumpTest::Zero),
+        "nc" => Some(JumpTest::NotCarry),
+        "c" => Some(JumpTest::Carry),
+        _ => None,
+    }
+}
+
+fn jump(mnemonic: &str, test: JumpTest) -> Instruction {
+    match mnemonic {
+        "jp" => Instruction::JP(test),
+        "jr" => Instruction::JR(test),
+        _ => Instruction::CALL(test),
+    }
+}
+
+fn jump_immediate(mnemonic: &str, target: &str) -> Immediate {
+    if mnemonic == "jr" {
+        Immediate::Relative(target.to_string())
+    } else {
+        Immediate::Word(target.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::decode;
+
+    #[test]
+    fn test_assemble() {
+        let program = asm!(
+            "start:
+                ld a, 5      ; load
+                add a, b
+                ld [hl+], a
+                ldh [$ff44], a
+            .loop:
+                dec c
+                jr nz, .loop
+                jp start
+                db 1, $ff
+                dw start + 2",
+            0x150
+        );
+        assert_eq!(
+            program,
+            [0x3E, 0x05, 0x80, 0x22, 0xE0, 0x44, 0x0D, 0x20, 0xFD, 0xC3, 0x50, 0x01, 0x01, 0xFF, 0x52, 0x01]
+        );
+
+        assert_eq!(assemble("ld [hl], [hl]").unwrap_err().message, "invalid operands");
+        assert_eq!(assemble("nop\njr far\nfar: nop").unwrap(), [0x00, 0x18, 0x00, 0x00]);
+        let locals = "first:\n.loop: jr .loop\nsecond:\n.loop: jr .loop\njp first.loop";
+        assert_eq!(assemble(locals).unwrap(), [0x18, 0xFE, 0x18, 0xFE, 0xC3, 0x00, 0x00]);
+        let error = AsmError { line: 2, message: "unknown instruction 'foo'".to_string() };
+        assert_eq!(assemble("\nfoo a").unwrap_err(), error);
+    }
+
+    // Every opcode disassembled and assembled again gives the same bytes
+    #[test]
+    fn test_round_trip() {
+        for prefixed in [false, true] {
+            for opcode in 0..=0xFF {
+                let bytes = if prefixed { vec![0xCB, opcode] } else { vec![opcode, 0x12, 0x34] };
+                let line = decode(&bytes, 0);
+                let Some(instruction) = line.instruction else { continue };
+                let mut expected = line.bytes.clone();
+                if instruction == Instruction::STOP {
+                    expected[1] = 0x00;
+                }
+
+                let text = instruction.to_asm(&line.bytes, 0x100);
+                assert_eq!(assemble_at(&text, 0x100), Ok(expected), "{}", text);
+            }
+        }
+    }
+}
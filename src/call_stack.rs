@@ -0,0 +1,219 @@
+// Shadow call stack, kept from the CALL, RST and interrupts entering functions and the RET
+// and RETI leaving them, for the backtrace of the debugger and the profiler. Every path of
+// calls is a node of a tree that adds up the cycles run in it, which gives the cycles of
+// each function and the folded stacks read by flamegraph.pl or inferno
+
+use std::collections::HashMap;
+
+use crate::symbols::Symbols;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallKind {
+    Call,
+    Restart,
+    Interrupt,
+}
+
+// Bank and address of the first instruction of a function
+pub type Function = (u16, u16);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    pub kind: CallKind,
+    pub function: Function,
+    pub return_address: u16,
+    // SP after the return address was pushed
+    sp: u16,
+    node: usize,
+}
+
+// Cycles and calls of a function over the whole run. Inclusive cycles count the functions
+// it called, exclusive ones only its own instructions
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FunctionProfile {
+    pub function: Function,
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+// A path of calls from the root, the root is node 0 and has no parent
+struct Node {
+    function: Function,
+    parent: Option<usize>,
+    calls: u64,
+    cycles: u64,
+}
+
+pub struct CallStack {
+    frames: Vec<Frame>,
+    nodes: Vec<Node>,
+    children: HashMap<(usize, Function), usize>,
+}
+
+impl CallStack {
+    // `root` is where the code runs from when no function was called, usually the entry point
+    pub fn new(root: u16, rom_bank: u16) -> CallStack {
+        let function = (bank_of(root, rom_bank), root);
+        CallStack {
+            frames: Vec::new(),
+            nodes: vec![Node { function, parent: None, calls: 1, cycles: 0 }],
+            children: HashMap::new(),
+        }
+    }
+
+    // Innermost last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    // Forget the frames, e.g. after loading a state, but keep the profile
+    pub fn clear_frames(&mut self) {
+        self.frames.clear();
+    }
+
+    pub(crate) fn add_cycles(&mut self, cycles: u32) {
+        let node = self.frames.last().map_or(0, |frame| frame.node);
+        self.nodes[node].cycles += cycles as u64;
+    }
+
+    pub(crate) fn enter(&mut self, kind: CallKind, address: u16, rom_bank: u16, return_address: u16, sp: u16) {
+        let function = (bank_of(address, rom_bank), address);
+        let parent = self.frames.last().map_or(0, |frame| frame.node);
+        let nodes = &mut self.nodes;
+        let node = *self.children.entry((parent, function)).or_insert_with(|| {
+            nodes.push(Node { function, parent: Some(parent), calls: 0, cycles: 0 });
+            nodes.len() - 1
+        });
+        self.nodes[node].calls += 1;
+        self.frames.push(Frame { kind, function, return_address, sp, node });
+    }
+
+    // A return popping its address from `sp`. Frames above it were left without returning,
+    // and returns to addresses pushed by the game itself don't leave any
+    pub(crate) fn leave(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|frame| frame.sp <= sp) {
+            self.frames.pop();
+        }
+    }
+
+    // The functions by inclusive cycles, most first. Recursive calls are only counted once
+    pub fn profile(&self) -> Vec<FunctionProfile> {
+        // Children always come after their parent
+        let mut totals: Vec<u64> = self.nodes.iter().map(|node| node.cycles).collect();
+        for (i, node) in self.nodes.iter().enumerate().rev() {
+            if let Some(parent) = node.parent {
+                totals[parent] += totals[i];
+            }
+        }
+
+        let mut functions: HashMap<Function, FunctionProfile> = HashMap::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            let profile = functions.entry(node.function).or_insert(FunctionProfile {
+                function: node.function,
+                calls: 0,
+                inclusive: 0,
+                exclusive: 0,
+            });
+            profile.calls += node.calls;
+            profile.exclusive += node.cycles;
+            if !self.ancestors(i).any(|ancestor| self.nodes[ancestor].function == node.function) {
+                profile.inclusive += totals[i];
+            }
+        }
+        let mut functions: Vec<FunctionProfile> = functions.into_values().collect();
+        functions.sort_by_key(|profile| (std::cmp::Reverse(profile.inclusive), profile.function));
+        functions
+    }
+
+    // One line per path of calls that ran code, `root;caller;callee cycles`
+    pub fn folded(&self, symbols: &Symbols) -> String {
+        let mut lines = Vec::new();
+        for (i, node) in self.nodes.iter().enumerate().filter(|(_, node)| node.cycles > 0) {
+            let mut path: Vec<usize> = self.ancestors(i).collect();
+            path.reverse();
+            path.push(i);
+            let names: Vec<String> = path.iter().map(|&node| name(self.nodes[node].function, symbols)).collect();
+            lines.push(format!("{} {}\n", names.join(";"), node.cycles));
+        }
+        lines.sort();
+        lines.concat()
+    }
+
+    fn ancestors(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(self.nodes[node].parent, |&node| self.nodes[node].parent)
+    }
+}
+
+// The label of the function, or `bank:address`
+pub fn name(function: Function, symbols: &Symbols) -> String {
+    let (bank, address) = function;
+    match symbols.name(bank, address) {
+        Some(name) => name.to_string(),
+        None => format!("{:02x}:{:04x}", bank, address),
+    }
+}
+
+// Table of the profile, with the cycles in percent of the whole run
+pub fn report(profile: &[FunctionProfile], symbols: &Symbols) -> String {
+    let total = profile.iter().map(|function| function.exclusive).sum::<u64>().max(1);
+    let mut report = format!("{:<32} {:>10} {:>14} {:>7} {:>14} {:>7}\n", "Function", "Calls", "Inclusive", "%", "Exclusive", "%");
+    for function in profile {
+        report.push_str(&format!(
+            "{:<32} {:>10} {:>14} {:>6.2}% {:>14} {:>6.2}%\n",
+            name(function.function, symbols),
+            function.calls,
+            function.inclusive,
+            function.inclusive as f64 * 100.0 / total as f64,
+            function.exclusive,
+            function.exclusive as f64 * 100.0 / total as f64
+        ));
+    }
+    report
+}
+
+fn bank_of(address: u16, rom_bank: u16) -> u16 {
+    match address {
+        0x4000..=0x7FFF => rom_bank,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoy;
+
+    #[test]
+    fn test_call_stack() {
+        let mut gameboy = GameBoy::from_program(
+            "loop: call outer\n jr loop\n\
+             outer: call inner\n call inner\n ret\n\
+             inner: nop\n ret",
+        );
+        gameboy.track_calls();
+
+        // call outer, call inner, nop
+        for _ in 0..3 {
+            gameboy.step();
+        }
+        let call_stack = gameboy.cpu.call_stack.as_ref().unwrap();
+        let frames: Vec<(Function, u16)> = call_stack.frames().iter().map(|frame| (frame.function, frame.return_address)).collect();
+        assert_eq!(frames, [((0, 0x105), 0x103), ((0, 0x10c), 0x108)]);
+
+        // ret, call inner, nop, ret, ret, jr loop
+        for _ in 0..6 {
+            gameboy.step();
+        }
+        let call_stack = gameboy.cpu.call_stack.as_ref().unwrap();
+        assert!(call_stack.frames().is_empty());
+        let profile = call_stack.profile();
+        // call 24 + jr 16, call 24 * 2 + ret 16, nop 4 + ret 16 twice
+        assert_eq!(profile[0], FunctionProfile { function: (0, 0x100), calls: 1, inclusive: 144, exclusive: 40 });
+        assert_eq!(profile[1], FunctionProfile { function: (0, 0x105), calls: 1, inclusive: 104, exclusive: 64 });
+        assert_eq!(profile[2], FunctionProfile { function: (0, 0x10c), calls: 2, inclusive: 40, exclusive: 40 });
+
+        let symbols = Symbols::parse("00:0100 Main\n00:010c Inner");
+        assert_eq!(call_stack.folded(&symbols), "Main 40\nMain;00:0105 64\nMain;00:0105;Inner 40\n");
+    }
+}
@@ -0,0 +1,501 @@
+// Command line interface. Parses the subcommands and their options, and reports
+// wrong arguments or unreadable files as errors instead of panicking
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use gameboy_emu::compat_palette::ButtonCombo;
+use gameboy_emu::disassembler;
+use gameboy_emu::gpu::{ColorCorrection, DmgPalette};
+use gameboy_emu::logger::LogLevel;
+use gameboy_emu::model::Model;
+use gameboy_emu::rom_reader::HeaderError;
+use gameboy_emu::save_state::StateError;
+use gameboy_emu::trace::TraceFilter;
+use gameboy_emu::LoadError;
+
+pub const USAGE: &str = "\
+Usage: gameboy-emu <command> [options] <rom>
+
+Commands:
+  run      Run a ROM in a window (the default when no command is given)
+  info     Print the ROM header decoded, with the logo and checksums verified
+  disasm   Disassemble the ROM
+  test     Run a test ROM without a window and report the result it sends over serial
+  help     Print this message
+
+Options of run:
+  --model <dmg|mgb|cgb|sgb>         Hardware to emulate, picked from the header by default
+  --scale <1|2|4|8|16|32>           Window scale (default 4)
+  --palette <grey|green>            Shades of DMG games (default grey)
+  --compat-palette <combo>          Palette of DMG games on a CGB, e.g. up+a (default from the title)
+  --color-correction <off|curves|lcd>  How CGB colors are shown (default lcd)
+  --boot-rom <file>                 Start from a boot ROM instead of skipping it
+  --save-dir <dir>                  Where battery saves go (default next to the ROM)
+  --speed <factor>                  Emulation speed, 0 runs as fast as possible (default 1)
+  --fast-forward <factor>           Speed multiplier while Tab is held, 0 is unlimited (default 4)
+  --rewind <seconds>                How far R rewinds, 0 turns rewinding off (default 10)
+  --headless                        Run without a window
+  --tiles                           Also show the tile data in VRAM in a second window, with the
+                                    index and address of the tile under the mouse in its title
+  --debug                           Start in the command line debugger, type help for its commands
+  --gdb <port>                      Wait for GDB on a local port, e.g. with gdb-multiarch:
+                                    set architecture gbz80, then target remote :<port>
+  --frames <count>                  Stop after running this many frames
+  --dump-frame <file>               Write the last frame to a PPM image on exit
+  --screenshot-at-frame <n> <file>  Save frame n as a PNG, can be given more than once
+  --screenshot-scale <n>            Size of screenshots, 1 is 160x144 (default 1)
+  --record-video <file>             Record every frame to an uncompressed .y4m video
+  --load-state <slot>               Start from the save state in slot 1 to 9
+  --save-state <slot>               Save the state to slot 1 to 9 on exit
+                                    States go next to the battery save as <rom>.ss<slot>
+  --trace <file>                    Log every instruction in the format of Gameboy Doctor,
+                                    with LY reading 90 as it expects. Turns rewinding off
+  --trace-range <start>-<end>       Only log the instructions at these addresses, in hex
+  --trace-bank <0|1>                Only log the instructions in this ROM bank, there is no MBC
+                                    to map the others yet
+  --cdl <file>                      Mark the ROM bytes run and read in a code/data log, adding to
+                                    the file when it exists. One byte of flags per ROM byte: bit 0
+                                    opcode, bit 1 operand, bit 2 data, 0 for unused. Without an MBC
+                                    only ROM banks 0 and 1 are logged
+  --profile <file>                  Count the cycles of every function, print them on exit and
+                                    write the calls as folded stacks for flamegraph.pl or inferno.
+                                    Turns rewinding off
+
+Keys of the window:
+  Arrows, Z, X, Enter, Backspace    D-pad, A, B, Start, Select
+  Tab (hold)                        Fast-forward
+  R (hold)                          Rewind
+  - / = / 0                         Halve, double or reset the speed
+  P / N                             Pause, advance one frame while paused
+                                    P breaks into the debugger with --debug
+  F12                               Save a screenshot next to the ROM
+  1 - 9 / F5 / F8                   Pick the state slot, save or load the state
+
+Options of info:
+  --json                            Print the header as JSON
+
+Options of test:
+  --model, --boot-rom, --trace, --trace-range, --trace-bank, --cdl, --profile  Same as run
+  --timeout <seconds>               Emulated seconds before giving up (default 120)
+
+Options of disasm:
+  --start <location>, --end <location>  Range to disassemble, a hex offset in the ROM file
+                                    or bank:address like 01:4000 (default the whole ROM)
+  --recursive                       Follow the code from the entry points and the start,
+                                    showing what isn't reached as data
+  --cdl <file>                      Also follow the code a code/data log saw running, implies --recursive
+
+Options of every command:
+  --log-level <off|error|warn|info|debug|trace>  (default info)
+
+Labels of an RGBDS symbol file next to the ROM, <rom>.sym, are shown in the disassembly,
+the debugger and the profile, and the debugger takes them as addresses. The --trace log keeps
+the plain Gameboy Doctor format without them";
+
+pub struct Cli {
+    pub command: Command,
+    pub log_level: LogLevel,
+}
+
+pub enum Command {
+    Run(RunOptions),
+    Info { rom: PathBuf, json: bool },
+    Disasm { rom: PathBuf, start: usize, end: Option<usize>, recursive: bool, cdl: Option<PathBuf> },
+    Test { options: RunOptions, timeout: u32 },
+    Help,
+}
+
+pub struct RunOptions {
+    pub rom: PathBuf,
+    pub model: Option<Model>,
+    pub scale: u8,
+    pub palette: DmgPalette,
+    pub compat_palette: Option<ButtonCombo>,
+    pub color_correction: ColorCorrection,
+    pub boot_rom: Option<PathBuf>,
+    pub save_dir: Option<PathBuf>,
+    pub speed: f64,
+    pub fast_forward: f64,
+    pub rewind: f64,
+    pub headless: bool,
+    pub tiles: bool,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub frames: Option<u64>,
+    pub dump_frame: Option<PathBuf>,
+    // Frames to save as PNG, with the file for each
+    pub screenshots: Vec<(u64, PathBuf)>,
+    pub screenshot_scale: usize,
+    pub record_video: Option<PathBuf>,
+    pub load_state: Option<u8>,
+    pub save_state: Option<u8>,
+    pub trace: Option<PathBuf>,
+    pub trace_filter: TraceFilter,
+    pub cdl: Option<PathBuf>,
+    pub profile: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    UnknownFlag(String),
+    MissingValue(String),
+    // A switch given a value with --flag=value
+    UnexpectedValue(String),
+    InvalidValue { flag: String, value: String, expected: &'static str },
+    MissingRom,
+    UnexpectedArgument(String),
+    Read { path: PathBuf, error: io::Error },
+    Write { path: PathBuf, error: io::Error },
+    InvalidHeader { path: PathBuf, error: HeaderError },
+    // The length of the file isn't the ROM size of its header, None when that is unknown
+    RomSize { path: PathBuf, length: usize, declared: Option<usize> },
+    Load { path: PathBuf, error: LoadError },
+    State { path: PathBuf, error: StateError },
+    Window(String),
+    Gdb(io::Error),
+}
+
+impl CliError {
+    // Errors caused by the arguments, shown with a hint to read the help
+    pub fn is_usage(&self) -> bool {
+        matches!(
+            self,
+            CliError::UnknownFlag(_)
+                | CliError::MissingValue(_)
+                | CliError::UnexpectedValue(_)
+                | CliError::InvalidValue { .. }
+                | CliError::MissingRom
+                | CliError::UnexpectedArgument(_)
+        )
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::UnknownFlag(flag) => write!(f, "unknown option --{}", flag),
+            CliError::MissingValue(flag) => write!(f, "--{} needs a value", flag),
+            CliError::UnexpectedValue(flag) => write!(f, "--{} doesn't take a value", flag),
+            CliError::InvalidValue { flag, value, expected } => {
+                write!(f, "invalid value '{}' for --{}, expected {}", value, flag, expected)
+            }
+            CliError::MissingRom => write!(f, "no ROM file given"),
+            CliError::UnexpectedArgument(argument) => write!(f, "unexpected argument '{}'", argument),
+            CliError::Read { path, error } => write!(f, "couldn't read {}: {}", path.display(), error),
+            CliError::Write { path, error } => write!(f, "couldn't write {}: {}", path.display(), error),
+            CliError::InvalidHeader { path, error } => write!(f, "{} is not a Game Boy ROM: {}", path.display(), error),
+            CliError::RomSize { path, length, declared: Some(declared) } => write!(
+                f,
+                "{} is {} bytes long, but its header declares a ROM of {} bytes",
+                path.display(),
+                length,
+                declared
+            ),
+            CliError::RomSize { path, declared: None, .. } => {
+                write!(f, "the header of {} declares an unknown ROM size", path.display())
+            }
+            CliError::Load { path, error } => write!(f, "couldn't start {}: {}", path.display(), error),
+            CliError::State { path, error } => write!(f, "couldn't load {}: {}", path.display(), error),
+            CliError::Window(error) => write!(f, "couldn't open the window: {}", error),
+            CliError::Gdb(error) => write!(f, "GDB connection failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+// Options that don't take a value
+const SWITCHES: [&str; 5] = ["headless", "tiles", "debug", "json", "recursive"];
+
+// Options that take two values
+const PAIRS: [&str; 1] = ["screenshot-at-frame"];
+
+// Parse the arguments without the program name
+pub fn parse(args: &[String]) -> Result<Cli, CliError> {
+    // Running a ROM is the default, so `gameboy-emu game.gb` still works
+    let (name, args) = match args.first().map(String::as_str) {
+        Some(name @ ("run" | "info" | "disasm" | "test" | "help")) => (name, &args[1..]),
+        Some("-h" | "--help") | None => ("help", args),
+        Some(_) => ("run", args),
+    };
+
+    let allowed: &[&str] = match name {
+        "run" => &[
+            "model",
+            "scale",
+            "palette",
+            "compat-palette",
+            "color-correction",
+            "boot-rom",
+            "save-dir",
+            "speed",
+            "fast-forward",
+            "rewind",
+            "headless",
+            "tiles",
+            "debug",
+            "gdb",
+            "frames",
+            "dump-frame",
+            "screenshot-at-frame",
+            "screenshot-scale",
+            "record-video",
+            "load-state",
+            "save-state",
+            "trace",
+            "trace-range",
+            "trace-bank",
+            "cdl",
+            "profile",
+            "log-level",
+        ],
+        "test" => &["model", "boot-rom", "timeout", "trace", "trace-range", "trace-bank", "cdl", "profile", "log-level"],
+        "disasm" => &["start", "end", "recursive", "cdl", "log-level"],
+        "info" => &["json", "log-level"],
+        _ => &["log-level"],
+    };
+    let mut arguments = Arguments::parse(args, allowed)?;
+
+    let log_level = arguments.value("log-level", "off, error, warn, info, debug or trace", LogLevel::from_name)?;
+    let command = match name {
+        "run" => Command::Run(arguments.run_options()?),
+        "info" => Command::Info {
+            rom: arguments.rom()?,
+            json: arguments.switch("json"),
+        },
+        "disasm" => Command::Disasm {
+            rom: arguments.rom()?,
+            start: arguments.value("start", "a hex offset or bank:address", parse_location)?.unwrap_or(0),
+            end: arguments.value("end", "a hex offset or bank:address", parse_location)?,
+            recursive: arguments.switch("recursive"),
+            cdl: arguments.value("cdl", "a file", |value| Some(PathBuf::from(value)))?,
+        },
+        "test" => Command::Test {
+            timeout: arguments.value("timeout", "a number of seconds", |value| value.parse().ok())?.unwrap_or(120),
+            options: arguments.run_options()?,
+        },
+        _ => Command::Help,
+    };
+
+    Ok(Cli {
+        command,
+        log_level: log_level.unwrap_or(LogLevel::Info),
+    })
+}
+
+// Options given as `--name value`, `--name=value`, `--pair first second` or `--switch`,
+// and the positional ROM path
+struct Arguments {
+    options: Vec<(String, Vec<String>)>,
+    rom: Option<PathBuf>,
+}
+
+impl Arguments {
+    fn parse(args: &[String], allowed: &[&str]) -> Result<Arguments, CliError> {
+        let mut options = Vec::new();
+        let mut rom = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if let Some(flag) = arg.strip_prefix("--") {
+                let (flag, inline_value) = match flag.split_once('=') {
+                    Some((flag, value)) => (flag, Some(value.to_string())),
+                    None => (flag, None),
+                };
+                if !allowed.contains(&flag) {
+                    return Err(CliError::UnknownFlag(flag.to_string()));
+                }
+                let count = if SWITCHES.contains(&flag) {
+                    if inline_value.is_some() {
+                        return Err(CliError::UnexpectedValue(flag.to_string()));
+                    }
+                    0
+                } else if PAIRS.contains(&flag) {
+                    2
+                } else {
+                    1
+                };
+                let mut values: Vec<String> = inline_value.into_iter().collect();
+                while values.len() < count {
+                    values.push(args.next().cloned().ok_or_else(|| CliError::MissingValue(flag.to_string()))?);
+                }
+                options.push((flag.to_string(), values));
+            } else if rom.is_none() {
+                rom = Some(PathBuf::from(arg));
+            } else {
+                return Err(CliError::UnexpectedArgument(arg.clone()));
+            }
+        }
+
+        Ok(Arguments { options, rom })
+    }
+
+    fn rom(&mut self) -> Result<PathBuf, CliError> {
+        self.rom.take().ok_or(CliError::MissingRom)
+    }
+
+    fn switch(&self, flag: &str) -> bool {
+        self.options.iter().any(|(name, _)| name == flag)
+    }
+
+    // Parse the last value given for the option, if any
+    fn value<T>(&self, flag: &str, expected: &'static str, parse: impl Fn(&str) -> Option<T>) -> Result<Option<T>, CliError> {
+        match self.options.iter().rev().find(|(name, _)| name == flag) {
+            Some((_, values)) => parse(&values[0]).map(Some).ok_or_else(|| CliError::InvalidValue {
+                flag: flag.to_string(),
+                value: values[0].clone(),
+                expected,
+            }),
+            None => Ok(None),
+        }
+    }
+
+    // Parse every pair given for the option, in order
+    fn pairs<T>(&self, flag: &str, expected: &'static str, parse: impl Fn(&str, &str) -> Option<T>) -> Result<Vec<T>, CliError> {
+        self.options
+            .iter()
+            .filter(|(name, _)| name == flag)
+            .map(|(_, values)| {
+                parse(&values[0], &values[1]).ok_or_else(|| CliError::InvalidValue {
+                    flag: flag.to_string(),
+                    value: values.join(" "),
+                    expected,
+                })
+            })
+            .collect()
+    }
+
+    fn run_options(&mut self) -> Result<RunOptions, CliError> {
+        let trace_range = self.value("trace-range", "a range of hex addresses like 4000-7fff", |value| {
+            let (start, end) = value.split_once('-')?;
+            let start = u16::try_from(parse_hex(start)?).ok()?;
+            let end = u16::try_from(parse_hex(end)?).ok()?;
+            (start <= end).then_some((start, end))
+        })?;
+        let (trace_start, trace_end) = trace_range.unwrap_or((0, 0xFFFF));
+        let scale = self.value("scale", "1, 2, 4, 8, 16 or 32", |value| {
+            value.parse().ok().filter(|scale| [1, 2, 4, 8, 16, 32].contains(scale))
+        })?;
+        let speed = self.value("speed", "a positive number", |value| {
+            value.parse().ok().filter(|speed: &f64| speed.is_finite() && *speed >= 0.0)
+        })?;
+        let fast_forward = self.value("fast-forward", "a positive number", |value| {
+            value.parse().ok().filter(|factor: &f64| factor.is_finite() && *factor >= 0.0)
+        })?;
+
+        Ok(RunOptions {
+            rom: self.rom()?,
+            model: self.value("model", "dmg, mgb, cgb or sgb", Model::from_name)?,
+            scale: scale.unwrap_or(4),
+            palette: self.value("palette", "grey or green", DmgPalette::from_name)?.unwrap_or(DmgPalette::Grey),
+            compat_palette: self.value("compat-palette", "up, left, down or right with an optional +a or +b", ButtonCombo::from_name)?,
+            color_correction: self
+                .value("color-correction", "off, curves or lcd", ColorCorrection::from_name)?
+                .unwrap_or(ColorCorrection::Lcd),
+            boot_rom: self.value("boot-rom", "a file", |value| Some(PathBuf::from(value)))?,
+            save_dir: self.value("save-dir", "a directory", |value| Some(PathBuf::from(value)))?,
+            speed: speed.unwrap_or(1.0),
+            fast_forward: fast_forward.unwrap_or(4.0),
+            rewind: self
+                .value("rewind", "a number of seconds", |value| {
+                    value.parse().ok().filter(|seconds: &f64| seconds.is_finite() && *seconds >= 0.0)
+                })?
+                .unwrap_or(10.0),
+            headless: self.switch("headless"),
+            tiles: self.switch("tiles"),
+            debug: self.switch("debug"),
+            gdb: self.value("gdb", "a port number", |value| value.parse().ok())?,
+            frames: self.value("frames", "a number of frames", |value| value.parse().ok())?,
+            dump_frame: self.value("dump-frame", "a file", |value| Some(PathBuf::from(value)))?,
+            screenshots: self.pairs("screenshot-at-frame", "a frame number and a file", |frame, path| {
+                Some((frame.parse().ok()?, PathBuf::from(path)))
+            })?,
+            screenshot_scale: self
+                .value("screenshot-scale", "a number from 1 to 32", |value| {
+                    value.parse().ok().filter(|scale| (1..=32).contains(scale))
+                })?
+                .unwrap_or(1),
+            record_video: self.value("record-video", "a file", |value| Some(PathBuf::from(value)))?,
+            load_state: self.value("load-state", "a slot from 1 to 9", parse_slot)?,
+            save_state: self.value("save-state", "a slot from 1 to 9", parse_slot)?,
+            trace: self.value("trace", "a file", |value| Some(PathBuf::from(value)))?,
+            trace_filter: TraceFilter {
+                start: trace_start,
+                end: trace_end,
+                bank: self.value("trace-bank", "0 or 1, there is no MBC to map other banks yet", |value| {
+                    u16::try_from(parse_hex(value)?).ok().filter(|&bank| bank <= 1)
+                })?,
+            },
+            cdl: self.value("cdl", "a file", |value| Some(PathBuf::from(value)))?,
+            profile: self.value("profile", "a file", |value| Some(PathBuf::from(value)))?,
+        })
+    }
+}
+
+fn parse_slot(value: &str) -> Option<u8> {
+    value.parse().ok().filter(|slot| (1..=9).contains(slot))
+}
+
+// A ROM offset given as is or as bank:address, both in hex
+fn parse_location(value: &str) -> Option<usize> {
+    match value.split_once(':') {
+        Some((bank, address)) => {
+            let address = u16::try_from(parse_hex(address)?).ok()?;
+            disassembler::offset_of(parse_hex(bank)?, address)
+        }
+        None => parse_hex(value),
+    }
+}
+
+// Hex numbers with an optional 0x or $ prefix
+fn parse_hex(value: &str) -> Option<usize> {
+    let digits = value.strip_prefix("0x").or_else(|| value.strip_prefix('$')).unwrap_or(value);
+    usize::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_run() {
+        let cli = parse(&args("game.gb --model=sgb --scale 2 --headless --frames 60 --log-level debug")).unwrap();
+        assert_eq!(cli.log_level, LogLevel::Debug);
+        let Command::Run(options) = cli.command else { panic!("Expected the run command") };
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+        assert_eq!(options.model, Some(Model::Sgb));
+        assert_eq!(options.scale, 2);
+        assert!(options.headless);
+        assert_eq!(options.frames, Some(60));
+
+        let cli = parse(&args("game.gb --screenshot-at-frame 10 a.png --tiles --screenshot-at-frame=20 b.png")).unwrap();
+        let Command::Run(options) = cli.command else { panic!("Expected the run command") };
+        assert!(options.tiles);
+        assert_eq!(options.screenshots, [(10, PathBuf::from("a.png")), (20, PathBuf::from("b.png"))]);
+        assert_eq!(options.rom, PathBuf::from("game.gb"));
+        assert_eq!(options.speed, 1.0);
+
+        let cli = parse(&args("test game.gb --trace out.log --trace-range 4000-7fff --trace-bank 1")).unwrap();
+        let Command::Test { options, .. } = cli.command else { panic!("Expected the test command") };
+        assert_eq!(options.trace, Some(PathBuf::from("out.log")));
+        assert_eq!(options.trace_filter, TraceFilter { start: 0x4000, end: 0x7FFF, bank: Some(1) });
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(parse(&args("run")), Err(CliError::MissingRom)));
+        assert!(matches!(parse(&args("run game.gb --scale 3")), Err(CliError::InvalidValue { .. })));
+        assert!(matches!(parse(&args("info game.gb --scale 2")), Err(CliError::UnknownFlag(_))));
+        assert!(matches!(parse(&args("run game.gb --model")), Err(CliError::MissingValue(_))));
+        assert!(matches!(parse(&args("run game.gb --headless=no")), Err(CliError::UnexpectedValue(_))));
+        assert!(matches!(parse(&args("run game.gb --trace-bank 2")), Err(CliError::InvalidValue { .. })));
+        assert!(matches!(parse(&args("disasm game.gb --start $150")).map(|cli| cli.command), Ok(Command::Disasm { start: 0x150, .. })));
+        assert!(matches!(parse(&args("disasm game.gb --start 02:4010")).map(|cli| cli.command), Ok(Command::Disasm { start: 0x8010, .. })));
+    }
+}
@@ -0,0 +1,275 @@
+// Palettes the CGB boot ROM gives to games made for the DMG.
+// The boot ROM hashes the title of Nintendo published games and looks the
+// result up in a table, other games get the default palette. The player can
+// also choose one of 12 palettes by holding a button combination on boot.
+
+use crate::rom_reader::RomHeader;
+
+// BGR555 colors for the background and the two sprite palettes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CompatPalette {
+    pub bg: [u16; 4],
+    pub obj0: [u16; 4],
+    pub obj1: [u16; 4],
+}
+
+// Button combinations held during the boot animation to pick a palette
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ButtonCombo {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl ButtonCombo {
+    // Parse names like "up", "left+a" or "Right+B"
+    pub fn from_name(name: &str) -> Option<ButtonCombo> {
+        match name.to_ascii_lowercase().replace(' ', "").as_str() {
+            "up" => Some(ButtonCombo::Up),
+            "up+a" => Some(ButtonCombo::UpA),
+            "up+b" => Some(ButtonCombo::UpB),
+            "left" => Some(ButtonCombo::Left),
+            "left+a" => Some(ButtonCombo::LeftA),
+            "left+b" => Some(ButtonCombo::LeftB),
+            "down" => Some(ButtonCombo::Down),
+            "down+a" => Some(ButtonCombo::DownA),
+            "down+b" => Some(ButtonCombo::DownB),
+            "right" => Some(ButtonCombo::Right),
+            "right+a" => Some(ButtonCombo::RightA),
+            "right+b" => Some(ButtonCombo::RightB),
+            _ => None,
+        }
+    }
+
+    // Index of the combination in PALETTE_COMBINATIONS
+    fn combination(&self) -> usize {
+        match self {
+            ButtonCombo::Up => 5,
+            ButtonCombo::UpA => 43,
+            ButtonCombo::UpB => 28,
+            ButtonCombo::Left => 48,
+            ButtonCombo::LeftA => 40,
+            ButtonCombo::LeftB => 7,
+            ButtonCombo::Down => 8,
+            ButtonCombo::DownA => 3,
+            ButtonCombo::DownB => 49,
+            ButtonCombo::Right => 1,
+            ButtonCombo::RightA => 0,
+            ButtonCombo::RightB => 6,
+        }
+    }
+}
+
+// Colors used by all the combinations, 4 colors per palette
+const COLORS: [u16; 30 * 4] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000, // 0
+    0x639F, 0x4279, 0x15B0, 0x04CB, // 1
+    0x7FFF, 0x6E31, 0x454A, 0x0000, // 2
+    0x7FFF, 0x1BEF, 0x0200, 0x0000, // 3
+    0x7FFF, 0x421F, 0x1CF2, 0x0000, // 4
+    0x7FFF, 0x5294, 0x294A, 0x0000, // 5
+    0x7FFF, 0x03FF, 0x012F, 0x0000, // 6
+    0x7FFF, 0x03EF, 0x01D6, 0x0000, // 7
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000, // 8
+    0x7E74, 0x03FF, 0x0180, 0x0000, // 9
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B, // 10
+    0x7ED6, 0x4BFF, 0x2175, 0x0000, // 11
+    0x53FF, 0x4A5F, 0x7E52, 0x0000, // 12
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0, // 13
+    0x03ED, 0x7FFF, 0x255F, 0x0000, // 14
+    0x036A, 0x021F, 0x03FF, 0x7FFF, // 15
+    0x7FFF, 0x01DF, 0x0112, 0x0000, // 16
+    0x231F, 0x035F, 0x00F2, 0x0009, // 17
+    0x7FFF, 0x03EA, 0x011F, 0x0000, // 18
+    0x299F, 0x001A, 0x000C, 0x0000, // 19
+    0x7FFF, 0x027F, 0x001F, 0x0000, // 20
+    0x7FFF, 0x03E0, 0x0206, 0x0120, // 21
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00, // 22
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F, // 23
+    0x7FFF, 0x03FF, 0x001F, 0x0000, // 24
+    0x03FF, 0x001F, 0x000C, 0x0000, // 25
+    0x7FFF, 0x033F, 0x0193, 0x0000, // 26
+    0x0000, 0x4200, 0x037F, 0x7FFF, // 27
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000, // 28
+    0x7FFF, 0x1BEF, 0x6180, 0x0000, // 29
+];
+
+// Offsets in COLORS of the OBJ0, OBJ1 and BG palettes. Most combinations use
+// whole palettes but a few start in the middle of one, like the boot ROM does
+const fn palettes(obj0: usize, obj1: usize, bg: usize) -> [usize; 3] {
+    [obj0 * 4, obj1 * 4, bg * 4]
+}
+
+const PALETTE_COMBINATIONS: [[usize; 3]; 51] = [
+    palettes(4, 4, 29),      // 0
+    palettes(18, 18, 18),    // 1
+    palettes(20, 20, 20),    // 2
+    palettes(24, 24, 24),    // 3
+    palettes(9, 9, 9),       // 4
+    palettes(0, 0, 0),       // 5
+    palettes(27, 27, 27),    // 6
+    palettes(5, 5, 5),       // 7
+    palettes(12, 12, 12),    // 8
+    palettes(26, 26, 26),    // 9
+    palettes(16, 8, 8),      // 10
+    palettes(4, 28, 28),     // 11
+    palettes(4, 2, 2),       // 12
+    palettes(3, 4, 4),       // 13
+    palettes(4, 29, 29),     // 14
+    palettes(28, 4, 28),     // 15
+    palettes(2, 17, 2),      // 16
+    palettes(16, 16, 8),     // 17
+    palettes(4, 4, 7),       // 18
+    palettes(4, 4, 18),      // 19
+    palettes(4, 4, 20),      // 20
+    palettes(19, 19, 9),     // 21
+    [4 * 4 - 1, 4 * 4 - 1, 11 * 4], // 22
+    palettes(17, 17, 2),     // 23
+    palettes(4, 4, 2),       // 24
+    palettes(4, 4, 3),       // 25
+    palettes(28, 28, 0),     // 26
+    palettes(3, 3, 0),       // 27
+    palettes(0, 0, 1),       // 28
+    palettes(18, 22, 18),    // 29
+    palettes(20, 22, 20),    // 30
+    palettes(24, 22, 24),    // 31
+    palettes(16, 22, 8),     // 32
+    palettes(17, 4, 13),     // 33
+    [28 * 4 - 1, 0, 14 * 4], // 34
+    [28 * 4 - 1, 4 * 4, 15 * 4], // 35
+    palettes(19, 22, 9),     // 36
+    palettes(16, 28, 10),    // 37
+    palettes(4, 23, 28),     // 38
+    palettes(17, 22, 2),     // 39
+    palettes(4, 0, 2),       // 40
+    palettes(4, 28, 3),      // 41
+    palettes(28, 3, 0),      // 42
+    palettes(3, 28, 4),      // 43
+    palettes(21, 28, 4),     // 44
+    palettes(3, 28, 0),      // 45
+    palettes(25, 3, 28),     // 46
+    palettes(0, 28, 8),      // 47
+    palettes(4, 3, 28),      // 48
+    palettes(28, 3, 6),      // 49
+    palettes(4, 28, 29),     // 50
+];
+
+// Sum of the title bytes of the games with their own palette
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    // From here on several games share a checksum, the 4th letter of the title tells them apart
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3, 0x46,
+    0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+
+const FIRST_CHECKSUM_WITH_DUPLICATE: usize = 65;
+
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Index in PALETTE_COMBINATIONS for every entry of TITLE_CHECKSUMS
+const COMBINATION_PER_CHECKSUM: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+impl CompatPalette {
+    // Pick the palette the CGB boot ROM would use for this game
+    pub fn from_header(header: &RomHeader) -> CompatPalette {
+        CompatPalette::from_combination(COMBINATION_PER_CHECKSUM[checksum_index(header).unwrap_or(0)] as usize)
+    }
+
+    pub fn from_button_combo(combo: ButtonCombo) -> CompatPalette {
+        CompatPalette::from_combination(combo.combination())
+    }
+
+    fn from_combination(combination: usize) -> CompatPalette {
+        let [obj0, obj1, bg] = PALETTE_COMBINATIONS[combination];
+        let palette = |offset: usize| {
+            let mut colors = [0; 4];
+            colors.copy_from_slice(&COLORS[offset..offset + 4]);
+            colors
+        };
+
+        CompatPalette {
+            bg: palette(bg),
+            obj0: palette(obj0),
+            obj1: palette(obj1),
+        }
+    }
+}
+
+// Find the entry of the game in TITLE_CHECKSUMS, only games published by Nintendo have one
+fn checksum_index(header: &RomHeader) -> Option<usize> {
+    let nintendo = header.old_licensee_code == 0x01
+        || (header.old_licensee_code == 0x33 && &header.new_licensee_code == b"01");
+    if !nintendo {
+        return None;
+    }
+
+    // The hash covers the 16 bytes from 0x134 to 0x143
+    let checksum = header
+        .title
+        .iter()
+        .fold(header.cgb_flag, |sum, byte| sum.wrapping_add(*byte));
+    let fourth_letter = header.title[3];
+
+    TITLE_CHECKSUMS.iter().enumerate().position(|(index, &entry)| {
+        entry == checksum
+            && (index < FIRST_CHECKSUM_WITH_DUPLICATE
+                || FOURTH_LETTERS[index - FIRST_CHECKSUM_WITH_DUPLICATE] == fourth_letter)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(title: &[u8], old_licensee_code: u8) -> RomHeader {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = old_licensee_code;
+        RomHeader::parse(&rom).unwrap()
+    }
+
+    #[test]
+    fn test_palette_from_title() {
+        // TETRIS gets white, yellow, red and black everywhere
+        let palette = CompatPalette::from_header(&header(b"TETRIS", 0x01));
+        assert_eq!(palette.bg, [0x7FFF, 0x03FF, 0x001F, 0x0000]);
+        assert_eq!(palette, CompatPalette::from_button_combo(ButtonCombo::DownA));
+
+        // Other publishers get the default palette
+        let palette = CompatPalette::from_header(&header(b"TETRIS", 0x08));
+        assert_eq!(palette, CompatPalette::from_button_combo(ButtonCombo::RightA));
+    }
+
+    #[test]
+    fn test_fourth_letter() {
+        // SUPER MARIOLAND and POKEMON BLUE hash to entries that need the 4th letter
+        let palette = CompatPalette::from_header(&header(b"SUPER MARIOLAND", 0x01));
+        assert_eq!(palette.bg, [0x7ED6, 0x4BFF, 0x2175, 0x0000]);
+        assert_eq!(palette.obj0, [0x0000, 0x7FFF, 0x421F, 0x1CF2]);
+
+        let palette = CompatPalette::from_header(&header(b"POKEMON BLUE", 0x01));
+        assert_eq!(palette.bg, [0x7FFF, 0x7E8C, 0x7C00, 0x0000]);
+
+        // Same checksum as SUPER MARIOLAND but the 4th letter doesn't match
+        let palette = CompatPalette::from_header(&header(b"SUPAER MRIOLAND", 0x01));
+        assert_eq!(palette, CompatPalette::from_button_combo(ButtonCombo::RightA));
+    }
+}
@@ -1,10 +1,10 @@
-mod registers;
+pub mod registers;
 use registers::Registers;
 
 pub mod memory;
 use memory::Memory;
 
-mod instructions;
+pub mod instructions;
 use instructions::{
     ADDHLTarget, ArithmeticTarget, IncDecTarget, Indirect, Instruction, JumpTest, LoadByteSource,
     LoadByteTarget, LoadType, LoadWordTarget, StackTarget,
@@ -12,23 +12,59 @@ use instructions::{
 
 use self::instructions::{BitPosition, PrefixTarget};
 
+use crate::call_stack::{CallKind, CallStack};
+use crate::model::Model;
+use crate::save_state::{StateError, StateReader, StateWriter};
+use crate::trace::Trace;
+
 pub struct Cpu {
     pub registers: Registers,
     pub memory: Memory,
     pub is_halted: bool,
     pub interrupts_enabled: bool,
+    // Logs every instruction before it runs, see `GameBoy::set_trace`
+    pub trace: Option<Trace>,
+    // Follows the calls and returns when debugging or profiling
+    pub call_stack: Option<CallStack>,
 }
 
 impl Cpu {
-    pub fn new() -> Cpu {
+    pub fn new(model: Model) -> Cpu {
         Cpu {
-            registers: Registers::new(),
-            memory: Memory::new(),
+            registers: Registers::new(model),
+            memory: Memory::new(model),
             is_halted: false,
             interrupts_enabled: false,
+            trace: None,
+            call_stack: None,
         }
     }
 
+    // Start from the boot ROM at 0x0000 instead of the state it leaves behind
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.memory.load_boot_rom(boot_rom);
+        self.registers.set_af(0);
+        self.registers.set_bc(0);
+        self.registers.set_de(0);
+        self.registers.set_hl(0);
+        self.registers.sp = 0;
+        self.registers.pc = 0;
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        self.registers.save_state(state);
+        state.bool(self.is_halted);
+        state.bool(self.interrupts_enabled);
+        self.memory.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.registers.load_state(state)?;
+        self.is_halted = state.bool()?;
+        self.interrupts_enabled = state.bool()?;
+        self.memory.load_state(state)
+    }
+
     fn jump(&self, jump_condition: bool) -> (u16, u8) {
         if jump_condition {
             (self.read_next_word(), 16)
@@ -47,7 +83,7 @@ impl Cpu {
             let pc = if offset >= 0 {
                 next_step.wrapping_add(offset as u16)
             } else {
-                next_step.wrapping_sub(offset.abs() as u16)
+                next_step.wrapping_sub(offset.unsigned_abs() as u16)
             };
             (pc, 16)
         } else {
@@ -98,39 +134,106 @@ impl Cpu {
     }
 
     pub fn read_next_byte(&self) -> u8 {
-        self.memory.read_byte(self.registers.pc + 1)
+        self.memory.fetch_byte(self.registers.pc + 1)
     }
 
     pub fn read_next_word(&self) -> u16 {
         // Gameboy is little endian so read pc + 2 as most significant bit
         // and pc + 1 as least significant bi
-        let least_significant_byte = self.memory.read_byte(self.registers.pc + 1) as u16;
-        let most_significant_byte = self.memory.read_byte(self.registers.pc + 2) as u16;
+        let least_significant_byte = self.memory.fetch_byte(self.registers.pc + 1) as u16;
+        let most_significant_byte = self.memory.fetch_byte(self.registers.pc + 2) as u16;
         (most_significant_byte << 8) | least_significant_byte
     }
 
-    pub fn step(&mut self) {
-        let op_byte = self.memory.read_byte(self.registers.pc);
+    // Service the highest priority pending interrupt and return the cycles it took
+    fn handle_interrupts(&mut self) -> u8 {
+        let pending = self.memory.peek(0xFFFF) & self.memory.peek(0xFF0F) & 0x1F;
+        if pending == 0 {
+            return 0;
+        }
+
+        // A pending interrupt wakes the CPU up from HALT even if IME is off
+        self.is_halted = false;
+        if !self.interrupts_enabled {
+            return 0;
+        }
+
+        let bit = pending.trailing_zeros() as u8;
+        self.memory.acknowledge_interrupt(bit);
+        self.interrupts_enabled = false;
+        self.push(self.registers.pc);
+        self.registers.pc = 0x40 + bit as u16 * 8;
+        20
+    }
+
+    // Execute the next instruction and return the number of cycles it took,
+    // including the time the CPU was stalled by a VRAM DMA
+    pub fn step(&mut self) -> u32 {
+        let interrupt_cycles = self.handle_interrupts();
+        if interrupt_cycles > 0 {
+            let cycles = interrupt_cycles as u32 + self.memory.take_dma_stall_cycles();
+            if let Some(call_stack) = &mut self.call_stack {
+                let sp = self.registers.sp;
+                let return_address = u16::from_le_bytes([self.memory.peek(sp), self.memory.peek(sp.wrapping_add(1))]);
+                call_stack.add_cycles(cycles);
+                call_stack.enter(CallKind::Interrupt, self.registers.pc, self.memory.rom_bank(), return_address, sp);
+            }
+            return cycles;
+        }
+        if self.is_halted {
+            if let Some(call_stack) = &mut self.call_stack {
+                call_stack.add_cycles(4);
+            }
+            return 4;
+        }
+        if let Some(trace) = &mut self.trace {
+            if let Err(error) = trace.log(&self.registers, &self.memory) {
+                warn!("Stopped tracing: {}", error);
+                self.trace = None;
+            }
+        }
+
+        let mut op_byte = self.memory.fetch_byte(self.registers.pc);
         let prefixed = op_byte == 0xCB;
         if prefixed {
-            let op_byte = self.memory.read_byte(self.registers.pc + 1);
+            op_byte = self.memory.fetch_byte(self.registers.pc + 1);
         }
-        // // TODO find out when to use prefixed opcodes and when not.
-        // let instruction = Instruction::from_byte(op_byte, false).expect("Invalid opcode");
-        // self.registers.pc += 1;
-        // instruction.execute(self);
-
-        let (new_pc, cycles) = if let Some(instruction) = Instruction::from_byte(op_byte, prefixed)
-        {
-            let description = format!("0x{}{:x} -> {:?}", if prefixed { "cb" } else { "" }, op_byte, instruction);
-            // println!("Stepped: {}", description);
-            self.execute(instruction)
-        } else {
+
+        let Some(instruction) = Instruction::from_byte(op_byte, prefixed) else {
             let description = format!("0x{}{:x}", if prefixed { "cb" } else { "" }, op_byte);
             panic!("Unkown instruction found for: {}", description)
         };
+        let (pc, sp) = (self.registers.pc, self.registers.sp);
+        if self.memory.has_cdl() {
+            self.memory.log_code(pc, instruction.length());
+        }
+        let (new_pc, cycles) = self.execute(instruction);
 
         self.registers.pc = new_pc;
+        let cycles = cycles as u32 + self.memory.take_dma_stall_cycles();
+        if self.call_stack.is_some() {
+            self.track_call(instruction, pc, sp, cycles);
+        }
+        cycles
+    }
+
+    // Calls and restarts that pushed their return address enter a function, returns that
+    // popped one leave it
+    fn track_call(&mut self, instruction: Instruction, pc: u16, sp: u16, cycles: u32) {
+        let rom_bank = self.memory.rom_bank();
+        let Some(call_stack) = &mut self.call_stack else { return };
+        call_stack.add_cycles(cycles);
+        let return_address = pc.wrapping_add(instruction.length());
+        match instruction {
+            Instruction::CALL(_) if self.registers.sp == sp.wrapping_sub(2) => {
+                call_stack.enter(CallKind::Call, self.registers.pc, rom_bank, return_address, self.registers.sp)
+            }
+            Instruction::RST(_) => {
+                call_stack.enter(CallKind::Restart, self.registers.pc, rom_bank, return_address, self.registers.sp)
+            }
+            Instruction::RET(_) | Instruction::RETI if self.registers.sp == sp.wrapping_add(2) => call_stack.leave(sp),
+            _ => {}
+        }
     }
 
     pub fn execute(&mut self, instruction: Instruction) -> (u16, u8) {
@@ -1149,6 +1252,15 @@ impl Cpu {
                 (self.registers.pc.wrapping_add(1), 4)
             }
             Instruction::NOP => (self.registers.pc.wrapping_add(1), 4),
+            Instruction::STOP => {
+                // DESCRIPTION: on the CGB, switch between normal and double speed
+                // if the switch was armed through KEY1. The low power mode
+                // is not emulated.
+                // PC:+2
+                // Cycles: 4
+                self.memory.try_speed_switch();
+                (self.registers.pc.wrapping_add(2), 4)
+            }
             Instruction::DI => {
                 self.interrupts_enabled = false;
                 (self.registers.pc.wrapping_add(1), 4)
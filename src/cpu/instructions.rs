@@ -63,6 +63,7 @@ pub enum Instruction {
 
     // System management
     HALT,
+    STOP,
     NOP,
     DI,
     EI,
@@ -147,6 +148,7 @@ pub enum LoadWordTarget {
     SP,
 }
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Indirect {
     BCIndirect,
@@ -229,7 +231,7 @@ pub enum RSTLocation {
     X38,
 }
 impl RSTLocation {
-    pub fn to_hex(&self) -> u16 {
+    pub fn to_hex(self) -> u16 {
         match self {
             RSTLocation::X00 => 0x00,
             RSTLocation::X08 => 0x08,
@@ -245,6 +247,46 @@ impl RSTLocation {
 
 /* --- OPCODES --- */
 impl Instruction {
+    // Number of bytes of the instruction, including the 0xCB prefix and the operands
+    pub fn length(&self) -> u16 {
+        match self {
+            Instruction::BIT(_, _)
+            | Instruction::RES(_, _)
+            | Instruction::SET(_, _)
+            | Instruction::SRL(_)
+            | Instruction::RR(_)
+            | Instruction::RL(_)
+            | Instruction::RRC(_)
+            | Instruction::RLC(_)
+            | Instruction::SRA(_)
+            | Instruction::SLA(_)
+            | Instruction::SWAP(_) => 2,
+
+            Instruction::ADD(ArithmeticTarget::D8)
+            | Instruction::ADC(ArithmeticTarget::D8)
+            | Instruction::SUB(ArithmeticTarget::D8)
+            | Instruction::SBC(ArithmeticTarget::D8)
+            | Instruction::AND(ArithmeticTarget::D8)
+            | Instruction::XOR(ArithmeticTarget::D8)
+            | Instruction::OR(ArithmeticTarget::D8)
+            | Instruction::CP(ArithmeticTarget::D8) => 2,
+
+            Instruction::LD(load_type) => match load_type {
+                LoadType::Byte(_, LoadByteSource::D8) => 2,
+                LoadType::Word(_) => 3,
+                LoadType::AFromIndirect(Indirect::WordIndirect)
+                | LoadType::IndirectFromA(Indirect::WordIndirect) => 3,
+                LoadType::AFromByteAddress | LoadType::ByteAddressFromA | LoadType::HLFromSPN => 2,
+                LoadType::IndirectFromSP => 3,
+                _ => 1,
+            },
+
+            Instruction::JP(_) | Instruction::CALL(_) => 3,
+            Instruction::JR(_) | Instruction::ADDSP | Instruction::STOP => 2,
+            _ => 1,
+        }
+    }
+
     pub fn from_byte(byte: u8, prefixed: bool) -> Option<Instruction> {
         if prefixed {
             Instruction::from_byte_prefixed(byte)
@@ -1061,6 +1103,7 @@ impl Instruction {
 
             0x00 => Some(Instruction::NOP),
             0x76 => Some(Instruction::HALT),
+            0x10 => Some(Instruction::STOP),
             0xf3 => Some(Instruction::DI),
             0xfb => Some(Instruction::EI),
 
@@ -1068,3 +1111,274 @@ impl Instruction {
         }
     }
 }
+
+/* --- ASSEMBLY SYNTAX --- */
+// Operands of an instruction taken from the bytes after the opcode
+#[derive(Copy, Clone)]
+struct Operands<'a> {
+    bytes: &'a [u8],
+    // Address of the instruction, relative jumps are shown as the address they go to
+    address: u16,
+}
+
+impl Operands<'_> {
+    fn byte(&self) -> Option<u8> {
+        self.bytes.get(1).copied()
+    }
+
+    fn word(&self) -> Option<u16> {
+        Some(u16::from_le_bytes([*self.bytes.get(1)?, *self.bytes.get(2)?]))
+    }
+}
+
+impl Instruction {
+    // The instruction in RGBDS syntax, with the operands read from `bytes` (the whole
+    // instruction, opcode included) of the instruction at `address`
+    pub fn to_asm(&self, bytes: &[u8], address: u16) -> String {
+        let mut asm = String::new();
+        let _ = self.write_asm(&mut asm, Some(Operands { bytes, address }));
+        asm
+    }
+
+    // Where a jump, call or restart goes, None for everything else and for `jp hl`
+    pub fn jump_target(&self, bytes: &[u8], address: u16) -> Option<u16> {
+        let operands = Operands { bytes, address };
+        match self {
+            Instruction::JP(_) | Instruction::CALL(_) => operands.word(),
+            Instruction::JR(_) => {
+                let offset = operands.byte()? as i8;
+                Some(address.wrapping_add(2).wrapping_add(offset as u16))
+            }
+            Instruction::RST(location) => Some(location.to_hex()),
+            _ => None,
+        }
+    }
+
+    // True when the next instruction is never executed after this one
+    pub fn ends_flow(&self) -> bool {
+        matches!(
+            self,
+            Instruction::JP(JumpTest::Always)
+                | Instruction::JR(JumpTest::Always)
+                | Instruction::RET(JumpTest::Always)
+                | Instruction::RETI
+                | Instruction::JPI
+        )
+    }
+
+    // Without operands the immediates are shown with the RGBDS placeholders n8, n16, a16 and e8
+    fn write_asm(&self, f: &mut impl std::fmt::Write, operands: Option<Operands>) -> std::fmt::Result {
+        let n8 = || match operands.and_then(|operands| operands.byte()) {
+            Some(value) => format!("${:02x}", value),
+            None => "n8".to_string(),
+        };
+        let n16 = |placeholder: &str| match operands.and_then(|operands| operands.word()) {
+            Some(value) => format!("${:04x}", value),
+            None => placeholder.to_string(),
+        };
+        let high = || match operands.and_then(|operands| operands.byte()) {
+            Some(value) => format!("[${:04x}]", 0xFF00 | value as u16),
+            None => "[a8]".to_string(),
+        };
+        let e8 = || match operands.and_then(|operands| operands.byte()) {
+            Some(value) if (value as i8) < 0 => format!("-${:02x}", (value as i8).unsigned_abs()),
+            Some(value) => format!("${:02x}", value),
+            None => "e8".to_string(),
+        };
+        let relative = || match operands.and_then(|operands| self.jump_target(operands.bytes, operands.address)) {
+            Some(target) => format!("${:04x}", target),
+            None => "e8".to_string(),
+        };
+
+        let arithmetic = |target: &ArithmeticTarget| match target {
+            ArithmeticTarget::A => "a".to_string(),
+            ArithmeticTarget::B => "b".to_string(),
+            ArithmeticTarget::C => "c".to_string(),
+            ArithmeticTarget::D => "d".to_string(),
+            ArithmeticTarget::E => "e".to_string(),
+            ArithmeticTarget::H => "h".to_string(),
+            ArithmeticTarget::L => "l".to_string(),
+            ArithmeticTarget::HLI => "[hl]".to_string(),
+            ArithmeticTarget::D8 => n8(),
+        };
+        let condition = |test: &JumpTest| match test {
+            JumpTest::NotZero => "nz, ",
+            JumpTest::Zero => "z, ",
+            JumpTest::NotCarry => "nc, ",
+            JumpTest::Carry => "c, ",
+            JumpTest::Always => "",
+        };
+
+        match self {
+            Instruction::ADD(target) => write!(f, "add a, {}", arithmetic(target)),
+            Instruction::ADC(target) => write!(f, "adc a, {}", arithmetic(target)),
+            Instruction::SUB(target) => write!(f, "sub a, {}", arithmetic(target)),
+            Instruction::SBC(target) => write!(f, "sbc a, {}", arithmetic(target)),
+            Instruction::AND(target) => write!(f, "and a, {}", arithmetic(target)),
+            Instruction::XOR(target) => write!(f, "xor a, {}", arithmetic(target)),
+            Instruction::OR(target) => write!(f, "or a, {}", arithmetic(target)),
+            Instruction::CP(target) => write!(f, "cp a, {}", arithmetic(target)),
+            Instruction::INC(target) => write!(f, "inc {}", inc_dec_name(target)),
+            Instruction::DEC(target) => write!(f, "dec {}", inc_dec_name(target)),
+            Instruction::ADDHL(target) => {
+                let name = match target {
+                    ADDHLTarget::BC => "bc",
+                    ADDHLTarget::DE => "de",
+                    ADDHLTarget::HL => "hl",
+                    ADDHLTarget::SP => "sp",
+                };
+                write!(f, "add hl, {}", name)
+            }
+            Instruction::ADDSP => write!(f, "add sp, {}", e8()),
+
+            Instruction::LD(load_type) => match load_type {
+                LoadType::Byte(target, source) => {
+                    let source = match source {
+                        LoadByteSource::A => "a".to_string(),
+                        LoadByteSource::B => "b".to_string(),
+                        LoadByteSource::C => "c".to_string(),
+                        LoadByteSource::D => "d".to_string(),
+                        LoadByteSource::E => "e".to_string(),
+                        LoadByteSource::H => "h".to_string(),
+                        LoadByteSource::L => "l".to_string(),
+                        LoadByteSource::D8 => n8(),
+                        LoadByteSource::HLI => "[hl]".to_string(),
+                    };
+                    write!(f, "ld {}, {}", load_byte_target_name(target), source)
+                }
+                LoadType::Word(target) => {
+                    let name = match target {
+                        LoadWordTarget::BC => "bc",
+                        LoadWordTarget::DE => "de",
+                        LoadWordTarget::HL => "hl",
+                        LoadWordTarget::SP => "sp",
+                    };
+                    write!(f, "ld {}, {}", name, n16("n16"))
+                }
+                LoadType::AFromIndirect(Indirect::LastByteIndirect) => write!(f, "ldh a, [c]"),
+                LoadType::IndirectFromA(Indirect::LastByteIndirect) => write!(f, "ldh [c], a"),
+                LoadType::AFromIndirect(indirect) => write!(f, "ld a, {}", indirect_name(indirect, n16)),
+                LoadType::IndirectFromA(indirect) => write!(f, "ld {}, a", indirect_name(indirect, n16)),
+                LoadType::AFromByteAddress => write!(f, "ldh a, {}", high()),
+                LoadType::ByteAddressFromA => write!(f, "ldh {}, a", high()),
+                LoadType::SPFromHL => write!(f, "ld sp, hl"),
+                LoadType::HLFromSPN => {
+                    let offset = e8();
+                    if offset.starts_with('-') {
+                        write!(f, "ld hl, sp{}", offset)
+                    } else {
+                        write!(f, "ld hl, sp+{}", offset)
+                    }
+                }
+                LoadType::IndirectFromSP => write!(f, "ld [{}], sp", n16("a16")),
+            },
+
+            Instruction::JP(test) => write!(f, "jp {}{}", condition(test), n16("a16")),
+            Instruction::JR(test) => write!(f, "jr {}{}", condition(test), relative()),
+            Instruction::JPI => write!(f, "jp hl"),
+            Instruction::CALL(test) => write!(f, "call {}{}", condition(test), n16("a16")),
+            Instruction::RET(JumpTest::Always) => write!(f, "ret"),
+            Instruction::RET(test) => write!(f, "ret {}", condition(test).trim_end_matches(", ")),
+            Instruction::RETI => write!(f, "reti"),
+            Instruction::RST(location) => write!(f, "rst ${:02x}", location.to_hex()),
+            Instruction::PUSH(target) => write!(f, "push {}", stack_name(target)),
+            Instruction::POP(target) => write!(f, "pop {}", stack_name(target)),
+
+            Instruction::BIT(target, position) => write!(f, "bit {}, {}", u8::from(*position), prefix_name(target)),
+            Instruction::RES(target, position) => write!(f, "res {}, {}", u8::from(*position), prefix_name(target)),
+            Instruction::SET(target, position) => write!(f, "set {}, {}", u8::from(*position), prefix_name(target)),
+            Instruction::SRL(target) => write!(f, "srl {}", prefix_name(target)),
+            Instruction::RR(target) => write!(f, "rr {}", prefix_name(target)),
+            Instruction::RL(target) => write!(f, "rl {}", prefix_name(target)),
+            Instruction::RRC(target) => write!(f, "rrc {}", prefix_name(target)),
+            Instruction::RLC(target) => write!(f, "rlc {}", prefix_name(target)),
+            Instruction::SRA(target) => write!(f, "sra {}", prefix_name(target)),
+            Instruction::SLA(target) => write!(f, "sla {}", prefix_name(target)),
+            Instruction::SWAP(target) => write!(f, "swap {}", prefix_name(target)),
+
+            Instruction::DAA => write!(f, "daa"),
+            Instruction::CPL => write!(f, "cpl"),
+            Instruction::CCF => write!(f, "ccf"),
+            Instruction::SCF => write!(f, "scf"),
+            Instruction::RRA => write!(f, "rra"),
+            Instruction::RLA => write!(f, "rla"),
+            Instruction::RRCA => write!(f, "rrca"),
+            Instruction::RLCA => write!(f, "rlca"),
+            Instruction::HALT => write!(f, "halt"),
+            Instruction::STOP => write!(f, "stop"),
+            Instruction::NOP => write!(f, "nop"),
+            Instruction::DI => write!(f, "di"),
+            Instruction::EI => write!(f, "ei"),
+        }
+    }
+}
+
+// RGBDS syntax with placeholders for the immediates, e.g. `ld a, [hl+]` or `jp nz, a16`
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.write_asm(f, None)
+    }
+}
+
+fn inc_dec_name(target: &IncDecTarget) -> &'static str {
+    match target {
+        IncDecTarget::A => "a",
+        IncDecTarget::B => "b",
+        IncDecTarget::C => "c",
+        IncDecTarget::D => "d",
+        IncDecTarget::E => "e",
+        IncDecTarget::H => "h",
+        IncDecTarget::L => "l",
+        IncDecTarget::HLI => "[hl]",
+        IncDecTarget::BC => "bc",
+        IncDecTarget::DE => "de",
+        IncDecTarget::HL => "hl",
+        IncDecTarget::SP => "sp",
+    }
+}
+
+fn load_byte_target_name(target: &LoadByteTarget) -> &'static str {
+    match target {
+        LoadByteTarget::A => "a",
+        LoadByteTarget::B => "b",
+        LoadByteTarget::C => "c",
+        LoadByteTarget::D => "d",
+        LoadByteTarget::E => "e",
+        LoadByteTarget::H => "h",
+        LoadByteTarget::L => "l",
+        LoadByteTarget::HLI => "[hl]",
+    }
+}
+
+fn indirect_name(indirect: &Indirect, n16: impl Fn(&str) -> String) -> String {
+    match indirect {
+        Indirect::BCIndirect => "[bc]".to_string(),
+        Indirect::DEIndirect => "[de]".to_string(),
+        Indirect::HLIndirectMinus => "[hl-]".to_string(),
+        Indirect::HLIndirectPlus => "[hl+]".to_string(),
+        Indirect::WordIndirect => format!("[{}]", n16("a16")),
+        Indirect::LastByteIndirect => "[c]".to_string(),
+    }
+}
+
+fn stack_name(target: &StackTarget) -> &'static str {
+    match target {
+        StackTarget::AF => "af",
+        StackTarget::BC => "bc",
+        StackTarget::DE => "de",
+        StackTarget::HL => "hl",
+    }
+}
+
+fn prefix_name(target: &PrefixTarget) -> &'static str {
+    match target {
+        PrefixTarget::A => "a",
+        PrefixTarget::B => "b",
+        PrefixTarget::C => "c",
+        PrefixTarget::D => "d",
+        PrefixTarget::E => "e",
+        PrefixTarget::H => "h",
+        PrefixTarget::L => "l",
+        PrefixTarget::HLI => "[hl]",
+    }
+}
@@ -1,3 +1,52 @@
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+
+use crate::cdl::{CodeDataLog, DATA, OPCODE, OPERAND};
+use crate::compat_palette::CompatPalette;
+use crate::disassembler;
+use crate::model::Model;
+use crate::save_state::{StateError, StateReader, StateWriter};
+use crate::sgb::Sgb;
+
+// Serial transfer data and control
+const SB: u16 = 0xFF01;
+const SC: u16 = 0xFF02;
+
+// Writing to BOOT unmaps the boot ROM
+const BOOT: u16 = 0xFF50;
+
+// CGB only registers
+const KEY0: u16 = 0xFF4C;
+const KEY1: u16 = 0xFF4D;
+const VBK: u16 = 0xFF4F;
+const SVBK: u16 = 0xFF70;
+const BCPS: u16 = 0xFF68;
+const BCPD: u16 = 0xFF69;
+const OCPS: u16 = 0xFF6A;
+const OCPD: u16 = 0xFF6B;
+const HDMA1: u16 = 0xFF51;
+const HDMA2: u16 = 0xFF52;
+const HDMA3: u16 = 0xFF53;
+const HDMA4: u16 = 0xFF54;
+const HDMA5: u16 = 0xFF55;
+
+// An access to a watched address, the old value is the one before a write
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub write: bool,
+    pub address: u16,
+    pub old: u8,
+    pub value: u8,
+}
+
+// Ranges watched by the debugger, and the accesses made to them since the last
+// `take_watch_hits`. Reads are recorded through `&self`, hence the RefCell
+struct Watch {
+    reads: Vec<RangeInclusive<u16>>,
+    writes: Vec<RangeInclusive<u16>>,
+    hits: RefCell<Vec<WatchHit>>,
+}
+
 pub struct Memory {
     // GB memory layout
     // 0x0000 - 0x3FFF: ROM bank 0
@@ -14,38 +63,704 @@ pub struct Memory {
     // 0xFFFF: Interrupt Enable Register
 
     pub memory: [u8; 0xFFFF+1],
+
+    // The cartridge, kept apart from `memory` as it can be larger than the address space.
+    // There is no MBC yet, so bank 0 and 1 are mapped at 0x0000 - 0x7FFF and writes there
+    // are ignored
+    rom: Vec<u8>,
+
+    // On the CGB, VRAM bank 0 and WRAM banks 0 and 1 live in `memory` like
+    // on the DMG, the extra banks are stored here and mapped in place of
+    // 0x8000 - 0x9FFF / 0xD000 - 0xDFFF when VBK / SVBK select them
+    vram_bank_1: [u8; 0x2000],
+    wram_banks: [[u8; 0x1000]; 6],
+    vram_bank: u8,
+    wram_bank: u8,
+
+    // KEY1: bit 0 is set by the game to request a speed switch on the next STOP
+    speed_switch_armed: bool,
+    pub double_speed: bool,
+
+    // CGB color palettes, 8 palettes of 4 BGR555 colors each, accessed through
+    // BCPS/BCPD and OCPS/OCPD. Bit 7 of the index registers enables auto-increment
+    bg_palette_ram: [u8; 64],
+    obj_palette_ram: [u8; 64],
+    bg_palette_index: u8,
+    obj_palette_index: u8,
+
+    // CGB VRAM DMA, copies blocks of 16 bytes from ROM/RAM to VRAM either all at
+    // once (general purpose) or one block per HBlank. The CPU is stalled while
+    // a block is copied, the stall is collected by the CPU after each step
+    hdma_source: u16,
+    hdma_destination: u16,
+    hdma_blocks_left: u8,
+    hdma_hblank_active: bool,
+    dma_stall_cycles: u32,
+
+    // Boot ROM mapped over the cartridge until the game is started by a write to BOOT.
+    // The CGB boot ROM also covers 0x0200 - 0x08FF, leaving the cartridge header visible
+    boot_rom: Option<Vec<u8>>,
+
+    // Buttons held down, bits 0-3 Right, Left, Up, Down and bits 4-7 A, B, Select, Start
+    buttons: u8,
+
+    // Bytes sent through the serial port, there is no link cable on the other side
+    pub serial_output: Vec<u8>,
+
+    pub model: Model,
+    // Present when running as a Super Game Boy, receives the packets written to P1
+    pub sgb: Option<Sgb>,
+    // A CGB running a DMG game locks the CGB registers and colors the DMG palettes
+    dmg_compatibility: bool,
+    // Only set while debugging with watchpoints, so the accesses cost one check otherwise
+    watch: Option<Box<Watch>>,
+    // What the game reads from LY instead of the line, for traces compared with other emulators
+    ly_stub: Option<u8>,
+    // Marks the ROM bytes run and read, data is marked by reads through `&self`
+    cdl: Option<RefCell<CodeDataLog>>,
 }
 
 impl Memory {
-    pub fn new() -> Memory {
-        Memory {
+    pub fn new(model: Model) -> Memory {
+        let mut memory = Memory {
             memory: [0; 0xFFFF+1],
+            rom: Vec::new(),
+            vram_bank_1: [0; 0x2000],
+            wram_banks: [[0; 0x1000]; 6],
+            vram_bank: 0,
+            wram_bank: 1,
+            speed_switch_armed: false,
+            double_speed: false,
+            // The boot ROM leaves the background palettes white
+            bg_palette_ram: [0xFF; 64],
+            obj_palette_ram: [0; 64],
+            bg_palette_index: 0,
+            obj_palette_index: 0,
+            hdma_source: 0,
+            hdma_destination: 0,
+            hdma_blocks_left: 0,
+            hdma_hblank_active: false,
+            dma_stall_cycles: 0,
+            boot_rom: None,
+            buttons: 0,
+            serial_output: Vec::new(),
+            model,
+            sgb: None,
+            dmg_compatibility: false,
+            watch: None,
+            ly_stub: None,
+            cdl: None,
+        };
+        memory.reset_io();
+        memory
+    }
+
+    // Set the I/O registers to the values left by the boot ROM
+    fn reset_io(&mut self) {
+        let io: [(u16, u8); 21] = [
+            (0xFF00, 0xCF), // P1
+            (0xFF02, 0x7E), // SC
+            (0xFF04, 0xAB), // DIV
+            (0xFF07, 0xF8), // TAC
+            (0xFF0F, 0xE1), // IF
+            (0xFF10, 0x80), // NR10
+            (0xFF11, 0xBF), // NR11
+            (0xFF12, 0xF3), // NR12
+            (0xFF14, 0xBF), // NR14
+            (0xFF16, 0x3F), // NR21
+            (0xFF19, 0xBF), // NR24
+            (0xFF1A, 0x7F), // NR30
+            (0xFF1B, 0xFF), // NR31
+            (0xFF1C, 0x9F), // NR32
+            (0xFF1E, 0xBF), // NR34
+            (0xFF24, 0x77), // NR50
+            (0xFF25, 0xF3), // NR51
+            (0xFF26, 0xF1), // NR52
+            (0xFF40, 0x91), // LCDC
+            (0xFF41, 0x85), // STAT
+            (0xFF47, 0xFC), // BGP
+        ];
+        for (address, value) in io {
+            self.memory[address as usize] = value;
+        }
+
+        if self.model.is_cgb() {
+            self.memory[0xFF04] = 0x00;
+            self.memory[0xFF41] = 0x81;
         }
     }
 
+    #[inline]
     pub fn read_byte(&self, address: u16) -> u8 {
+        let value = self.peek(address);
+        if self.watch.is_some() {
+            self.watch_access(false, address, value, value);
+        }
+        if self.cdl.is_some() {
+            self.log_data(address);
+        }
+        value
+    }
+
+    // Read of an opcode or operand by the CPU, which logs them as code instead of data
+    // and leaves them to the exec watchpoints
+    #[inline]
+    pub fn fetch_byte(&self, address: u16) -> u8 {
+        self.peek(address)
+    }
+
+    // Read without triggering the watchpoints, for the debugger
+    #[inline]
+    pub fn peek(&self, address: u16) -> u8 {
         match address {
-            0x0000..=0xFFFF => self.memory[address as usize],
-            _ => panic!("Address out of range: 0x{:x}", address),
+            0xFF00 => self.read_joypad(),
+            0xFF44 => self.ly_stub.unwrap_or(self.memory[0xFF44]),
+            0x0000..=0x00FF | 0x0200..=0x08FF if self.boot_rom_mapped(address) => {
+                self.boot_rom.as_ref().unwrap()[address as usize]
+            }
+            0x0000..=0x7FFF => self.read_rom(address),
+            0x8000..=0x9FFF if self.vram_bank == 1 => self.vram_bank_1[(address - 0x8000) as usize],
+            0xD000..=0xDFFF if self.wram_bank >= 2 => {
+                self.wram_banks[(self.wram_bank - 2) as usize][(address - 0xD000) as usize]
+            }
+            KEY1 if self.cgb_mode() => {
+                ((self.double_speed as u8) << 7) | 0x7E | self.speed_switch_armed as u8
+            }
+            VBK if self.cgb_mode() => 0xFE | self.vram_bank,
+            SVBK if self.cgb_mode() => 0xF8 | self.wram_bank,
+            BCPS if self.cgb_mode() => self.bg_palette_index | 0x40,
+            BCPD if self.cgb_mode() => self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize],
+            OCPS if self.cgb_mode() => self.obj_palette_index | 0x40,
+            OCPD if self.cgb_mode() => self.obj_palette_ram[(self.obj_palette_index & 0x3F) as usize],
+            // Bit 7 is clear while an HBlank DMA is running, the low bits are the blocks left - 1
+            HDMA5 if self.cgb_mode() => {
+                let blocks = self.hdma_blocks_left.wrapping_sub(1) & 0x7F;
+                if self.hdma_hblank_active { blocks } else { 0x80 | blocks }
+            }
+            KEY1 | VBK | SVBK | BCPS | BCPD | OCPS | OCPD | HDMA1..=HDMA5 => 0xFF,
+            _ => self.memory[address as usize],
         }
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        if self.watch.is_some() {
+            self.watch_access(true, address, self.peek(address), value);
+        }
+
         match address {
-            0x0000..=0xFFFF => self.memory[address as usize] = value,
+            0x0000..=0x7FFF => {}
+            0x8000..=0x9FFF if self.vram_bank == 1 => {
+                self.vram_bank_1[(address - 0x8000) as usize] = value
+            }
+            0xD000..=0xDFFF if self.wram_bank >= 2 => {
+                self.wram_banks[(self.wram_bank - 2) as usize][(address - 0xD000) as usize] = value
+            }
+            // Only the CGB boot ROM can lock the CGB registers for a DMG game
+            KEY0 if self.cgb_mode() && self.boot_rom.is_some() => {
+                self.dmg_compatibility = value & 0x04 != 0
+            }
+            KEY1 if self.cgb_mode() => self.speed_switch_armed = value & 0x01 != 0,
+            VBK if self.cgb_mode() => self.vram_bank = value & 0x01,
+            // Selecting bank 0 maps bank 1 like on real hardware
+            SVBK if self.cgb_mode() => self.wram_bank = (value & 0x07).max(1),
+            BCPS if self.cgb_mode() => self.bg_palette_index = value & 0xBF,
+            BCPD if self.cgb_mode() => {
+                self.bg_palette_ram[(self.bg_palette_index & 0x3F) as usize] = value;
+                self.bg_palette_index = increment_palette_index(self.bg_palette_index);
+            }
+            OCPS if self.cgb_mode() => self.obj_palette_index = value & 0xBF,
+            OCPD if self.cgb_mode() => {
+                self.obj_palette_ram[(self.obj_palette_index & 0x3F) as usize] = value;
+                self.obj_palette_index = increment_palette_index(self.obj_palette_index);
+            }
+            HDMA1 if self.cgb_mode() => {
+                self.hdma_source = (self.hdma_source & 0x00F0) | (value as u16) << 8
+            }
+            HDMA2 if self.cgb_mode() => {
+                self.hdma_source = (self.hdma_source & 0xFF00) | (value & 0xF0) as u16
+            }
+            HDMA3 if self.cgb_mode() => {
+                self.hdma_destination = (self.hdma_destination & 0x00F0) | ((value & 0x1F) as u16) << 8
+            }
+            HDMA4 if self.cgb_mode() => {
+                self.hdma_destination = (self.hdma_destination & 0x1F00) | (value & 0xF0) as u16
+            }
+            HDMA5 if self.cgb_mode() => self.start_hdma(value),
+            KEY0 | KEY1 | VBK | SVBK | BCPS | BCPD | OCPS | OCPD | HDMA1..=HDMA5 => {}
+            BOOT => {
+                if value != 0 && self.boot_rom.is_some() {
+                    debug!("Boot ROM finished, starting the cartridge");
+                    self.boot_rom = None;
+                }
+            }
+            // A transfer with the internal clock completes at once, as if nothing was connected
+            SC => {
+                if value & 0x81 == 0x81 {
+                    self.serial_output.push(self.memory[SB as usize]);
+                    self.memory[SC as usize] = value & 0x7F;
+                    self.request_interrupt(3);
+                } else {
+                    self.memory[SC as usize] = value;
+                }
+            }
+            // Only the select bits of P1 are writable, the SGB also listens to them
+            0xFF00 => {
+                self.memory[0xFF00] = (value & 0x30) | (self.memory[0xFF00] & 0xCF);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(value, &self.memory[0x8000..0xA000], self.memory[0xFF40]);
+                }
+            }
+            // Only the interrupt enable bits of STAT are writable, LY is read only
+            0xFF41 => self.memory[0xFF41] = (value & 0x78) | (self.memory[0xFF41] & 0x87),
+            0xFF44 => {}
+            // OAM DMA, copied all at once instead of over 160 cycles
+            0xFF46 => {
+                self.memory[0xFF46] = value;
+                let source = (value as u16) << 8;
+                for i in 0..0xA0 {
+                    self.memory[0xFE00 + i as usize] = self.dma_read(source + i);
+                }
+            }
+            _ => self.memory[address as usize] = value,
+        }
+    }
+
+    // Bit 4 low selects the directions, bit 5 low the other buttons, pressed buttons read 0.
+    // With no group selected the SGB answers with the selected joypad, which is how games detect it
+    fn read_joypad(&self) -> u8 {
+        let select = self.memory[0xFF00] & 0x30;
+        let mut pressed = 0;
+        if select & 0x10 == 0 {
+            pressed |= self.buttons & 0x0F;
+        }
+        if select & 0x20 == 0 {
+            pressed |= self.buttons >> 4;
+        }
+        let buttons = match &self.sgb {
+            Some(sgb) if select == 0x30 => sgb.joypad_id(),
+            _ => 0x0F & !pressed,
+        };
+        0xC0 | select | buttons
+    }
+
+    // Pressing a button requests the joypad interrupt
+    pub fn set_buttons(&mut self, buttons: u8) {
+        if buttons & !self.buttons != 0 {
+            self.request_interrupt(4);
+        }
+        self.buttons = buttons;
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    // Change the buttons without requesting an interrupt, for states that are restored
+    pub(crate) fn restore_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    // True if the CGB features are available to the game
+    pub fn cgb_mode(&self) -> bool {
+        self.model.is_cgb() && !self.dmg_compatibility
+    }
+
+    // Switch a CGB to DMG compatibility mode like the boot ROM does for DMG games.
+    // The colors go to BG palette 0 and OBJ palettes 0 and 1, where the GPU
+    // looks up the shades picked by BGP, OBP0 and OBP1
+    pub fn enter_dmg_compatibility(&mut self, palette: &CompatPalette) {
+        for i in 0..4 {
+            self.bg_palette_ram[i * 2..i * 2 + 2].copy_from_slice(&palette.bg[i].to_le_bytes());
+            self.obj_palette_ram[i * 2..i * 2 + 2].copy_from_slice(&palette.obj0[i].to_le_bytes());
+            self.obj_palette_ram[8 + i * 2..8 + i * 2 + 2].copy_from_slice(&palette.obj1[i].to_le_bytes());
+        }
+        self.dmg_compatibility = true;
+    }
+
+    // Called when the CPU executes STOP. Returns true if the speed was switched
+    pub fn try_speed_switch(&mut self) -> bool {
+        if self.cgb_mode() && self.speed_switch_armed {
+            self.speed_switch_armed = false;
+            self.double_speed = !self.double_speed;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn start_hdma(&mut self, value: u8) {
+        // Writing with bit 7 clear while an HBlank DMA runs cancels it
+        if self.hdma_hblank_active && value & 0x80 == 0 {
+            self.hdma_hblank_active = false;
+            return;
+        }
+
+        self.hdma_blocks_left = (value & 0x7F) + 1;
+        if value & 0x80 != 0 {
+            self.hdma_hblank_active = true;
+            // With the LCD off there are no HBlanks, the first block is copied right away
+            if self.memory[0xFF40] & 0x80 == 0 {
+                self.transfer_hdma_block();
+            }
+        } else {
+            while self.hdma_blocks_left > 0 {
+                self.transfer_hdma_block();
+            }
+        }
+    }
+
+    // The DMA engines don't trigger the watchpoints, but the CDL still sees their source as data
+    fn dma_read(&self, address: u16) -> u8 {
+        if self.cdl.is_some() {
+            self.log_data(address);
+        }
+        self.peek(address)
+    }
+
+    fn transfer_hdma_block(&mut self) {
+        for i in 0..0x10 {
+            let value = self.dma_read(self.hdma_source.wrapping_add(i));
+            let offset = (self.hdma_destination.wrapping_add(i) & 0x1FFF) as usize;
+            if self.vram_bank == 1 {
+                self.vram_bank_1[offset] = value;
+            } else {
+                self.memory[0x8000 + offset] = value;
+            }
+        }
+        self.hdma_source = self.hdma_source.wrapping_add(0x10);
+        self.hdma_destination = (self.hdma_destination + 0x10) & 0x1FF0;
+        self.hdma_blocks_left -= 1;
+        if self.hdma_blocks_left == 0 {
+            self.hdma_hblank_active = false;
+        }
+
+        // A block takes 8 µs, which is twice as many CPU cycles in double speed mode
+        self.dma_stall_cycles += if self.double_speed { 64 } else { 32 };
+    }
+
+    // Called by the GPU at the start of every HBlank
+    pub fn hblank_dma(&mut self) {
+        if self.hdma_hblank_active {
+            self.transfer_hdma_block();
+        }
+    }
+
+    // Get and reset the cycles the CPU was stalled by DMA since the last call
+    pub fn take_dma_stall_cycles(&mut self) -> u32 {
+        std::mem::take(&mut self.dma_stall_cycles)
+    }
+
+    // The buttons held and the serial output aren't part of the state, they belong to the frontend
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.memory);
+        state.bytes(&self.vram_bank_1);
+        for bank in &self.wram_banks {
+            state.bytes(bank);
+        }
+        state.u8(self.vram_bank);
+        state.u8(self.wram_bank);
+        state.bool(self.speed_switch_armed);
+        state.bool(self.double_speed);
+        state.bytes(&self.bg_palette_ram);
+        state.bytes(&self.obj_palette_ram);
+        state.u8(self.bg_palette_index);
+        state.u8(self.obj_palette_index);
+        state.u16(self.hdma_source);
+        state.u16(self.hdma_destination);
+        state.u8(self.hdma_blocks_left);
+        state.bool(self.hdma_hblank_active);
+        state.u32(self.dma_stall_cycles);
+        state.bool(self.boot_rom.is_some());
+        if let Some(boot_rom) = &self.boot_rom {
+            state.vec(boot_rom);
+        }
+        state.bool(self.dmg_compatibility);
+        state.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            sgb.save_state(state);
+        }
+    }
 
-            _ => panic!("Address out of range: 0x{:x}", address),
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes(&mut self.memory)?;
+        state.bytes(&mut self.vram_bank_1)?;
+        for bank in &mut self.wram_banks {
+            state.bytes(bank)?;
+        }
+        self.vram_bank = state.u8()?;
+        self.wram_bank = state.u8()?;
+        if self.vram_bank > 1 || !(1..=7).contains(&self.wram_bank) {
+            return Err(StateError::Corrupt("memory bank"));
+        }
+        self.speed_switch_armed = state.bool()?;
+        self.double_speed = state.bool()?;
+        state.bytes(&mut self.bg_palette_ram)?;
+        state.bytes(&mut self.obj_palette_ram)?;
+        self.bg_palette_index = state.u8()?;
+        self.obj_palette_index = state.u8()?;
+        self.hdma_source = state.u16()?;
+        self.hdma_destination = state.u16()?;
+        self.hdma_blocks_left = state.u8()?;
+        self.hdma_hblank_active = state.bool()?;
+        // The destination is a block of VRAM, and an HBlank DMA always has a block left
+        let destination_valid = self.hdma_destination <= 0x1FF0 && self.hdma_destination & 0x0F == 0;
+        if !destination_valid || self.hdma_blocks_left > 0x80 || (self.hdma_hblank_active && self.hdma_blocks_left == 0) {
+            return Err(StateError::Corrupt("hdma"));
         }
+        self.dma_stall_cycles = state.u32()?;
+        self.boot_rom = if state.bool()? { Some(state.vec()?) } else { None };
+        self.dmg_compatibility = state.bool()?;
+        self.sgb = if state.bool()? {
+            let mut sgb = Sgb::new(false);
+            sgb.load_state(state)?;
+            Some(sgb)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) {
+        self.rom = rom.to_vec();
     }
 
-    pub fn load_rom(&mut self, rom: &Vec<u8>) {
-        self.memory[0..rom.len()].copy_from_slice(rom);
-        // Copy the rom vector into the rom_bank_0 and rom_bank_1 arrays like a single contiguous array
-        // self.rom_bank_0.copy_from_slice(&rom[0..0x4000]);
-        // self.rom_bank_1.copy_from_slice(&rom[0x4000..0x8000]);
+    // Bytes past the end of a small ROM read as an open bus
+    fn read_rom(&self, address: u16) -> u8 {
+        let offset = disassembler::offset_of(self.rom_bank() as usize, address);
+        offset.and_then(|offset| self.rom.get(offset)).copied().unwrap_or(0xFF)
     }
 
-    pub fn tileset(&self) -> &[u8] {
-        &self.memory[0x8000..0x9800]
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    fn boot_rom_mapped(&self, address: u16) -> bool {
+        self.boot_rom.as_ref().is_some_and(|boot_rom| (address as usize) < boot_rom.len())
+    }
+
+    // Cartridge RAM, saved to disk for games with a battery. Without bank switching
+    // only the 8 KiB mapped at 0xA000 - 0xBFFF are available
+    pub fn external_ram(&self) -> &[u8] {
+        &self.memory[0xA000..0xC000]
+    }
+
+    pub fn load_external_ram(&mut self, ram: &[u8]) {
+        let length = ram.len().min(0x2000);
+        self.memory[0xA000..0xA000 + length].copy_from_slice(&ram[..length]);
+    }
+
+    // Get the whole 8 KiB of a VRAM bank regardless of the bank mapped by VBK
+    pub fn vram(&self, bank: u8) -> &[u8] {
+        match bank {
+            0 => &self.memory[0x8000..0xA000],
+            _ => &self.vram_bank_1,
+        }
+    }
+
+    pub fn bg_palette_ram(&self) -> &[u8] {
+        &self.bg_palette_ram
+    }
+
+    pub fn obj_palette_ram(&self) -> &[u8] {
+        &self.obj_palette_ram
+    }
+
+    // ROM bank mapped at 0x4000 - 0x7FFF, always 1 as there is no MBC yet
+    pub fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    // Make LY read `value` instead of the line being drawn, the GPU still sees the line
+    pub fn stub_ly(&mut self, value: Option<u8>) {
+        self.ly_stub = value;
+    }
+
+    // Record the reads and writes made to the ranges, nothing is recorded when both are empty
+    pub fn set_watch(&mut self, reads: Vec<RangeInclusive<u16>>, writes: Vec<RangeInclusive<u16>>) {
+        self.watch = if reads.is_empty() && writes.is_empty() {
+            None
+        } else {
+            Some(Box::new(Watch { reads, writes, hits: RefCell::new(Vec::new()) }))
+        };
+    }
+
+    #[cold]
+    fn watch_access(&self, write: bool, address: u16, old: u8, value: u8) {
+        let Some(watch) = &self.watch else { return };
+        let ranges = if write { &watch.writes } else { &watch.reads };
+        if ranges.iter().any(|range| range.contains(&address)) {
+            watch.hits.borrow_mut().push(WatchHit { write, address, old, value });
+        }
+    }
+
+    pub fn set_cdl(&mut self, cdl: Option<CodeDataLog>) {
+        self.cdl = cdl.map(RefCell::new);
+    }
+
+    pub(crate) fn has_cdl(&self) -> bool {
+        self.cdl.is_some()
+    }
+
+    pub fn cdl(&self) -> Option<std::cell::Ref<'_, CodeDataLog>> {
+        self.cdl.as_ref().map(RefCell::borrow)
+    }
+
+    // Offset in the ROM file of an address, None outside of the ROM or in the boot ROM
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        if matches!(address, 0x0000..=0x00FF | 0x0200..=0x08FF) && self.boot_rom_mapped(address) {
+            return None;
+        }
+        disassembler::offset_of(self.rom_bank() as usize, address)
+    }
+
+    #[cold]
+    fn log_data(&self, address: u16) {
+        let (Some(cdl), Some(offset)) = (&self.cdl, self.rom_offset(address)) else { return };
+        cdl.borrow_mut().mark(offset, DATA);
+    }
+
+    // An instruction ran from `pc`, the byte after a CB prefix counts as an operand
+    pub(crate) fn log_code(&mut self, pc: u16, length: u16) {
+        for i in 0..length {
+            let address = pc.wrapping_add(i);
+            let Some(offset) = self.rom_offset(address) else { continue };
+            let flag = if i == 0 { OPCODE } else { OPERAND };
+            if let Some(cdl) = &mut self.cdl {
+                cdl.get_mut().mark(offset, flag);
+            }
+        }
+    }
+
+    // Get and reset the accesses to the watched ranges since the last call
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        match &mut self.watch {
+            Some(watch) => std::mem::take(watch.hits.get_mut()),
+            None => Vec::new(),
+        }
+    }
+
+    // Set the bit of the interrupt in IF (0: VBlank, 1: STAT, 2: Timer, 3: Serial, 4: Joypad)
+    pub fn request_interrupt(&mut self, bit: u8) {
+        self.memory[0xFF0F] |= 1 << bit;
+    }
+
+    pub fn acknowledge_interrupt(&mut self, bit: u8) {
+        self.memory[0xFF0F] &= !(1 << bit);
+    }
+}
+
+// Advance a BCPS/OCPS index if its auto-increment bit is set
+fn increment_palette_index(index: u8) -> u8 {
+    if index & 0x80 != 0 {
+        0x80 | ((index + 1) & 0x3F)
+    } else {
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_corrupt_hdma() {
+        for (destination, blocks_left, hblank_active) in [(0xFFFF, 1, false), (0x0008, 1, false), (0, 0x81, false), (0, 0, true)] {
+            let mut memory = Memory::new(Model::Cgb);
+            memory.hdma_destination = destination;
+            memory.hdma_blocks_left = blocks_left;
+            memory.hdma_hblank_active = hblank_active;
+            let mut state = StateWriter::new();
+            memory.save_state(&mut state);
+            let data = state.into_bytes();
+            let result = Memory::new(Model::Cgb).load_state(&mut StateReader::new(&data));
+            assert_eq!(result, Err(StateError::Corrupt("hdma")));
+        }
+    }
+
+    #[test]
+    fn test_cgb_banking() {
+        let mut memory = Memory::new(Model::Cgb);
+
+        memory.write_byte(0xD000, 0x11);
+        memory.write_byte(SVBK, 0x03);
+        assert_eq!(memory.read_byte(0xD000), 0x00);
+        memory.write_byte(0xD000, 0x33);
+        memory.write_byte(SVBK, 0x00);
+        assert_eq!(memory.read_byte(SVBK), 0xF9);
+        assert_eq!(memory.read_byte(0xD000), 0x11);
+
+        memory.write_byte(VBK, 0x01);
+        memory.write_byte(0x8000, 0x42);
+        memory.write_byte(VBK, 0x00);
+        assert_eq!(memory.read_byte(0x8000), 0x00);
+    }
+
+    #[test]
+    fn test_palette_auto_increment() {
+        let mut memory = Memory::new(Model::Cgb);
+
+        memory.write_byte(BCPS, 0xBE);
+        memory.write_byte(BCPD, 0x12);
+        memory.write_byte(BCPD, 0x34);
+        assert_eq!(memory.read_byte(BCPS), 0xC0);
+        memory.write_byte(BCPD, 0x56);
+        assert_eq!(memory.bg_palette_ram()[0x3E], 0x12);
+        assert_eq!(memory.bg_palette_ram()[0x3F], 0x34);
+        assert_eq!(memory.bg_palette_ram()[0x00], 0x56);
+
+        memory.write_byte(OCPS, 0x02);
+        memory.write_byte(OCPD, 0x56);
+        memory.write_byte(OCPD, 0x78);
+        assert_eq!(memory.read_byte(OCPD), 0x78);
+        assert_eq!(memory.obj_palette_ram()[0x02], 0x78);
+    }
+
+    #[test]
+    fn test_hdma() {
+        let mut memory = Memory::new(Model::Cgb);
+        for i in 0..0x20 {
+            memory.write_byte(0xC000 + i, i as u8);
+        }
+        memory.write_byte(HDMA1, 0xC0);
+        memory.write_byte(HDMA2, 0x00);
+        memory.write_byte(HDMA3, 0x01);
+        memory.write_byte(HDMA4, 0x00);
+
+        // General purpose DMA copies everything at once
+        memory.write_byte(HDMA5, 0x01);
+        assert_eq!(memory.read_byte(0x811F), 0x1F);
+        assert_eq!(memory.read_byte(HDMA5), 0xFF);
+        assert_eq!(memory.take_dma_stall_cycles(), 64);
+
+        // HBlank DMA copies one block per HBlank and can be cancelled
+        memory.write_byte(HDMA2, 0x00);
+        memory.write_byte(HDMA3, 0x02);
+        memory.write_byte(HDMA4, 0x00);
+        memory.write_byte(HDMA5, 0x82);
+        assert_eq!(memory.read_byte(HDMA5), 0x02);
+        memory.hblank_dma();
+        assert_eq!(memory.read_byte(0x820F), 0x0F);
+        assert_eq!(memory.read_byte(0x8210), 0x00);
+        assert_eq!(memory.read_byte(HDMA5), 0x01);
+        memory.write_byte(HDMA5, 0x00);
+        assert_eq!(memory.read_byte(HDMA5), 0x81);
+        memory.hblank_dma();
+        assert_eq!(memory.read_byte(0x8210), 0x00);
+    }
+
+    #[test]
+    fn test_speed_switch() {
+        let mut memory = Memory::new(Model::Cgb);
+        assert!(!memory.try_speed_switch());
+
+        memory.write_byte(KEY1, 0x01);
+        assert_eq!(memory.read_byte(KEY1), 0x7F);
+        assert!(memory.try_speed_switch());
+        assert_eq!(memory.read_byte(KEY1), 0xFE);
+
+        let mut memory = Memory::new(Model::Dmg);
+        memory.write_byte(KEY1, 0x01);
+        assert!(!memory.try_speed_switch());
+        assert_eq!(memory.read_byte(KEY1), 0xFF);
     }
 }
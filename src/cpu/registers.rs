@@ -1,4 +1,7 @@
 
+use crate::model::Model;
+use crate::save_state::{StateError, StateReader, StateWriter};
+
 #[derive(Debug)]
 pub struct Registers {
     // 8-bit registers
@@ -22,8 +25,31 @@ pub struct Registers {
 }
 
 impl Registers {
-    pub fn new() -> Registers {
-        Registers {
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&[self.a, self.b, self.c, self.d, self.e, self.h, self.l]);
+        state.u16(self.pc);
+        state.u16(self.sp);
+        for flag in [self.flag_z, self.flag_n, self.flag_h, self.flag_c] {
+            state.bool(flag);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut bytes = [0; 7];
+        state.bytes(&mut bytes)?;
+        [self.a, self.b, self.c, self.d, self.e, self.h, self.l] = bytes;
+        self.pc = state.u16()?;
+        self.sp = state.u16()?;
+        self.flag_z = state.bool()?;
+        self.flag_n = state.bool()?;
+        self.flag_h = state.bool()?;
+        self.flag_c = state.bool()?;
+        Ok(())
+    }
+
+    // Create the registers with the values left by the boot ROM of the model
+    pub fn new(model: Model) -> Registers {
+        let mut registers = Registers {
             a: 0,
             b: 0,
             c: 0,
@@ -37,7 +63,37 @@ impl Registers {
             flag_n: false,
             flag_h: false,
             flag_c: false,
+        };
+
+        match model {
+            Model::Dmg => {
+                registers.set_af(0x01B0);
+                registers.set_bc(0x0013);
+                registers.set_de(0x00D8);
+                registers.set_hl(0x014D);
+            }
+            Model::Mgb => {
+                registers.set_af(0xFFB0);
+                registers.set_bc(0x0013);
+                registers.set_de(0x00D8);
+                registers.set_hl(0x014D);
+            }
+            Model::Sgb => {
+                registers.set_af(0x0100);
+                registers.set_bc(0x0014);
+                registers.set_de(0x0000);
+                registers.set_hl(0xC060);
+            }
+            // A = 0x11 is how games detect they are running on a CGB
+            Model::Cgb => {
+                registers.set_af(0x1180);
+                registers.set_bc(0x0000);
+                registers.set_de(0xFF56);
+                registers.set_hl(0x000D);
+            }
         }
+
+        registers
     }
 
     // Get the value of the 16-bit register af
@@ -116,16 +172,19 @@ impl Registers {
     }
 
     // Increment the program counter by n
+    #[allow(dead_code)]
     pub fn inc_pc(&mut self, n: u8) {
         self.pc += n as u16;
     }
 
     // Increment the stack pointer by n
+    #[allow(dead_code)]
     pub fn inc_sp(&mut self, n: u8) {
         self.sp += n as u16;
     }
 
     // Decrement the stack pointer by n
+    #[allow(dead_code)]
     pub fn dec_sp(&mut self, n: u8) {
         self.sp -= n as u16;
     }
@@ -0,0 +1,887 @@
+// Command line debugger of `--debug`. The frontend reads the commands and prints what
+// `execute` returns, then calls `run` while the console is running to stop it at the
+// breakpoints and watchpoints. Addresses and values are hex, with or without a $ or 0x
+// prefix, or labels of the symbols loaded with `set_symbols`. Counts are decimal
+
+pub mod condition;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Write};
+
+use crate::call_stack::CallKind;
+use crate::cpu::instructions::Instruction;
+use crate::cpu::memory::WatchHit;
+use crate::disassembler;
+use crate::gameboy::GameBoy;
+use crate::symbols::Symbols;
+use condition::Condition;
+
+pub const HELP: &str = "\
+Commands:
+  s, step [count]          Run one instruction, or count of them
+  n, next                  Run one instruction, stepping over calls and restarts
+  c, continue              Run until a breakpoint
+  b, break <address> [if <condition>]  Stop before the instruction at the address runs, e.g. break Main.loop
+  watch <address>[-<end>] [read|write|access|change|exec] [if <condition>]
+                           Stop after an access to the addresses, writes by default
+  d, delete [address]      Remove the breakpoint and watchpoints at the address, or all of them
+  breakpoints              List the breakpoints and watchpoints
+  bt, backtrace            Show the calls that led to PC, innermost first
+  r, registers             Show the registers and flags
+  x <address> [length]     Dump memory, 64 bytes by default
+  l, list [address] [count]  Disassemble from the address, around PC by default
+  set <register> <value>   Change a, b, c, d, e, h, l, af, bc, de, hl, sp, pc or a flag zf, nf, hf, cf
+  w, write <address> <byte>...  Write bytes to memory
+  h, help                  Show this message
+  q, quit                  Stop the emulator
+An empty line runs the last command again
+
+Conditions compare registers, flags, [address] and the value accessed by a watchpoint, e.g.
+  a == 3 && zf    [hl] != value    (bc > $100 || !cf)
+Names of registers win over labels and labels over hex numbers, write $c for 0xc";
+
+// Instructions before PC shown by `list`
+const HISTORY: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    FlagZ,
+    FlagN,
+    FlagH,
+    FlagC,
+}
+
+impl Register {
+    pub fn from_name(name: &str) -> Option<Register> {
+        match name.to_ascii_lowercase().as_str() {
+            "a" => Some(Register::A),
+            "b" => Some(Register::B),
+            "c" => Some(Register::C),
+            "d" => Some(Register::D),
+            "e" => Some(Register::E),
+            "h" => Some(Register::H),
+            "l" => Some(Register::L),
+            "af" => Some(Register::AF),
+            "bc" => Some(Register::BC),
+            "de" => Some(Register::DE),
+            "hl" => Some(Register::HL),
+            "sp" => Some(Register::SP),
+            "pc" => Some(Register::PC),
+            "zf" | "flag_z" => Some(Register::FlagZ),
+            "nf" | "flag_n" => Some(Register::FlagN),
+            "hf" | "flag_h" => Some(Register::FlagH),
+            "cf" | "flag_c" => Some(Register::FlagC),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::A => "a",
+            Register::B => "b",
+            Register::C => "c",
+            Register::D => "d",
+            Register::E => "e",
+            Register::H => "h",
+            Register::L => "l",
+            Register::AF => "af",
+            Register::BC => "bc",
+            Register::DE => "de",
+            Register::HL => "hl",
+            Register::SP => "sp",
+            Register::PC => "pc",
+            Register::FlagZ => "zf",
+            Register::FlagN => "nf",
+            Register::FlagH => "hf",
+            Register::FlagC => "cf",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // Reads and writes
+    Access,
+    // Writes of a value different from the one in memory
+    Change,
+    // The CPU running an instruction in the range
+    Execute,
+}
+
+impl WatchKind {
+    pub fn from_name(name: &str) -> Option<WatchKind> {
+        match name {
+            "read" => Some(WatchKind::Read),
+            "write" => Some(WatchKind::Write),
+            "access" => Some(WatchKind::Access),
+            "change" => Some(WatchKind::Change),
+            "exec" => Some(WatchKind::Execute),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+            WatchKind::Change => "change",
+            WatchKind::Execute => "exec",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub start: u16,
+    pub end: u16,
+    pub condition: Option<Condition>,
+}
+
+impl Watchpoint {
+    fn contains(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+
+    fn matches(&self, hit: &WatchHit) -> bool {
+        let kind = match self.kind {
+            WatchKind::Read => !hit.write,
+            WatchKind::Write | WatchKind::Access => hit.write || self.kind == WatchKind::Access,
+            WatchKind::Change => hit.write && hit.old != hit.value,
+            WatchKind::Execute => false,
+        };
+        kind && self.contains(hit.address)
+    }
+}
+
+// `change c0a0-c0af if a == $3`
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:04x}", self.kind.name(), self.start)?;
+        if self.end != self.start {
+            write!(f, "-{:04x}", self.end)?;
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Step(u32),
+    Next,
+    Continue,
+    Break { address: u16, condition: Option<Condition> },
+    Watch(Watchpoint),
+    Delete(Option<u16>),
+    Breakpoints,
+    Backtrace,
+    Registers,
+    Memory { address: u16, length: u16 },
+    List { address: Option<u16>, count: usize },
+    Set(Register, u16),
+    Write { address: u16, bytes: Vec<u8> },
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        Command::parse_with(line, &Symbols::default())
+    }
+
+    // Addresses can be labels of the symbols
+    pub fn parse_with(line: &str, symbols: &Symbols) -> Result<Command, String> {
+        let (line, condition) = match line.split_once(" if ") {
+            Some((line, condition)) => (line, Some(Condition::parse_with(condition, symbols)?)),
+            None => (line, None),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, arguments)) = words.split_first() else {
+            return Err("no command".to_string());
+        };
+        let argument = |index: usize| arguments.get(index).copied();
+        let address = |index: usize| {
+            let text = argument(index).ok_or("an address is missing")?;
+            parse_address(text, symbols).ok_or(format!("'{}' isn't an address", text))
+        };
+        let count = |index: usize, default: u32| match argument(index) {
+            Some(text) => text.parse().map_err(|_| format!("'{}' isn't a count", text)),
+            None => Ok(default),
+        };
+
+        let command = match name {
+            "s" | "step" => Command::Step(count(0, 1)?),
+            "n" | "next" => Command::Next,
+            "c" | "continue" => Command::Continue,
+            "b" | "break" => return Ok(Command::Break { address: address(0)?, condition }),
+            "watch" => {
+                let range = argument(0).ok_or("an address is missing")?;
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (parse_address(start, symbols), parse_address(end, symbols)),
+                    None => (parse_address(range, symbols), parse_address(range, symbols)),
+                };
+                let (Some(start), Some(end)) = (start, end) else {
+                    return Err(format!("'{}' isn't an address or a range like c000-c0ff", range));
+                };
+                if end < start {
+                    return Err(format!("the range {} ends before it starts", range));
+                }
+                let kind = match argument(1) {
+                    Some(name) => WatchKind::from_name(name)
+                        .ok_or(format!("'{}' isn't read, write, access, change or exec", name))?,
+                    None => WatchKind::Write,
+                };
+                return Ok(Command::Watch(Watchpoint { kind, start, end, condition }));
+            }
+            "d" | "delete" => Command::Delete(if arguments.is_empty() { None } else { Some(address(0)?) }),
+            "breakpoints" => Command::Breakpoints,
+            "bt" | "backtrace" => Command::Backtrace,
+            "r" | "registers" => Command::Registers,
+            "x" => Command::Memory { address: address(0)?, length: count(1, 64)?.min(0xFFFF) as u16 },
+            "l" | "list" => Command::List {
+                address: if arguments.is_empty() { None } else { Some(address(0)?) },
+                count: count(1, 10)? as usize,
+            },
+            "set" => {
+                let name = argument(0).ok_or("a register is missing")?;
+                let register = Register::from_name(name).ok_or(format!("'{}' isn't a register or flag", name))?;
+                Command::Set(register, address(1)?)
+            }
+            "w" | "write" => {
+                let bytes = arguments[1.min(arguments.len())..]
+                    .iter()
+                    .map(|text| parse_hex(text).filter(|&value| value <= 0xFF).map(|value| value as u8))
+                    .collect::<Option<Vec<u8>>>()
+                    .ok_or("the bytes to write should be hex numbers up to ff")?;
+                if bytes.is_empty() {
+                    return Err("the bytes to write are missing".to_string());
+                }
+                Command::Write { address: address(0)?, bytes }
+            }
+            "h" | "help" => Command::Help,
+            "q" | "quit" => Command::Quit,
+            _ => return Err(format!("unknown command '{}', try help", name)),
+        };
+        match condition {
+            Some(_) => Err("only break and watch take a condition".to_string()),
+            None => Ok(command),
+        }
+    }
+}
+
+pub(crate) fn parse_hex(text: &str) -> Option<u16> {
+    let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// A label or a hex number
+pub(crate) fn parse_address(text: &str, symbols: &Symbols) -> Option<u16> {
+    symbols.location(text).map(|(_, address)| address).or_else(|| parse_hex(text))
+}
+
+// What the frontend does after a command
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Output(String),
+    // The console runs, call `run` until it stops
+    Resume,
+    Quit,
+}
+
+// How far the console runs before stopping again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resume {
+    Steps(u32),
+    Until(u16),
+    Continue,
+}
+
+// Why the console stopped, watchpoints are given by their index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    // The steps or the `next` are done
+    Done,
+    Breakpoint(u16),
+    Execute { watchpoint: usize, pc: u16 },
+    Access { watchpoint: usize, hit: WatchHit },
+    Interrupted,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Done => Ok(()),
+            Stop::Breakpoint(pc) => write!(f, "Breakpoint at {:04x}", pc),
+            Stop::Execute { watchpoint, pc } => write!(f, "Watchpoint {}: exec at {:04x}", watchpoint + 1, pc),
+            Stop::Access { watchpoint, hit } if hit.write => write!(
+                f,
+                "Watchpoint {}: write of ${:02x} to {:04x}, was ${:02x}",
+                watchpoint + 1,
+                hit.value,
+                hit.address,
+                hit.old
+            ),
+            Stop::Access { watchpoint, hit } => {
+                write!(f, "Watchpoint {}: read of ${:02x} from {:04x}", watchpoint + 1, hit.value, hit.address)
+            }
+            Stop::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeMap<u16, Option<Condition>>,
+    watchpoints: Vec<Watchpoint>,
+    // Addresses of the last instructions run, newest last
+    history: VecDeque<u16>,
+    resume: Option<Resume>,
+    // An instruction ran since resuming, so the breakpoint it stopped at doesn't stop it again
+    moved: bool,
+    // Repeated by an empty line
+    last_command: Option<Command>,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.resume.is_some()
+    }
+
+    // Stop running, e.g. when the user breaks in from the window
+    pub fn interrupt(&mut self) {
+        self.resume = None;
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16, condition: Option<Condition>) {
+        self.breakpoints.insert(address, condition);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    // Remove the watchpoints of the kind on exactly that range
+    pub fn remove_watchpoint(&mut self, kind: WatchKind, start: u16, end: u16) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| (watchpoint.kind, watchpoint.start, watchpoint.end) != (kind, start, end));
+        self.watchpoints.len() < count
+    }
+
+    pub fn watchpoint(&self, index: usize) -> Option<&Watchpoint> {
+        self.watchpoints.get(index)
+    }
+
+    // Labels for the addresses typed and shown
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Parse and execute a line typed by the user
+    pub fn execute_line(&mut self, gameboy: &mut GameBoy, line: &str) -> Result<Reply, String> {
+        let command = if line.trim().is_empty() {
+            self.last_command.clone().ok_or("no command to repeat")?
+        } else {
+            Command::parse_with(line, &self.symbols)?
+        };
+        self.last_command = Some(command.clone());
+        Ok(self.execute(gameboy, command))
+    }
+
+    pub fn execute(&mut self, gameboy: &mut GameBoy, command: Command) -> Reply {
+        let output = match command {
+            Command::Step(count) => return self.start(Resume::Steps(count.max(1))),
+            Command::Next => {
+                let pc = gameboy.cpu.registers.pc;
+                let (bytes, instruction) = decode(gameboy, pc);
+                return match instruction {
+                    Some(Instruction::CALL(_) | Instruction::RST(_)) => {
+                        self.start(Resume::Until(pc.wrapping_add(bytes.len() as u16)))
+                    }
+                    _ => self.start(Resume::Steps(1)),
+                };
+            }
+            Command::Continue => return self.start(Resume::Continue),
+            Command::Break { address, condition } => {
+                let mut output = format!("Breakpoint at {:04x}", address);
+                if let Some(label) = self.symbols.label(gameboy.rom_bank(), address) {
+                    let _ = write!(output, " ({})", label);
+                }
+                if let Some(condition) = &condition {
+                    let _ = write!(output, " if {}", condition);
+                }
+                self.add_breakpoint(address, condition);
+                output
+            }
+            Command::Watch(watchpoint) => {
+                let output = format!("Watchpoint {}: {}", self.watchpoints.len() + 1, watchpoint);
+                self.add_watchpoint(watchpoint);
+                output
+            }
+            Command::Delete(Some(address)) => {
+                let watchpoints = self.watchpoints.len();
+                self.watchpoints.retain(|watchpoint| watchpoint.start != address);
+                let breakpoint = self.breakpoints.remove(&address).is_some();
+                match breakpoint || watchpoints > self.watchpoints.len() {
+                    true => format!("Deleted the breakpoint and watchpoints at {:04x}", address),
+                    false => format!("No breakpoint or watchpoint at {:04x}", address),
+                }
+            }
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                self.watchpoints.clear();
+                "Deleted all breakpoints and watchpoints".to_string()
+            }
+            Command::Breakpoints if self.breakpoints.is_empty() && self.watchpoints.is_empty() => {
+                "No breakpoints or watchpoints".to_string()
+            }
+            Command::Breakpoints => {
+                let mut lines = Vec::new();
+                for (&address, condition) in &self.breakpoints {
+                    match condition {
+                        Some(condition) => lines.push(format!("{}  if {}", self.line(gameboy, address), condition)),
+                        None => lines.push(self.line(gameboy, address)),
+                    }
+                }
+                for (i, watchpoint) in self.watchpoints.iter().enumerate() {
+                    lines.push(format!("Watchpoint {}: {}", i + 1, watchpoint));
+                }
+                lines.join("\n")
+            }
+            Command::Backtrace => self.backtrace(gameboy),
+            Command::Registers => registers(gameboy),
+            Command::Memory { address, length } => hexdump(gameboy, address, length),
+            Command::List { address, count } => self.list(gameboy, address, count),
+            Command::Set(register, value) => {
+                set_register(gameboy, register, value);
+                registers(gameboy)
+            }
+            Command::Write { address, bytes } => {
+                for (i, &byte) in bytes.iter().enumerate() {
+                    gameboy.cpu.memory.write_byte(address.wrapping_add(i as u16), byte);
+                }
+                hexdump(gameboy, address, bytes.len() as u16)
+            }
+            Command::Help => HELP.to_string(),
+            Command::Quit => return Reply::Quit,
+        };
+        Reply::Output(output)
+    }
+
+    fn start(&mut self, resume: Resume) -> Reply {
+        self.resume(resume);
+        Reply::Resume
+    }
+
+    pub fn resume(&mut self, resume: Resume) {
+        self.resume = Some(resume);
+        self.moved = false;
+    }
+
+    // Run until the console stops or completes a frame. Returns where it stopped with the
+    // registers, or None when the frame is done and it is still running
+    pub fn run(&mut self, gameboy: &mut GameBoy) -> Option<String> {
+        self.run_until_stop(gameboy).map(|stop| self.describe(gameboy, &stop))
+    }
+
+    // `run` for frontends that show the stop themselves
+    pub fn run_until_stop(&mut self, gameboy: &mut GameBoy) -> Option<Stop> {
+        self.watch_memory(gameboy);
+        loop {
+            let pc = gameboy.cpu.registers.pc;
+            let stop = match self.resume? {
+                Resume::Steps(0) => Some(Stop::Done),
+                Resume::Until(address) if address == pc && self.moved => Some(Stop::Done),
+                // The instruction a breakpoint stopped at runs when resuming
+                _ if self.moved => self.breakpoint_hit(gameboy, pc),
+                _ => None,
+            };
+            if stop.is_some() {
+                self.resume = None;
+                return stop;
+            }
+
+            self.moved = true;
+            if let Some(Resume::Steps(count)) = &mut self.resume {
+                *count -= 1;
+            }
+            if self.history.back() != Some(&pc) {
+                if self.history.len() == HISTORY {
+                    self.history.pop_front();
+                }
+                self.history.push_back(pc);
+            }
+
+            // `GameBoy::step`, with the accesses of the CPU taken apart from the reads
+            // the LCD makes to draw
+            let cycles = gameboy.cpu.step();
+            let hits = gameboy.cpu.memory.take_watch_hits();
+            let frame_done = gameboy.gpu.step(&mut gameboy.cpu.memory, cycles);
+            gameboy.cpu.memory.take_watch_hits();
+
+            if let Some(stop) = self.watchpoint_hit(gameboy, &hits) {
+                self.resume = None;
+                return Some(stop);
+            }
+            if frame_done {
+                return None;
+            }
+        }
+    }
+
+    // Why the console stopped followed by the registers and the next instruction
+    pub fn describe(&self, gameboy: &GameBoy, stop: &Stop) -> String {
+        match stop {
+            Stop::Done => self.location(gameboy),
+            _ => format!("{}\n{}", stop, self.location(gameboy)),
+        }
+    }
+
+    // Have the memory record the accesses the watchpoints are interested in
+    fn watch_memory(&self, gameboy: &mut GameBoy) {
+        let ranges = |kinds: &[WatchKind]| {
+            let watchpoints = self.watchpoints.iter().filter(|watchpoint| kinds.contains(&watchpoint.kind));
+            watchpoints.map(|watchpoint| watchpoint.start..=watchpoint.end).collect()
+        };
+        let reads = ranges(&[WatchKind::Read, WatchKind::Access]);
+        let writes = ranges(&[WatchKind::Write, WatchKind::Access, WatchKind::Change]);
+        gameboy.cpu.memory.set_watch(reads, writes);
+    }
+
+    // Breakpoints and execute watchpoints at `pc` whose condition is true
+    fn breakpoint_hit(&self, gameboy: &GameBoy, pc: u16) -> Option<Stop> {
+        let true_or_none = |condition: &Option<Condition>| {
+            condition.as_ref().is_none_or(|condition| condition.is_true(gameboy, None))
+        };
+        if self.breakpoints.get(&pc).is_some_and(true_or_none) {
+            return Some(Stop::Breakpoint(pc));
+        }
+        let watchpoint = self.watchpoints.iter().position(|watchpoint| {
+            watchpoint.kind == WatchKind::Execute && watchpoint.contains(pc) && true_or_none(&watchpoint.condition)
+        })?;
+        Some(Stop::Execute { watchpoint, pc })
+    }
+
+    // The first access of the last instruction that a watchpoint stops at
+    fn watchpoint_hit(&self, gameboy: &GameBoy, hits: &[WatchHit]) -> Option<Stop> {
+        hits.iter().find_map(|&hit| {
+            let watchpoint = self.watchpoints.iter().position(|watchpoint| {
+                watchpoint.matches(&hit)
+                    && watchpoint.condition.as_ref().is_none_or(|condition| condition.is_true(gameboy, Some(hit.value)))
+            })?;
+            Some(Stop::Access { watchpoint, hit })
+        })
+    }
+
+    // The registers and the next instruction, shown whenever the console stops
+    pub fn location(&self, gameboy: &GameBoy) -> String {
+        format!("{}\n{}", registers(gameboy), self.line(gameboy, gameboy.cpu.registers.pc))
+    }
+
+    // `#0  0153  Main.loop+$3` for PC, then the address each function returns to
+    fn backtrace(&self, gameboy: &GameBoy) -> String {
+        let Some(call_stack) = &gameboy.cpu.call_stack else {
+            return "Calls aren't tracked".to_string();
+        };
+        let frame = |number: usize, address: u16| {
+            let label = self.symbols.label(gameboy.rom_bank(), address).unwrap_or_default();
+            format!("#{:<2} {:04x}  {}", number, address, label).trim_end().to_string()
+        };
+        let mut lines = vec![frame(0, gameboy.cpu.registers.pc)];
+        for (i, call) in call_stack.frames().iter().rev().enumerate() {
+            let mut line = frame(i + 1, call.return_address);
+            if call.kind == CallKind::Interrupt {
+                line.push_str("  <interrupted>");
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+
+    // The last instructions run and the ones after PC, or `count` instructions from `address`
+    fn list(&self, gameboy: &GameBoy, address: Option<u16>, count: usize) -> String {
+        let pc = gameboy.cpu.registers.pc;
+        let mut lines: Vec<String> = Vec::new();
+        let mut address = match address {
+            Some(address) => address,
+            None => {
+                let before = self.history.iter().filter(|&&address| address != pc);
+                lines.extend(before.map(|&address| self.line(gameboy, address)));
+                pc
+            }
+        };
+        while lines.len() < count {
+            lines.push(self.line(gameboy, address));
+            address = address.wrapping_add(decode(gameboy, address).0.len() as u16);
+        }
+        lines.join("\n")
+    }
+
+    // `=> 0150  c3 50 01  jp Main`, marked with => at PC and * at a breakpoint. A label at
+    // the address goes on the line before
+    fn line(&self, gameboy: &GameBoy, address: u16) -> String {
+        let (bytes, instruction) = decode(gameboy, address);
+        let asm = match instruction {
+            Some(instruction) => self.symbols.annotate(&instruction.to_asm(&bytes, address), gameboy.rom_bank()),
+            None => format!("db ${:02x}", bytes[0]),
+        };
+        let label = match self.symbols.name(gameboy.rom_bank(), address) {
+            Some(name) => format!("{}:\n", name),
+            None => String::new(),
+        };
+        let marker = if address == gameboy.cpu.registers.pc {
+            "=>"
+        } else if self.breakpoints.contains_key(&address) {
+            " *"
+        } else {
+            "  "
+        };
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}{} {:04x}  {:<8}  {}", label, marker, address, bytes.join(" "), asm)
+    }
+}
+
+// The instruction at `address` in the memory as the CPU sees it, with its bytes
+fn decode(gameboy: &GameBoy, address: u16) -> (Vec<u8>, Option<Instruction>) {
+    let bytes: Vec<u8> = (0..3).map(|i| gameboy.cpu.memory.peek(address.wrapping_add(i))).collect();
+    let line = disassembler::decode(&bytes, 0);
+    (line.bytes, line.instruction)
+}
+
+pub fn registers(gameboy: &GameBoy) -> String {
+    let cpu = &gameboy.cpu;
+    let r = &cpu.registers;
+    let flag = |set: bool, name: char| if set { name } else { '-' };
+    format!(
+        "A:{:02x} F:{}{}{}{} B:{:02x} C:{:02x} D:{:02x} E:{:02x} H:{:02x} L:{:02x} SP:{:04x} PC:{:04x} IME:{} {}",
+        r.a,
+        flag(r.flag_z, 'Z'),
+        flag(r.flag_n, 'N'),
+        flag(r.flag_h, 'H'),
+        flag(r.flag_c, 'C'),
+        r.b,
+        r.c,
+        r.d,
+        r.e,
+        r.h,
+        r.l,
+        r.sp,
+        r.pc,
+        cpu.interrupts_enabled as u8,
+        if cpu.is_halted { "halted" } else { "" },
+    )
+    .trim_end()
+    .to_string()
+}
+
+pub fn register(gameboy: &GameBoy, register: Register) -> u16 {
+    let r = &gameboy.cpu.registers;
+    match register {
+        Register::A => r.a as u16,
+        Register::B => r.b as u16,
+        Register::C => r.c as u16,
+        Register::D => r.d as u16,
+        Register::E => r.e as u16,
+        Register::H => r.h as u16,
+        Register::L => r.l as u16,
+        Register::AF => r.af(),
+        Register::BC => r.bc(),
+        Register::DE => r.de(),
+        Register::HL => r.hl(),
+        Register::SP => r.sp,
+        Register::PC => r.pc,
+        Register::FlagZ => r.flag_z as u16,
+        Register::FlagN => r.flag_n as u16,
+        Register::FlagH => r.flag_h as u16,
+        Register::FlagC => r.flag_c as u16,
+    }
+}
+
+pub fn set_register(gameboy: &mut GameBoy, register: Register, value: u16) {
+    let r = &mut gameboy.cpu.registers;
+    let byte = value as u8;
+    match register {
+        Register::A => r.a = byte,
+        Register::B => r.b = byte,
+        Register::C => r.c = byte,
+        Register::D => r.d = byte,
+        Register::E => r.e = byte,
+        Register::H => r.h = byte,
+        Register::L => r.l = byte,
+        Register::AF => r.set_af(value),
+        Register::BC => r.set_bc(value),
+        Register::DE => r.set_de(value),
+        Register::HL => r.set_hl(value),
+        Register::SP => r.sp = value,
+        Register::PC => r.pc = value,
+        Register::FlagZ => r.flag_z = value != 0,
+        Register::FlagN => r.flag_n = value != 0,
+        Register::FlagH => r.flag_h = value != 0,
+        Register::FlagC => r.flag_c = value != 0,
+    }
+}
+
+// 16 bytes per line with their ASCII, `c000  3e 05 ...  >.`
+fn hexdump(gameboy: &GameBoy, address: u16, length: u16) -> String {
+    let mut output = String::new();
+    let end = address as u32 + length as u32;
+    let mut line = address as u32;
+    while line < end {
+        let bytes: Vec<u8> = (line..end.min(line + 16)).map(|a| gameboy.cpu.memory.peek(a as u16)).collect();
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() { byte as char } else { '.' }).collect();
+        let _ = writeln!(output, "{:04x}  {:<47}  {}", line, hex.join(" "), text);
+        line += 16;
+    }
+    output.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(debugger: &mut Debugger, gameboy: &mut GameBoy, line: &str) -> String {
+        match debugger.execute_line(gameboy, line).unwrap() {
+            Reply::Resume => loop {
+                if let Some(stop) = debugger.run(gameboy) {
+                    return stop;
+                }
+            },
+            Reply::Output(output) => output,
+            Reply::Quit => String::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("s 3"), Ok(Command::Step(3)));
+        assert_eq!(Command::parse("b $0150"), Ok(Command::Break { address: 0x150, condition: None }));
+        let Ok(Command::Watch(watchpoint)) = Command::parse("watch c0a0-c0af change if a == $3") else {
+            panic!("Expected a watchpoint")
+        };
+        assert_eq!(watchpoint.to_string(), "change c0a0-c0af if a == $3");
+        assert!(Command::parse("watch c0af-c0a0").is_err());
+        assert!(Command::parse("step if a == 1").is_err());
+        assert_eq!(Command::parse("x c000"), Ok(Command::Memory { address: 0xC000, length: 64 }));
+        assert_eq!(Command::parse("set hl 0xc000"), Ok(Command::Set(Register::HL, 0xC000)));
+        assert_eq!(Command::parse("w ff80 1 ff"), Ok(Command::Write { address: 0xFF80, bytes: vec![1, 0xFF] }));
+        assert!(Command::parse("w ff80 100").is_err());
+        assert!(Command::parse("break").is_err());
+        assert!(Command::parse("jump").is_err());
+
+        let symbols = Symbols::parse("00:0153 Main.loop\n00:c0a0 wScore");
+        let parse = |line: &str| Command::parse_with(line, &symbols);
+        assert_eq!(parse("b Main.loop"), Ok(Command::Break { address: 0x153, condition: None }));
+        let Ok(Command::Watch(watchpoint)) = parse("watch wScore if value > [wScore]") else {
+            panic!("watch with labels should parse")
+        };
+        assert_eq!(watchpoint.to_string(), "write c0a0 if value > [$c0a0]");
+    }
+
+    #[test]
+    fn test_debugger() {
+        let mut gameboy = GameBoy::from_program(
+            "ld a, 1
+            loop:
+             call add
+             ld b, a
+             jr loop
+            add:
+             add a, a
+             ret",
+        );
+        let mut debugger = Debugger::new();
+
+        run(&mut debugger, &mut gameboy, "step");
+        assert_eq!(gameboy.cpu.registers.a, 1);
+        // Stepping over the call runs all of it
+        run(&mut debugger, &mut gameboy, "next");
+        assert_eq!((gameboy.cpu.registers.pc, gameboy.cpu.registers.a), (0x105, 2));
+
+        run(&mut debugger, &mut gameboy, "set pc 100");
+        gameboy.track_calls();
+        run(&mut debugger, &mut gameboy, "break 108");
+        let stop = run(&mut debugger, &mut gameboy, "continue");
+        assert!(stop.starts_with("Breakpoint at 0108\nA:01"), "{}", stop);
+        assert!(stop.ends_with("=> 0108  87        add a, a"), "{}", stop);
+        // An empty line repeats the command, and continuing leaves the breakpoint
+        assert!(run(&mut debugger, &mut gameboy, "").starts_with("Breakpoint at 0108\nA:02"));
+        assert_eq!(run(&mut debugger, &mut gameboy, "bt"), "#0  0108\n#1  0105");
+
+        let list = run(&mut debugger, &mut gameboy, "list");
+        let lines: Vec<&str> = list.lines().take(4).collect();
+        assert_eq!(
+            lines,
+            [
+                "   0105  47        ld b, a",
+                "   0106  18 fa     jr $0102",
+                "   0102  cd 08 01  call $0108",
+                "=> 0108  87        add a, a"
+            ]
+        );
+
+        run(&mut debugger, &mut gameboy, "write c000 12 34");
+        assert_eq!(run(&mut debugger, &mut gameboy, "x c000 2"), format!("c000  12 34{:42}  .4", ""));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut gameboy = GameBoy::from_program(
+            "ld hl, $c000
+            loop:
+             inc a
+             ld [hl], a
+             jr loop",
+        );
+        let mut debugger = Debugger::new();
+
+        run(&mut debugger, &mut gameboy, "watch c000 change if value == 3");
+        let stop = run(&mut debugger, &mut gameboy, "continue");
+        assert!(stop.starts_with("Watchpoint 1: write of $03 to c000, was $02\nA:03"), "{}", stop);
+        run(&mut debugger, &mut gameboy, "delete c000");
+
+        run(&mut debugger, &mut gameboy, "break 103 if a == 10");
+        let stop = run(&mut debugger, &mut gameboy, "continue");
+        assert!(stop.starts_with("Breakpoint at 0103\nA:10"), "{}", stop);
+        run(&mut debugger, &mut gameboy, "delete");
+
+        // Opcode fetches and the interrupt dispatch reading IE and IF are not data reads
+        run(&mut debugger, &mut gameboy, "watch c000-c0ff read");
+        run(&mut debugger, &mut gameboy, "watch 0100-0106 read");
+        run(&mut debugger, &mut gameboy, "watch ffff read");
+        run(&mut debugger, &mut gameboy, "watch 0105-0106 exec");
+        let stop = run(&mut debugger, &mut gameboy, "continue");
+        assert!(stop.starts_with("Watchpoint 4: exec at 0105"), "{}", stop);
+    }
+}
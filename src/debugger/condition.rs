@@ -0,0 +1,289 @@
+// Conditions of breakpoints and watchpoints, e.g. `a == 3 && flag_z` or `[hl] != value`.
+// Operands are registers, flags (1 when set), labels, hex numbers, memory as `[address]` and
+// `value`, the byte read or written by a watchpoint. They are compared with == != < <= > >=
+// and combined with && || ! and parentheses. Anything that isn't 0 is true
+
+use std::fmt;
+
+use super::{parse_address, Register};
+use crate::gameboy::GameBoy;
+use crate::symbols::Symbols;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+}
+
+impl Operator {
+    fn symbol(self) -> &'static str {
+        match self {
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::And => "&&",
+            Operator::Or => "||",
+        }
+    }
+
+    fn from_symbol(symbol: &str) -> Option<Operator> {
+        match symbol {
+            "==" => Some(Operator::Equal),
+            "!=" => Some(Operator::NotEqual),
+            "<" => Some(Operator::Less),
+            "<=" => Some(Operator::LessOrEqual),
+            ">" => Some(Operator::Greater),
+            ">=" => Some(Operator::GreaterOrEqual),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Number(u16),
+    Register(Register),
+    Memory(Box<Condition>),
+    Value,
+    Not(Box<Condition>),
+    Binary(Operator, Box<Condition>, Box<Condition>),
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        Condition::parse_with(text, &Symbols::default())
+    }
+
+    // Labels of the symbols are read as their address
+    pub fn parse_with(text: &str, symbols: &Symbols) -> Result<Condition, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens: &tokens, position: 0, symbols };
+        let condition = parser.or()?;
+        match parser.next() {
+            None => Ok(condition),
+            Some(token) => Err(format!("unexpected '{}' in the condition", token)),
+        }
+    }
+
+    // `value` is the byte accessed when checking a watchpoint
+    pub fn evaluate(&self, gameboy: &GameBoy, value: Option<u8>) -> u16 {
+        match self {
+            Condition::Number(number) => *number,
+            Condition::Register(register) => super::register(gameboy, *register),
+            Condition::Memory(address) => gameboy.cpu.memory.peek(address.evaluate(gameboy, value)) as u16,
+            Condition::Value => value.unwrap_or(0) as u16,
+            Condition::Not(condition) => (condition.evaluate(gameboy, value) == 0) as u16,
+            Condition::Binary(operator, left, right) => {
+                let left = left.evaluate(gameboy, value);
+                // && and || don't evaluate the right side when the left one decides
+                match operator {
+                    Operator::And if left == 0 => return 0,
+                    Operator::Or if left != 0 => return 1,
+                    _ => {}
+                }
+                let right = right.evaluate(gameboy, value);
+                let result = match operator {
+                    Operator::Equal => left == right,
+                    Operator::NotEqual => left != right,
+                    Operator::Less => left < right,
+                    Operator::LessOrEqual => left <= right,
+                    Operator::Greater => left > right,
+                    Operator::GreaterOrEqual => left >= right,
+                    Operator::And | Operator::Or => right != 0,
+                };
+                result as u16
+            }
+        }
+    }
+
+    pub fn is_true(&self, gameboy: &GameBoy, value: Option<u8>) -> bool {
+        self.evaluate(gameboy, value) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Number(number) => write!(f, "${:x}", number),
+            Condition::Register(register) => write!(f, "{}", register.name()),
+            Condition::Memory(address) => write!(f, "[{}]", address),
+            Condition::Value => write!(f, "value"),
+            Condition::Not(condition) => write!(f, "!{}", Operand(condition)),
+            Condition::Binary(operator @ (Operator::And | Operator::Or), left, right) => {
+                write!(f, "({} {} {})", left, operator.symbol(), right)
+            }
+            Condition::Binary(operator, left, right) => {
+                write!(f, "{} {} {}", Operand(left), operator.symbol(), Operand(right))
+            }
+        }
+    }
+}
+
+// A comparison in operand position needs parentheses to parse back the same way
+struct Operand<'a>(&'a Condition);
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Condition::Binary(Operator::And | Operator::Or, ..) => write!(f, "{}", self.0),
+            Condition::Binary(..) => write!(f, "({})", self.0),
+            condition => write!(f, "{}", condition),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut characters = text.chars().peekable();
+    while let Some(character) = characters.next() {
+        match character {
+            _ if character.is_whitespace() => {}
+            '(' | ')' | '[' | ']' => tokens.push(character.to_string()),
+            '=' | '!' | '<' | '>' | '&' | '|' => {
+                let mut symbol = character.to_string();
+                if let Some(&next) = characters.peek() {
+                    if next == '=' || (next == character && matches!(character, '&' | '|')) {
+                        symbol.push(next);
+                        characters.next();
+                    }
+                }
+                if matches!(symbol.as_str(), "=" | "&" | "|") {
+                    return Err(format!("unknown operator '{}', did you mean '{}{}'?", symbol, symbol, symbol));
+                }
+                tokens.push(symbol);
+            }
+            _ if character.is_ascii_alphanumeric() || matches!(character, '$' | '_' | '.') => {
+                let mut word = character.to_string();
+                while let Some(&next) =
+                    characters.peek().filter(|next| next.is_ascii_alphanumeric() || matches!(next, '_' | '.'))
+                {
+                    word.push(next);
+                    characters.next();
+                }
+                tokens.push(word);
+            }
+            _ => return Err(format!("unexpected '{}' in the condition", character)),
+        }
+    }
+    Ok(tokens)
+}
+
+// Recursive descent, || binds the loosest, then &&, then the comparisons
+struct Parser<'a> {
+    tokens: &'a [String],
+    position: usize,
+    symbols: &'a Symbols,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Option<&str> {
+        let token = self.tokens.get(self.position).map(String::as_str);
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(format!("expected '{}' instead of '{}'", expected, token)),
+            None => Err(format!("expected '{}' at the end of the condition", expected)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.and()?;
+        while self.peek() == Some("||") {
+            self.next();
+            condition = Condition::Binary(Operator::Or, Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.comparison()?;
+        while self.peek() == Some("&&") {
+            self.next();
+            condition = Condition::Binary(Operator::And, Box::new(condition), Box::new(self.comparison()?));
+        }
+        Ok(condition)
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        let left = self.operand()?;
+        match self.peek().and_then(Operator::from_symbol) {
+            Some(operator) => {
+                self.next();
+                Ok(Condition::Binary(operator, Box::new(left), Box::new(self.operand()?)))
+            }
+            None => Ok(left),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Condition, String> {
+        let token = self.next().ok_or("the condition ends too early")?.to_string();
+        match token.as_str() {
+            "!" => Ok(Condition::Not(Box::new(self.operand()?))),
+            "(" => {
+                let condition = self.or()?;
+                self.expect(")")?;
+                Ok(condition)
+            }
+            "[" => {
+                let address = self.or()?;
+                self.expect("]")?;
+                Ok(Condition::Memory(Box::new(address)))
+            }
+            "value" => Ok(Condition::Value),
+            word => match (Register::from_name(word), parse_address(word, self.symbols)) {
+                (Some(register), _) => Ok(Condition::Register(register)),
+                (None, Some(number)) => Ok(Condition::Number(number)),
+                (None, None) => Err(format!("'{}' isn't a register, flag, label or number", word)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::Config;
+
+    #[test]
+    fn test_condition() {
+        let mut gameboy = GameBoy::new(&[0; 0x8000], Config::default()).unwrap();
+        gameboy.cpu.registers.a = 3;
+        gameboy.cpu.registers.flag_z = true;
+        gameboy.cpu.registers.set_hl(0xC000);
+        gameboy.cpu.memory.write_byte(0xC000, 0x42);
+
+        let check = |text: &str| Condition::parse(text).unwrap().is_true(&gameboy, Some(0x10));
+        assert!(check("a == 0x3 && flag_z"));
+        assert!(check("[hl] == 42 && !(a > 3 || value != 10)"));
+        assert!(check("hl >= c000 && [c000] < 43"));
+        assert!(!check("a == 4 || nf"));
+
+        let condition = Condition::parse("a==$3&&zf").unwrap();
+        assert_eq!(condition.to_string(), "(a == $3 && zf)");
+        for text in ["!(a == 3)", "(a == 3) != zf", "[hl] == 42 && !(a > 3 || value != 10)"] {
+            let condition = Condition::parse(text).unwrap();
+            assert_eq!(Condition::parse(&condition.to_string()).unwrap(), condition);
+        }
+        assert_eq!(Condition::parse("!(a == 3)").unwrap().to_string(), "!(a == $3)");
+        assert!(Condition::parse("a = 3").is_err());
+        assert!(Condition::parse("(a == 3").is_err());
+        assert!(Condition::parse("a == q").is_err());
+    }
+}
@@ -0,0 +1,208 @@
+// Disassembler for ROM files. A linear sweep decodes every byte of a range as code, while
+// the recursive descent follows the jumps and calls from the entry points to find what is
+// code and shows everything else of the range as data
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::cpu::instructions::Instruction;
+use crate::symbols::Symbols;
+
+const BANK_SIZE: usize = 0x4000;
+
+// Where the CPU starts: the restart and interrupt vectors and the entry point of the header
+pub const ENTRY_POINTS: [usize; 14] = [
+    0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0x40, 0x48, 0x50, 0x58, 0x60, 0x100,
+];
+
+// Data bytes grouped on one `db` line
+const DATA_PER_LINE: usize = 8;
+
+pub struct Line {
+    // Offset in the ROM file
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    // None for data
+    pub instruction: Option<Instruction>,
+}
+
+impl Line {
+    // Bank of the offset, banks other than 0 are mapped at 0x4000 - 0x7FFF
+    pub fn bank(&self) -> usize {
+        self.offset / BANK_SIZE
+    }
+
+    pub fn address(&self) -> u16 {
+        address_of(self.offset)
+    }
+
+    pub fn asm(&self) -> String {
+        match self.instruction {
+            Some(instruction) => instruction.to_asm(&self.bytes, self.address()),
+            None => {
+                let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("${:02x}", byte)).collect();
+                format!("db {}", bytes.join(", "))
+            }
+        }
+    }
+
+    // The line with the addresses that have a label replaced by it, and the label at
+    // the address on a line of its own before
+    pub fn with_symbols(&self, symbols: &Symbols) -> String {
+        let bank = self.bank() as u16;
+        let line = self.format(&symbols.annotate(&self.asm(), bank));
+        match symbols.name(bank, self.address()) {
+            Some(name) => format!("{}:\n{}", name, line),
+            None => line,
+        }
+    }
+
+    // `bank:address  bytes  instruction`, e.g. `00:0150  c3 50 01  jp $0150`
+    fn format(&self, asm: &str) -> String {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{:02x}:{:04x}  {:<23}  {}", self.bank(), self.address(), bytes.join(" "), asm)
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(&self.asm()))
+    }
+}
+
+fn address_of(offset: usize) -> u16 {
+    if offset < BANK_SIZE {
+        offset as u16
+    } else {
+        (BANK_SIZE + offset % BANK_SIZE) as u16
+    }
+}
+
+// ROM offset of an address reached from code in `bank`, None outside of the ROM
+pub fn offset_of(bank: usize, address: u16) -> Option<usize> {
+    match address {
+        0x0000..=0x3FFF => Some(address as usize),
+        0x4000..=0x7FFF => Some(bank.max(1) * BANK_SIZE + (address as usize - BANK_SIZE)),
+        _ => None,
+    }
+}
+
+// Decode the instruction at `offset`, None for the bytes that aren't opcodes
+pub fn decode(rom: &[u8], offset: usize) -> Line {
+    let prefixed = rom[offset] == 0xCB;
+    let op_byte = if prefixed { rom.get(offset + 1).copied().unwrap_or(0) } else { rom[offset] };
+    let instruction = Instruction::from_byte(op_byte, prefixed);
+    let length = instruction.map_or(1, |instruction| instruction.length() as usize);
+
+    // An instruction cut by the end of the ROM is data
+    match rom.get(offset..offset + length) {
+        Some(bytes) => Line { offset, bytes: bytes.to_vec(), instruction },
+        None => Line { offset, bytes: vec![rom[offset]], instruction: None },
+    }
+}
+
+// Every instruction from `start` to `end` as if it was all code
+pub fn linear_sweep(rom: &[u8], start: usize, end: usize) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut offset = start;
+    while offset < end.min(rom.len()) {
+        let line = decode(rom, offset);
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+// The code reachable from the entry points, following the jumps, calls and restarts.
+// Returns the instructions found by offset
+pub fn trace_code(rom: &[u8], entry_points: &[usize]) -> BTreeMap<usize, Line> {
+    let mut code = BTreeMap::new();
+    let mut pending: Vec<usize> = entry_points.iter().copied().filter(|&offset| offset < rom.len()).collect();
+
+    while let Some(mut offset) = pending.pop() {
+        while offset < rom.len() && !code.contains_key(&offset) {
+            let line = decode(rom, offset);
+            let Some(instruction) = line.instruction else { break };
+
+            if let Some(target) = instruction.jump_target(&line.bytes, line.address()) {
+                pending.extend(offset_of(line.bank(), target).filter(|&target| target < rom.len()));
+            }
+            let next = offset + line.bytes.len();
+            code.insert(offset, line);
+            // Code after a jump can't be reached by running into it, and the bank of
+            // 0x4000 - 0x7FFF isn't known at the end of bank 0
+            if instruction.ends_flow() || next == BANK_SIZE {
+                break;
+            }
+            offset = next;
+        }
+    }
+    code
+}
+
+// The range with the code found by `trace_code` decoded and the rest shown as data
+pub fn recursive_descent(rom: &[u8], start: usize, end: usize, entry_points: &[usize]) -> Vec<Line> {
+    let mut code = trace_code(rom, entry_points);
+    let end = end.min(rom.len());
+
+    let mut lines = Vec::new();
+    let mut offset = start;
+    while offset < end {
+        if let Some(line) = code.remove(&offset) {
+            offset += line.bytes.len();
+            lines.push(line);
+            continue;
+        }
+
+        // Data up to the next instruction, the end of the line or of the bank
+        let mut length = 1;
+        while length < DATA_PER_LINE
+            && offset + length < end
+            && !(offset + length).is_multiple_of(BANK_SIZE)
+            && !code.contains_key(&(offset + length))
+        {
+            length += 1;
+        }
+        lines.push(Line {
+            offset,
+            bytes: rom[offset..offset + length].to_vec(),
+            instruction: None,
+        });
+        offset += length;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asm() {
+        let asm = |bytes: &[u8], address: u16| decode(bytes, 0).instruction.unwrap().to_asm(bytes, address);
+        assert_eq!(asm(&[0x3E, 0x12], 0), "ld a, $12");
+        assert_eq!(asm(&[0xC3, 0x50, 0x01], 0), "jp $0150");
+        assert_eq!(asm(&[0x20, 0xFE], 0x0150), "jr nz, $0150");
+        assert_eq!(asm(&[0xE0, 0x44], 0), "ldh [$ff44], a");
+        assert_eq!(asm(&[0xF8, 0xFD], 0), "ld hl, sp-$03");
+        assert_eq!(asm(&[0x2A], 0), "ld a, [hl+]");
+        assert_eq!(asm(&[0xCB, 0x7E], 0), "bit 7, [hl]");
+        assert_eq!(asm(&[0xC8], 0), "ret z");
+        assert_eq!(Instruction::CALL(crate::cpu::instructions::JumpTest::Carry).to_string(), "call c, a16");
+    }
+
+    #[test]
+    fn test_recursive_descent() {
+        // jp $0105 over two data bytes, then nop and halt
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x108].copy_from_slice(&[0xC3, 0x05, 0x01, 0xAA, 0xBB, 0x00, 0x76, 0x18]);
+        let lines = recursive_descent(&rom, 0x100, 0x107, &[0x100]);
+        let asm: Vec<String> = lines.iter().map(Line::asm).collect();
+        assert_eq!(asm, ["jp $0105", "db $aa, $bb", "nop", "halt"]);
+        assert_eq!(lines[0].to_string(), "00:0100  c3 05 01                 jp $0105");
+
+        let symbols = Symbols::parse("00:0105 Start");
+        assert_eq!(lines[2].with_symbols(&symbols), "Start:\n00:0105  00                       nop");
+        assert_eq!(lines[0].with_symbols(&symbols), "00:0100  c3 05 01                 jp Start");
+    }
+}
@@ -0,0 +1,387 @@
+// The whole console behind one type, for frontends and tools embedding the emulator
+
+use crate::call_stack::CallStack;
+use crate::cdl::CodeDataLog;
+use crate::compat_palette::{ButtonCombo, CompatPalette};
+use crate::cpu::Cpu;
+use crate::gpu::{ColorCorrection, DmgPalette, Gpu};
+use crate::model::Model;
+use crate::rom_reader::{HeaderError, RomHeader};
+use crate::save_state::{StateError, StateHeader, StateReader, StateWriter, VERSION};
+use crate::sgb::Sgb;
+use crate::trace::{Trace, DOCTOR_LY};
+
+// CPU cycles per emulated second
+pub const CLOCK_SPEED: u64 = 4194304;
+
+// 512 banks of 16 KiB, the most the header can declare
+pub const MAX_ROM_SIZE: usize = 0x800000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+// How the console is set up, the defaults pick the model from the header and skip the boot ROM
+pub struct Config {
+    pub model: Option<Model>,
+    pub boot_rom: Option<Vec<u8>>,
+    // Palette of DMG games on a CGB, picked from the title like the boot ROM when not set
+    pub compat_palette: Option<ButtonCombo>,
+    pub color_correction: ColorCorrection,
+    pub dmg_palette: DmgPalette,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            model: None,
+            boot_rom: None,
+            compat_palette: None,
+            color_correction: ColorCorrection::Lcd,
+            dmg_palette: DmgPalette::Grey,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    Header(HeaderError),
+    // Boot ROMs are 256 bytes (DMG) or 2304 bytes (CGB), holds the length of the file
+    InvalidBootRom(usize),
+    // Larger than any cartridge, holds the length of the file
+    UnsupportedRomSize(usize),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoadError::Header(error) => write!(f, "{}", error),
+            LoadError::InvalidBootRom(length) => {
+                write!(f, "the boot ROM is {} bytes long, it should be 256 bytes (DMG) or 2304 bytes (CGB)", length)
+            }
+            LoadError::UnsupportedRomSize(length) => {
+                write!(f, "the ROM is {} bytes long, cartridges hold at most {} bytes", length, MAX_ROM_SIZE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<HeaderError> for LoadError {
+    fn from(error: HeaderError) -> LoadError {
+        LoadError::Header(error)
+    }
+}
+
+pub struct GameBoy {
+    pub cpu: Cpu,
+    pub gpu: Gpu,
+    header: RomHeader,
+    model: Model,
+}
+
+impl GameBoy {
+    pub fn new(rom: &[u8], config: Config) -> Result<GameBoy, LoadError> {
+        let header = RomHeader::parse(rom)?;
+        let model = config.model.unwrap_or(Model::from_cgb_flag(header.cgb_flag));
+        debug!("Title: {}", header.title());
+        debug!("Model: {:?}", model);
+
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(LoadError::UnsupportedRomSize(rom.len()));
+        }
+        if rom.len() > 0x8000 {
+            warn!("There is no MBC yet, only the first 32 KiB of the ROM are mapped");
+        }
+        let mut cpu = Cpu::new(model);
+        cpu.memory.load_rom(rom);
+
+        let has_boot_rom = config.boot_rom.is_some();
+        if let Some(boot_rom) = config.boot_rom {
+            if boot_rom.len() != 0x100 && boot_rom.len() != 0x900 {
+                return Err(LoadError::InvalidBootRom(boot_rom.len()));
+            }
+            cpu.load_boot_rom(boot_rom);
+        }
+
+        // Games that don't declare SGB support still get the border and default colors
+        if model == Model::Sgb {
+            if !header.supports_sgb() {
+                warn!("The game doesn't support SGB functions, commands will be ignored");
+            }
+            cpu.memory.sgb = Some(Sgb::new(header.supports_sgb()));
+        }
+
+        // A CGB runs DMG games in compatibility mode, colored with the palette the boot ROM
+        // picks from the title or the one chosen with a button combination.
+        // A real boot ROM does this by itself
+        if model.is_cgb() && Model::from_cgb_flag(header.cgb_flag) == Model::Dmg && !has_boot_rom {
+            let palette = match config.compat_palette {
+                Some(combo) => CompatPalette::from_button_combo(combo),
+                None => CompatPalette::from_header(&header),
+            };
+            debug!("DMG compatibility palette: {:?}", palette);
+            cpu.memory.enter_dmg_compatibility(&palette);
+        }
+
+        Ok(GameBoy {
+            cpu,
+            gpu: Gpu::new(config.color_correction, config.dmg_palette, model),
+            header,
+            model,
+        })
+    }
+
+    pub fn header(&self) -> &RomHeader {
+        &self.header
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    // ROM bank mapped at 0x4000 - 0x7FFF
+    pub fn rom_bank(&self) -> u16 {
+        self.cpu.memory.rom_bank()
+    }
+
+    // Follow the calls from here on, for the backtrace of the debugger and the profiler
+    pub fn track_calls(&mut self) {
+        self.cpu.call_stack = Some(CallStack::new(self.cpu.registers.pc, self.rom_bank()));
+    }
+
+    // Mark the ROM bytes run and read from here on, see `cdl` for the format
+    pub fn set_cdl(&mut self, cdl: Option<CodeDataLog>) {
+        self.cpu.memory.set_cdl(cdl);
+    }
+
+    pub fn cdl(&self) -> Option<std::cell::Ref<'_, CodeDataLog>> {
+        self.cpu.memory.cdl()
+    }
+
+    // Log every instruction run in the format of Gameboy Doctor, LY is stubbed while tracing
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.cpu.memory.stub_ly(trace.is_some().then_some(DOCTOR_LY));
+        self.cpu.trace = trace;
+    }
+
+    // Execute one instruction, returns the cycles it took and whether a frame was completed
+    pub fn step(&mut self) -> (u32, bool) {
+        let cycles = self.cpu.step();
+        let frame_done = self.gpu.step(&mut self.cpu.memory, cycles);
+        (cycles, frame_done)
+    }
+
+    // Run until the next frame is completed
+    pub fn run_frame(&mut self) {
+        while !self.step().1 {}
+    }
+
+    // Set the buttons that are held down
+    pub fn set_input(&mut self, pressed: &[Button]) {
+        self.cpu.memory.set_buttons(button_bits(pressed));
+    }
+
+    // The last complete frame, 0RGB pixels of `screen_size()`
+    pub fn framebuffer(&self) -> &[u32] {
+        self.gpu.frame()
+    }
+
+    pub fn screen_size(&self) -> (usize, usize) {
+        self.gpu.screen_size()
+    }
+
+    // Every tile in VRAM for the tile viewer, 16 tiles per row, see `gpu::tile_at`
+    pub fn tile_data(&self) -> Vec<u32> {
+        self.gpu.render_tile_data(&self.cpu.memory)
+    }
+
+    pub fn tile_data_size(&self) -> (usize, usize) {
+        Gpu::tile_data_size(self.model)
+    }
+
+    // There is no sound emulation yet, so there are never any samples
+    pub fn take_audio_samples(&mut self) -> Vec<f32> {
+        Vec::new()
+    }
+
+    // Bytes the game sent through the serial port
+    pub fn serial_output(&self) -> &[u8] {
+        &self.cpu.memory.serial_output
+    }
+
+    // Battery backed cartridge RAM, `None` for cartridges without a battery
+    pub fn save_data(&self) -> Option<&[u8]> {
+        self.header.has_battery().then(|| self.cpu.memory.external_ram())
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.cpu.memory.load_external_ram(data);
+    }
+
+    // Snapshot of the whole console, see `save_state` for the format
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        self.state_header().write(&mut state);
+        self.cpu.save_state(&mut state);
+        self.gpu.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    // Restore a snapshot of the same game and model. The state is read once into a scratch
+    // console to check it, so a bad one leaves this console untouched. It is then read into
+    // this one, which keeps what isn't emulated like the buttons held, the trace or the log
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data);
+        let header = StateHeader::read(&mut state)?;
+        let expected = self.state_header();
+        if header.title != expected.title || header.global_checksum != expected.global_checksum {
+            return Err(StateError::WrongRom(header.title));
+        }
+        if header.model != self.model {
+            return Err(StateError::WrongModel(header.model));
+        }
+
+        let mut cpu = Cpu::new(self.model);
+        let mut gpu = Gpu::new(self.gpu.color_correction, self.gpu.dmg_palette, self.model);
+        read_state(&mut cpu, &mut gpu, &mut state.clone(), header.version)?;
+        read_state(&mut self.cpu, &mut self.gpu, &mut state, header.version)?;
+
+        // The calls made before the state was saved aren't known
+        if let Some(call_stack) = &mut self.cpu.call_stack {
+            call_stack.clear_frames();
+        }
+        Ok(())
+    }
+
+    fn state_header(&self) -> StateHeader {
+        StateHeader {
+            version: VERSION,
+            model: self.model,
+            global_checksum: self.header.global_checksum,
+            title: self.header.title(),
+        }
+    }
+}
+
+// A blank 32 KiB ROM with the program assembled at the entry point, for tests
+#[cfg(test)]
+pub(crate) fn program_rom(source: &str) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    let program = asm!(source, 0x100);
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom
+}
+
+#[cfg(test)]
+impl GameBoy {
+    pub(crate) fn from_program(source: &str) -> GameBoy {
+        GameBoy::new(&program_rom(source), Config::default()).unwrap()
+    }
+}
+
+fn read_state(cpu: &mut Cpu, gpu: &mut Gpu, state: &mut StateReader, version: u16) -> Result<(), StateError> {
+    cpu.load_state(state)?;
+    gpu.load_state(state, version)?;
+    if !state.is_empty() {
+        return Err(StateError::Corrupt("length"));
+    }
+    Ok(())
+}
+
+// Bits of P1 for the buttons, bits 0-3 Right, Left, Up, Down and bits 4-7 A, B, Select, Start
+pub(crate) fn button_bits(pressed: &[Button]) -> u8 {
+    pressed.iter().fold(0, |buttons, &button| buttons | 1 << button as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_joypad() {
+        let mut gameboy = GameBoy::from_program("loop: jr loop");
+        gameboy.set_input(&[Button::Down, Button::Start]);
+
+        gameboy.cpu.memory.write_byte(0xFF00, 0x20);
+        assert_eq!(gameboy.cpu.memory.read_byte(0xFF00), 0xE7);
+        gameboy.cpu.memory.write_byte(0xFF00, 0x10);
+        assert_eq!(gameboy.cpu.memory.read_byte(0xFF00), 0xD7);
+        assert_ne!(gameboy.cpu.memory.read_byte(0xFF0F) & 0x10, 0);
+
+        gameboy.run_frame();
+        assert_eq!(gameboy.framebuffer().len(), 160 * 144);
+    }
+
+    #[test]
+    fn test_rom_size() {
+        let mut rom = vec![0; 0x20000];
+        let program = asm!("loop: jr loop", 0x100);
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        rom[0x4000] = 0x12;
+        let mut gameboy = GameBoy::new(&rom, Config::default()).unwrap();
+        gameboy.run_frame();
+        assert_eq!(gameboy.cpu.memory.read_byte(0x4000), 0x12);
+        gameboy.cpu.memory.write_byte(0x0100, 0x00);
+        assert_eq!(gameboy.cpu.memory.read_byte(0x0100), program[0]);
+        assert_eq!(gameboy.cpu.memory.read_byte(0x8000), 0x00);
+
+        let rom = vec![0; MAX_ROM_SIZE + 1];
+        assert_eq!(GameBoy::new(&rom, Config::default()).err(), Some(LoadError::UnsupportedRomSize(MAX_ROM_SIZE + 1)));
+    }
+
+    #[test]
+    fn test_save_state() {
+        let rom = std::fs::read("roms/tetris.gb").expect("Should have been able to read the file tetris.gb");
+        for model in [Model::Dmg, Model::Cgb, Model::Sgb] {
+            let config = Config { model: Some(model), ..Config::default() };
+            let mut gameboy = GameBoy::new(&rom, config).unwrap();
+            for _ in 0..60 {
+                gameboy.run_frame();
+            }
+
+            let state = gameboy.save_state();
+            for _ in 0..30 {
+                gameboy.run_frame();
+            }
+            let (pc, frame) = (gameboy.cpu.registers.pc, gameboy.framebuffer().to_vec());
+
+            // The watchpoints aren't part of the state and stay set
+            gameboy.cpu.memory.set_watch(Vec::new(), vec![0xFF80..=0xFFFE]);
+            gameboy.load_state(&state).unwrap();
+            for _ in 0..30 {
+                gameboy.run_frame();
+            }
+            assert_eq!(gameboy.cpu.registers.pc, pc);
+            assert_eq!(gameboy.framebuffer(), frame);
+            assert!(!gameboy.cpu.memory.take_watch_hits().is_empty());
+
+            let registers = gameboy.cpu.registers.af();
+            assert_eq!(gameboy.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+            assert_eq!(gameboy.cpu.registers.af(), registers);
+        }
+
+        // Version 1 also had the SGB frame on the DMG, at the end of the state
+        let mut other = GameBoy::new(&rom, Config::default()).unwrap();
+        let mut state = other.save_state();
+        assert!(state.len() < 256 * 224 * 4);
+        state[4..6].copy_from_slice(&1u16.to_le_bytes());
+        state.extend(std::iter::repeat_n(0, 256 * 224 * 4));
+        assert_eq!(other.load_state(&state), Ok(()));
+        state.pop();
+        assert_eq!(other.load_state(&state), Err(StateError::Truncated));
+
+        let mut state = other.save_state();
+        state[7] ^= 0xFF;
+        assert_eq!(other.load_state(&state), Err(StateError::WrongRom("TETRIS".to_string())));
+    }
+}
@@ -0,0 +1,418 @@
+// Stub of the GDB remote serial protocol, so GDB can debug a game over a local TCP port.
+// GDB has no SM83 target, `gdb-multiarch` with `set architecture gbz80` reads the registers
+// af, bc, de, hl, sp and pc in that order. Breakpoints and watchpoints are kept by the
+// `Debugger`, memory goes through `Memory` like the game's own accesses
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::debugger::{self, Debugger, Register, Resume, Stop, WatchKind, Watchpoint};
+use crate::gameboy::GameBoy;
+
+// Registers in the order of the `g` packet
+const REGISTERS: [Register; 6] = [Register::AF, Register::BC, Register::DE, Register::HL, Register::SP, Register::PC];
+
+// Ctrl-C sent by GDB to stop the running console
+const INTERRUPT: u8 = 0x03;
+
+// SIGINT when interrupted, SIGTRAP for everything else
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// What the stub does after a packet
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Resume,
+    // Kill or detach, with the reply GDB waits for
+    End(Option<String>),
+}
+
+pub struct GdbStub {
+    stream: TcpStream,
+    // Bytes received but not handled yet
+    input: Vec<u8>,
+    no_ack: bool,
+    debugger: Debugger,
+    last_stop: String,
+}
+
+impl GdbStub {
+    // Wait on 127.0.0.1 until GDB connects
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("Waiting for GDB on port {}, connect with `target remote :{}`", port, port);
+        let (stream, address) = listener.accept()?;
+        info!("GDB connected from {}", address);
+        Ok(GdbStub::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> GdbStub {
+        let _ = stream.set_nodelay(true);
+        GdbStub {
+            stream,
+            input: Vec::new(),
+            no_ack: false,
+            debugger: Debugger::new(),
+            last_stop: format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.debugger.is_running()
+    }
+
+    // Handle packets while the console is stopped. Returns true when GDB resumes it and
+    // false when the session is over
+    pub fn serve(&mut self, gameboy: &mut GameBoy) -> io::Result<bool> {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(Some(packet)) => packet,
+                // GDB went away without detaching
+                Err(error) if error.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+                Err(error) => return Err(error),
+                // Ctrl-C while stopped, say where it is stopped
+                Ok(None) => {
+                    let reply = self.last_stop.clone();
+                    self.send(&reply)?;
+                    continue;
+                }
+            };
+            trace!("GDB sent {}", packet);
+            match self.handle(gameboy, &packet) {
+                Action::Reply(reply) => self.send(&reply)?,
+                Action::Resume => return Ok(true),
+                Action::End(reply) => {
+                    if let Some(reply) = reply {
+                        self.send(&reply)?;
+                    }
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    // Run until the console stops or completes a frame, telling GDB when it stops
+    pub fn run(&mut self, gameboy: &mut GameBoy) -> io::Result<()> {
+        match self.poll_interrupt() {
+            Ok(false) => {}
+            Ok(true) => {
+                self.debugger.interrupt();
+                return self.report(gameboy, Stop::Interrupted);
+            }
+            // Stop, `serve` then ends the session
+            Err(error) if error.kind() == ErrorKind::UnexpectedEof => {
+                self.debugger.interrupt();
+                return Ok(());
+            }
+            Err(error) => return Err(error),
+        }
+        match self.debugger.run_until_stop(gameboy) {
+            Some(stop) => self.report(gameboy, stop),
+            None => Ok(()),
+        }
+    }
+
+    fn report(&mut self, gameboy: &GameBoy, stop: Stop) -> io::Result<()> {
+        debug!("Stopped at {:04x}", gameboy.cpu.registers.pc);
+        self.last_stop = match stop {
+            Stop::Interrupted => format!("S{:02x}", SIGINT),
+            Stop::Access { watchpoint, hit } => {
+                let kind = self.debugger.watchpoint(watchpoint).map(|watchpoint| watchpoint.kind);
+                let name = match kind {
+                    Some(WatchKind::Write) => "watch",
+                    Some(WatchKind::Read) => "rwatch",
+                    _ => "awatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.address)
+            }
+            _ => format!("S{:02x}", SIGTRAP),
+        };
+        let reply = self.last_stop.clone();
+        self.send(&reply)
+    }
+
+    fn handle(&mut self, gameboy: &mut GameBoy, packet: &str) -> Action {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => REGISTERS.iter().map(|&register| hex_word(debugger::register(gameboy, register))).collect(),
+            "G" => {
+                for (i, &register) in REGISTERS.iter().enumerate() {
+                    if let Some(value) = arguments.get(i * 4..i * 4 + 4).and_then(parse_word) {
+                        debugger::set_register(gameboy, register, value);
+                    }
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(arguments, 16).ok().and_then(|i| REGISTERS.get(i)) {
+                Some(&register) => hex_word(debugger::register(gameboy, register)),
+                None => "xxxx".to_string(),
+            },
+            "P" => {
+                let register = arguments.split_once('=').and_then(|(number, value)| {
+                    let register = REGISTERS.get(usize::from_str_radix(number, 16).ok()?)?;
+                    Some((*register, parse_word(value)?))
+                });
+                match register {
+                    Some((register, value)) => {
+                        debugger::set_register(gameboy, register, value);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(arguments) {
+                Some((address, length)) => (0..length)
+                    .map(|i| format!("{:02x}", gameboy.cpu.memory.peek(address.wrapping_add(i))))
+                    .collect(),
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let write = arguments.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_range(range)?;
+                    let bytes = parse_bytes(data)?;
+                    (bytes.len() == length as usize).then_some((address, bytes))
+                });
+                match write {
+                    Some((address, bytes)) => {
+                        for (i, byte) in bytes.into_iter().enumerate() {
+                            gameboy.cpu.memory.write_byte(address.wrapping_add(i as u16), byte);
+                        }
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            "c" | "s" => {
+                // An address resumes from there
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    gameboy.cpu.registers.pc = address;
+                }
+                self.debugger.resume(if command == "s" { Resume::Steps(1) } else { Resume::Continue });
+                return Action::Resume;
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", arguments),
+            "H" => "OK".to_string(),
+            "k" => return Action::End(None),
+            "D" => return Action::End(Some("OK".to_string())),
+            _ => self.query(packet),
+        };
+        Action::Reply(reply)
+    }
+
+    // Set or remove a breakpoint or watchpoint from `type,address,kind`
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let Some((kind, (address, length))) =
+            arguments.split_once(',').and_then(|(kind, range)| Some((kind, parse_range(range)?)))
+        else {
+            return "E01".to_string();
+        };
+        let watch = match kind {
+            "0" | "1" => {
+                match insert {
+                    true => self.debugger.add_breakpoint(address, None),
+                    false => {
+                        self.debugger.remove_breakpoint(address);
+                    }
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+        let end = address.saturating_add(length.max(1) - 1);
+        match insert {
+            true => self.debugger.add_watchpoint(Watchpoint { kind: watch, start: address, end, condition: None }),
+            false => {
+                self.debugger.remove_watchpoint(watch, address, end);
+            }
+        }
+        "OK".to_string()
+    }
+
+    // General queries, unknown ones get the empty reply
+    fn query(&mut self, packet: &str) -> String {
+        let name = packet.split([':', ';']).next().unwrap_or_default();
+        match name {
+            "qSupported" => "PacketSize=1000;QStartNoAckMode+".to_string(),
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // The next packet without its framing, or None for Ctrl-C
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                b'$' => {}
+                INTERRUPT => return Ok(None),
+                // Acks of our replies, and anything else outside a packet
+                _ => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                .is_some_and(|checksum| checksum == checksum_of(&data));
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            warn!("GDB packet with a wrong checksum");
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if self.input.is_empty() {
+            let mut buffer = [0; 1024];
+            let length = self.stream.read(&mut buffer)?;
+            if length == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            self.input.extend_from_slice(&buffer[..length]);
+        }
+        Ok(self.input.remove(0))
+    }
+
+    // Whether GDB sent Ctrl-C, without waiting
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buffer = [0; 1024];
+        let result = self.stream.read(&mut buffer);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(length) => self.input.extend_from_slice(&buffer[..length]),
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error),
+        }
+        let interrupted = self.input.contains(&INTERRUPT);
+        self.input.retain(|&byte| byte != INTERRUPT);
+        Ok(interrupted)
+    }
+
+    // Send a reply and wait for the ack, sending it again when GDB asks
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        trace!("Replying {}", reply);
+        let packet = format!("${}#{:02x}", reply, checksum_of(reply.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    // GDB doesn't send anything else before acking
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+// 16 bit registers are sent little endian
+fn hex_word(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, value >> 8)
+}
+
+fn parse_word(text: &str) -> Option<u16> {
+    let bytes = parse_bytes(text)?;
+    match bytes[..] {
+        [low, high] => Some(u16::from_le_bytes([low, high])),
+        _ => None,
+    }
+}
+
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// `address,length` in hex, the length is capped at the end of memory
+fn parse_range(text: &str) -> Option<(u16, u16)> {
+    let (address, length) = text.split_once(',')?;
+    let address = u16::from_str_radix(address, 16).ok()?;
+    let length = usize::from_str_radix(length, 16).ok()?;
+    Some((address, length.min(0x10000 - address as usize) as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn send(stream: &mut TcpStream, packet: &str) -> String {
+        write!(stream, "${}#{:02x}", packet, checksum_of(packet.as_bytes())).unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while reply.last() != Some(&b'#') {
+            stream.read_exact(&mut byte).unwrap();
+            if !(reply.is_empty() && byte[0] != b'$') {
+                reply.push(byte[0]);
+            }
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply[1..reply.len() - 1].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_gdb_stub() {
+        let mut gameboy = GameBoy::from_program("ld a, 5\n ld [$c000], a\n loop: jr loop");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stub = GdbStub::new(listener.accept().unwrap().0);
+            loop {
+                if stub.is_running() {
+                    stub.run(&mut gameboy).unwrap();
+                } else if !stub.serve(&mut gameboy).unwrap() {
+                    return gameboy.cpu.registers.a;
+                }
+            }
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        assert_eq!(send(&mut client, "qSupported:swbreak+"), "PacketSize=1000;QStartNoAckMode+");
+        assert_eq!(send(&mut client, "?"), "S05");
+        assert!(send(&mut client, "g").ends_with("feff0001"));
+        assert_eq!(send(&mut client, "Z2,c000,1"), "OK");
+        assert_eq!(send(&mut client, "c"), "T05watch:c000;");
+        assert_eq!(send(&mut client, "mc000,1"), "05");
+        assert_eq!(send(&mut client, "p5"), "0501");
+        assert_eq!(send(&mut client, "Z0,105,1"), "OK");
+        assert_eq!(send(&mut client, "P0=00ff"), "OK");
+        assert_eq!(send(&mut client, "c"), "S05");
+        assert_eq!(send(&mut client, "s"), "S05");
+        assert_eq!(send(&mut client, "Mc000,2:abcd"), "OK");
+        assert_eq!(send(&mut client, "mc000,2"), "abcd");
+        assert_eq!(send(&mut client, "D"), "OK");
+        assert_eq!(server.join().unwrap(), 0xFF);
+    }
+}
@@ -1,62 +1,542 @@
-use std::collections::VecDeque;
+pub const WIDTH: usize = 160;
+pub const HEIGHT: usize = 144;
 
-use minifb::Window;
-use minifb::WindowOptions;
+use crate::cpu::memory::Memory;
+use crate::model::Model;
+use crate::save_state::{StateError, StateReader, StateWriter};
+use crate::sgb;
 
-const WIDTH: usize = 160;
-const HEIGHT: usize = 144;
+// LCD registers
+const LCDC: u16 = 0xFF40;
+const STAT: u16 = 0xFF41;
+const SCY: u16 = 0xFF42;
+const SCX: u16 = 0xFF43;
+const LY: u16 = 0xFF44;
+const LYC: u16 = 0xFF45;
+const BGP: u16 = 0xFF47;
+const OBP0: u16 = 0xFF48;
+const OBP1: u16 = 0xFF49;
+const WY: u16 = 0xFF4A;
+const WX: u16 = 0xFF4B;
 
-use crate::cpu::memory::Memory;
+// LCD timings in dots, a dot is one cycle at normal speed
+const OAM_SCAN_DOTS: u32 = 80;
+const TRANSFER_DOTS: u32 = 172;
+const SCANLINE_DOTS: u32 = 456;
+const LINES_PER_FRAME: u8 = 154;
+pub const FRAME_DOTS: u32 = SCANLINE_DOTS * LINES_PER_FRAME as u32;
+// Frames per second of the LCD, about 59.73
+pub const FRAME_RATE: f64 = 4194304.0 / FRAME_DOTS as f64;
+
+// Tile data in VRAM, 0x8000 - 0x97FF in both banks, shown 16 tiles wide by the tile viewer
+pub const TILES_PER_BANK: usize = 384;
+const TILES_PER_ROW: usize = 16;
+pub const TILE_DATA_WIDTH: usize = TILES_PER_ROW * 8;
+pub const TILE_DATA_HEIGHT: usize = TILES_PER_BANK / TILES_PER_ROW * 8;
 
+// STAT modes
+const MODE_HBLANK: u8 = 0;
+const MODE_VBLANK: u8 = 1;
+const MODE_OAM_SCAN: u8 = 2;
+const MODE_TRANSFER: u8 = 3;
+
+// How the 15-bit CGB colors are converted to the 24-bit colors of the framebuffer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorCorrection {
+    // Scale every channel linearly, very saturated compared to the real LCD
+    Off,
+    // Per channel brightness curve of the CGB LCD
+    Curves,
+    // Curves plus the channel mixing of the CGB LCD, washes the colors out
+    Lcd,
+}
+
+impl ColorCorrection {
+    pub fn from_name(name: &str) -> Option<ColorCorrection> {
+        match name.to_ascii_lowercase().as_str() {
+            "off" => Some(ColorCorrection::Off),
+            "curves" => Some(ColorCorrection::Curves),
+            "lcd" => Some(ColorCorrection::Lcd),
+            _ => None,
+        }
+    }
+}
+
+// Shades used for DMG games when they aren't colored by a CGB or SGB
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DmgPalette {
+    Grey,
+    // The green tint of the original DMG screen
+    Green,
+}
+
+impl DmgPalette {
+    pub fn from_name(name: &str) -> Option<DmgPalette> {
+        match name.to_ascii_lowercase().as_str() {
+            "grey" | "gray" => Some(DmgPalette::Grey),
+            "green" => Some(DmgPalette::Green),
+            _ => None,
+        }
+    }
+
+    fn colors(&self) -> [u32; 4] {
+        match self {
+            DmgPalette::Grey => [0x00FFFFFF, 0x00AAAAAA, 0x00555555, 0x00000000],
+            DmgPalette::Green => [0x009BBC0F, 0x008BAC0F, 0x00306230, 0x000F380F],
+        }
+    }
+}
+
+// Renders the LCD into a framebuffer of 0RGB pixels, showing it is up to the frontend
 pub struct Gpu {
-    window: Window,
+    buffer: Vec<u32>,
+    // DMG shade (0-3) of every pixel, colored by the SGB into a bigger frame with the border
+    shades: Vec<u8>,
+    sgb_buffer: Vec<u32>,
+    sgb: bool,
+    // Dots elapsed in the current scanline (or frame while the LCD is off)
+    dots: u32,
+    lcd_on: bool,
+    // The window layer has its own line counter that only advances on lines where it is drawn
+    window_line: u8,
+    pub color_correction: ColorCorrection,
+    pub dmg_palette: DmgPalette,
 }
 
 impl Gpu {
-    pub fn new() -> Gpu {
-
-        // Initialize the window using minifb
-        let window = Window::new(
-            "Gameboy Emulator",
-            WIDTH,
-            HEIGHT,
-            WindowOptions {
-                resize: false,
-                scale: minifb::Scale::X4,
-                ..WindowOptions::default()
-            },
-        ).unwrap();
-
-        Gpu { window }
-    }
-
-    pub fn render(&mut self, memory: &Memory) {
-        if self.window.is_open() {
-            let tileset = memory.tileset();
-            let tile_vec = tile_to_vec(&tileset[0+2..16+2]);
-            // Testing tile: FF 00 7E FF 85 81 89 83 93 85 A5 8B C9 97 7E FF
-            // let tileset: [u8; 16] = [0xFF, 0x00, 0x7E, 0xFF, 0x85, 0x81, 0x89, 0x83, 0x93, 0x85, 0xA5, 0x8B, 0xC9, 0x97, 0x7E, 0xFF];
-            // let tile_vec: Vec<u8> = tile_to_vec(&tileset);
-            let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
-
-            for x in 0..8 {
-                for y in 0..8 {
-                    let color = tile_vec[x + y * 8];
-                    // print_tile(&tile_vec);
-                    let rgb = byte_to_rgb(color);
-                    buffer[x + y * WIDTH] = rgb;
+    pub fn new(color_correction: ColorCorrection, dmg_palette: DmgPalette, model: Model) -> Gpu {
+        Gpu {
+            buffer: vec![0x00FFFFFF; WIDTH * HEIGHT],
+            shades: vec![0; WIDTH * HEIGHT],
+            sgb_buffer: vec![0; sgb::SCREEN_WIDTH * sgb::SCREEN_HEIGHT],
+            sgb: model == Model::Sgb,
+            dots: 0,
+            lcd_on: false,
+            window_line: 0,
+            color_correction,
+            dmg_palette,
+        }
+    }
+
+    // The SGB shows the game inside a border
+    pub fn screen_size(&self) -> (usize, usize) {
+        if self.sgb {
+            (sgb::SCREEN_WIDTH, sgb::SCREEN_HEIGHT)
+        } else {
+            (WIDTH, HEIGHT)
+        }
+    }
+
+    // The last complete frame, `screen_size()` pixels
+    pub fn frame(&self) -> &[u32] {
+        if self.sgb {
+            &self.sgb_buffer
+        } else {
+            &self.buffer
+        }
+    }
+
+    // Size of `render_tile_data()`, the CGB has a second bank shown on the right
+    pub fn tile_data_size(model: Model) -> (usize, usize) {
+        let banks = if model.is_cgb() { 2 } else { 1 };
+        (TILE_DATA_WIDTH * banks, TILE_DATA_HEIGHT)
+    }
+
+    // Every tile in VRAM with the colors of BGP, or BG palette 0 on the CGB
+    pub fn render_tile_data(&self, memory: &Memory) -> Vec<u32> {
+        let (width, height) = Gpu::tile_data_size(memory.model);
+        let mut image = vec![0; width * height];
+        let colors: Vec<u32> = (0..4)
+            .map(|color| match memory.cgb_mode() {
+                true => self.cgb_color(memory.bg_palette_ram(), 0, color),
+                false => self.dmg_color(memory, memory.bg_palette_ram(), 0, shade(memory.read_byte(BGP), color)),
+            })
+            .collect();
+
+        for bank in 0..width / TILE_DATA_WIDTH {
+            let vram = memory.vram(bank as u8);
+            for tile in 0..TILES_PER_BANK {
+                let x = bank * TILE_DATA_WIDTH + tile % TILES_PER_ROW * 8;
+                let y = tile / TILES_PER_ROW * 8;
+                for (i, &color) in tile_to_vec(&vram[tile * 16..tile * 16 + 16]).iter().enumerate() {
+                    image[(y + i / 8) * width + x + i % 8] = colors[color as usize];
+                }
+            }
+        }
+        image
+    }
+
+    // The frame is saved too, so it can be shown before the next one is drawn
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u32(self.dots);
+        state.bool(self.lcd_on);
+        state.u8(self.window_line);
+        for pixel in &self.buffer {
+            state.u32(*pixel);
+        }
+        state.bytes(&self.shades);
+        if self.sgb {
+            for pixel in &self.sgb_buffer {
+                state.u32(*pixel);
+            }
+        }
+    }
+
+    // `version` is the one of the state, version 1 has the SGB frame on every model
+    pub(crate) fn load_state(&mut self, state: &mut StateReader, version: u16) -> Result<(), StateError> {
+        self.dots = state.u32()?;
+        self.lcd_on = state.bool()?;
+        self.window_line = state.u8()?;
+        for pixel in &mut self.buffer {
+            *pixel = state.u32()?;
+        }
+        state.bytes(&mut self.shades)?;
+        if self.sgb || version == 1 {
+            for pixel in &mut self.sgb_buffer {
+                *pixel = state.u32()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Advance the LCD by the number of CPU cycles of the last instruction,
+    // returns true when a frame was completed
+    pub fn step(&mut self, memory: &mut Memory, cycles: u32) -> bool {
+        // In double speed mode the CPU runs twice as fast as the LCD
+        let dots = if memory.double_speed { cycles / 2 } else { cycles };
+
+        if memory.read_byte(LCDC) & 0x80 == 0 {
+            // While the LCD is off LY stays at 0 and nothing is drawn, but frames
+            // are still completed so the frontend stays responsive
+            if self.lcd_on {
+                self.lcd_on = false;
+                self.dots = 0;
+                memory.memory[LY as usize] = 0;
+                set_mode(memory, MODE_HBLANK);
+                let white = if memory.model.is_cgb() { 0x00FFFFFF } else { self.dmg_palette.colors()[0] };
+                self.buffer.fill(white);
+                self.shades.fill(0);
+            }
+            self.dots += dots;
+            if self.dots >= FRAME_DOTS {
+                self.dots -= FRAME_DOTS;
+                self.present(memory);
+                return true;
+            }
+            return false;
+        }
+        if !self.lcd_on {
+            self.lcd_on = true;
+            self.dots = 0;
+            self.window_line = 0;
+            set_mode(memory, MODE_OAM_SCAN);
+            compare_ly(memory);
+        }
+
+        self.dots += dots;
+        // A long VRAM DMA stall can span several lines, which all go through their modes
+        let mut frame_done = false;
+        loop {
+            // Not read through `read_byte`, which returns the LY stubbed for traces
+            let ly = memory.memory[LY as usize];
+
+            if ly < HEIGHT as u8 {
+                let mode = if self.dots < OAM_SCAN_DOTS {
+                    MODE_OAM_SCAN
+                } else if self.dots < OAM_SCAN_DOTS + TRANSFER_DOTS {
+                    MODE_TRANSFER
+                } else {
+                    MODE_HBLANK
+                };
+                if mode != memory.read_byte(STAT) & 0x03 {
+                    // The whole line is drawn at once when the transfer ends
+                    if mode == MODE_HBLANK {
+                        self.render_scanline(memory, ly);
+                        memory.hblank_dma();
+                    }
+                    set_mode(memory, mode);
+                }
+            }
+
+            if self.dots < SCANLINE_DOTS {
+                break;
+            }
+            self.dots -= SCANLINE_DOTS;
+            let ly = (ly + 1) % LINES_PER_FRAME;
+            memory.memory[LY as usize] = ly;
+            compare_ly(memory);
+
+            if ly == HEIGHT as u8 {
+                set_mode(memory, MODE_VBLANK);
+                memory.request_interrupt(0);
+                self.present(memory);
+                frame_done = true;
+            } else if ly == 0 {
+                self.window_line = 0;
+                set_mode(memory, MODE_OAM_SCAN);
+            }
+        }
+        frame_done
+    }
+
+    // The SGB colors the finished frame and draws its border around it
+    fn present(&mut self, memory: &mut Memory) {
+        if let Some(sgb) = &mut memory.sgb {
+            sgb.render(&self.shades, &mut self.sgb_buffer);
+        }
+    }
+
+    fn render_scanline(&mut self, memory: &Memory, ly: u8) {
+        let cgb = memory.cgb_mode();
+        let lcdc = memory.read_byte(LCDC);
+        let vram = [memory.vram(0), memory.vram(1)];
+        let line = ly as usize * WIDTH;
+
+        // Color index and CGB priority attribute of the background, needed to mix the sprites
+        let mut bg_colors = [0u8; WIDTH];
+        let mut bg_priority = [false; WIDTH];
+
+        // On the CGB bit 0 of LCDC doesn't disable the background, it removes its priority instead
+        if cgb || lcdc & 0x01 != 0 {
+            let scy = memory.read_byte(SCY);
+            let scx = memory.read_byte(SCX);
+            let wy = memory.read_byte(WY);
+            let wx = memory.read_byte(WX) as usize;
+            let window_visible = lcdc & 0x20 != 0 && ly >= wy && wx <= 166;
+
+            for x in 0..WIDTH {
+                let in_window = window_visible && x + 7 >= wx;
+                let (map_base, map_x, map_y) = if in_window {
+                    let map_base = if lcdc & 0x40 != 0 { 0x1C00 } else { 0x1800 };
+                    (map_base, x + 7 - wx, self.window_line as usize)
+                } else {
+                    let map_base = if lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+                    (map_base, (x + scx as usize) & 0xFF, (ly as usize + scy as usize) & 0xFF)
+                };
+
+                let map_address = map_base + (map_y / 8) * 32 + map_x / 8;
+                let tile_index = vram[0][map_address];
+                // CGB attributes: bit 0-2 palette, 3 tile bank, 5 x flip, 6 y flip, 7 priority
+                let attributes = if cgb { vram[1][map_address] } else { 0 };
+
+                let tile_address = if lcdc & 0x10 != 0 {
+                    tile_index as usize * 16
+                } else {
+                    (0x1000 + tile_index as i8 as i32 * 16) as usize
+                };
+                let row = if attributes & 0x40 != 0 { 7 - map_y % 8 } else { map_y % 8 };
+                let column = if attributes & 0x20 != 0 { 7 - map_x % 8 } else { map_x % 8 };
+                let bank = ((attributes >> 3) & 0x01) as usize;
+                let color = tile_pixel(vram[bank], tile_address, row, column);
+
+                bg_colors[x] = color;
+                bg_priority[x] = attributes & 0x80 != 0;
+                self.buffer[line + x] = if cgb {
+                    self.cgb_color(memory.bg_palette_ram(), attributes & 0x07, color)
+                } else {
+                    let shade = shade(memory.read_byte(BGP), color);
+                    self.shades[line + x] = shade;
+                    self.dmg_color(memory, memory.bg_palette_ram(), 0, shade)
+                };
+            }
+
+            if window_visible {
+                self.window_line += 1;
+            }
+        } else {
+            let white = self.dmg_color(memory, memory.bg_palette_ram(), 0, 0);
+            self.buffer[line..line + WIDTH].fill(white);
+            self.shades[line..line + WIDTH].fill(0);
+        }
+
+        if lcdc & 0x02 != 0 {
+            self.render_sprites(memory, ly, lcdc, &bg_colors, &bg_priority);
+        }
+    }
+
+    fn render_sprites(&mut self, memory: &Memory, ly: u8, lcdc: u8, bg_colors: &[u8; WIDTH], bg_priority: &[bool; WIDTH]) {
+        let cgb = memory.cgb_mode();
+        let vram = [memory.vram(0), memory.vram(1)];
+        let height = if lcdc & 0x04 != 0 { 16 } else { 8 };
+        let line = ly as usize * WIDTH;
+
+        // Only the first 10 sprites in OAM that are on this line are drawn
+        let mut sprites: Vec<(u8, u8, u8, u8)> = (0..40)
+            .map(|index| {
+                let address = 0xFE00 + index * 4;
+                (
+                    memory.read_byte(address),
+                    memory.read_byte(address + 1),
+                    memory.read_byte(address + 2),
+                    memory.read_byte(address + 3),
+                )
+            })
+            .filter(|&(y, _, _, _)| ly as i32 + 16 >= y as i32 && (ly as i32 + 16) < y as i32 + height)
+            .take(10)
+            .collect();
+
+        // On the DMG the sprite with the smallest x wins, on the CGB the first one in OAM
+        if !cgb {
+            sprites.sort_by_key(|&(_, x, _, _)| x);
+        }
+
+        let mut drawn = [false; WIDTH];
+        for (y, x, tile, attributes) in sprites {
+            let mut row = (ly as i32 + 16 - y as i32) as usize;
+            if attributes & 0x40 != 0 {
+                row = height as usize - 1 - row;
+            }
+            let tile = if height == 16 { tile & 0xFE } else { tile };
+            let tile_address = tile as usize * 16 + (row / 8) * 16;
+            let bank = if cgb { ((attributes >> 3) & 0x01) as usize } else { 0 };
+
+            for pixel in 0..8 {
+                let screen_x = x as i32 - 8 + pixel;
+                if !(0..WIDTH as i32).contains(&screen_x) || drawn[screen_x as usize] {
+                    continue;
+                }
+                let screen_x = screen_x as usize;
+                let column = if attributes & 0x20 != 0 { 7 - pixel as usize } else { pixel as usize };
+                let color = tile_pixel(vram[bank], tile_address, row % 8, column);
+                if color == 0 {
+                    continue;
                 }
+                drawn[screen_x] = true;
+
+                let behind_bg = if cgb {
+                    lcdc & 0x01 != 0 && bg_colors[screen_x] != 0 && (bg_priority[screen_x] || attributes & 0x80 != 0)
+                } else {
+                    attributes & 0x80 != 0 && bg_colors[screen_x] != 0
+                };
+                if behind_bg {
+                    continue;
+                }
+
+                self.buffer[line + screen_x] = if cgb {
+                    self.cgb_color(memory.obj_palette_ram(), attributes & 0x07, color)
+                } else {
+                    let (palette, register) = if attributes & 0x10 != 0 { (1, OBP1) } else { (0, OBP0) };
+                    let shade = shade(memory.read_byte(register), color);
+                    self.shades[line + screen_x] = shade;
+                    self.dmg_color(memory, memory.obj_palette_ram(), palette, shade)
+                };
             }
+        }
+    }
+
+    // Look up a color in CGB palette RAM, every palette has 4 little endian BGR555 colors
+    fn cgb_color(&self, palette_ram: &[u8], palette: u8, color: u8) -> u32 {
+        let index = palette as usize * 8 + color as usize * 2;
+        let bgr555 = palette_ram[index] as u16 | (palette_ram[index + 1] as u16) << 8;
+        bgr555_to_rgb(bgr555, self.color_correction)
+    }
 
-            self.window.update_with_buffer(&buffer, WIDTH, HEIGHT).unwrap();
+    // DMG palettes are shades of grey, unless a CGB colors them in compatibility mode
+    fn dmg_color(&self, memory: &Memory, palette_ram: &[u8], palette: u8, shade: u8) -> u32 {
+        if memory.model.is_cgb() {
+            self.cgb_color(palette_ram, palette, shade)
+        } else {
+            self.dmg_palette.colors()[shade as usize]
         }
     }
+}
 
+// Write the mode to STAT and request the STAT interrupt if it is enabled for that mode
+fn set_mode(memory: &mut Memory, mode: u8) {
+    let stat = memory.read_byte(STAT);
+    memory.memory[STAT as usize] = (stat & !0x03) | mode;
+
+    let interrupt_enabled = match mode {
+        MODE_HBLANK => stat & 0x08 != 0,
+        MODE_VBLANK => stat & 0x10 != 0,
+        MODE_OAM_SCAN => stat & 0x20 != 0,
+        _ => false,
+    };
+    if interrupt_enabled {
+        memory.request_interrupt(1);
+    }
 }
+
+// Update the LY == LYC flag of STAT and request the STAT interrupt if it is enabled
+fn compare_ly(memory: &mut Memory) {
+    let stat = memory.read_byte(STAT);
+    if memory.memory[LY as usize] == memory.read_byte(LYC) {
+        memory.memory[STAT as usize] = stat | 0x04;
+        if stat & 0x40 != 0 {
+            memory.request_interrupt(1);
+        }
+    } else {
+        memory.memory[STAT as usize] = stat & !0x04;
+    }
+}
+
+// Color index (0-3) of a single pixel of the tile starting at `tile_address` in VRAM
+fn tile_pixel(vram: &[u8], tile_address: usize, row: usize, column: usize) -> u8 {
+    let byte1 = vram[tile_address + row * 2];
+    let byte2 = vram[tile_address + row * 2 + 1];
+    let bit1 = (byte1 >> (7 - column)) & 0x01;
+    let bit2 = (byte2 >> (7 - column)) & 0x01;
+    bit1 | (bit2 << 1)
+}
+
+// Shade (0-3) a DMG palette register (BGP, OBP0, OBP1) assigns to a color index
+fn shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+// Brightness of a 5-bit channel on the CGB LCD, which is darker in the low end than a linear scale
+const CHANNEL_CURVE: [u8; 32] = [
+    0, 6, 12, 20, 28, 36, 45, 56, 66, 76, 88, 100, 113, 125, 137, 149, 161, 172, 182, 192, 202,
+    210, 218, 225, 232, 238, 243, 247, 250, 252, 254, 255,
+];
+
+pub fn bgr555_to_rgb(bgr555: u16, color_correction: ColorCorrection) -> u32 {
+    let r = (bgr555 & 0x1F) as u32;
+    let g = ((bgr555 >> 5) & 0x1F) as u32;
+    let b = ((bgr555 >> 10) & 0x1F) as u32;
+
+    let (r, g, b) = match color_correction {
+        ColorCorrection::Off => ((r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2)),
+        ColorCorrection::Curves => (
+            CHANNEL_CURVE[r as usize] as u32,
+            CHANNEL_CURVE[g as usize] as u32,
+            CHANNEL_CURVE[b as usize] as u32,
+        ),
+        ColorCorrection::Lcd => {
+            // Every subpixel of the LCD bleeds into the others
+            let (r, g, b) = (
+                CHANNEL_CURVE[r as usize] as u32,
+                CHANNEL_CURVE[g as usize] as u32,
+                CHANNEL_CURVE[b as usize] as u32,
+            );
+            ((r * 13 + g * 2 + b) / 16, (g * 3 + b) / 4, (r * 3 + g * 2 + b * 11) / 16)
+        }
+    };
+
+    (r << 16) | (g << 8) | b
+}
+
+// Binary PPM (P6) image of a 0RGB frame, readable by most image tools without any encoding
+pub fn encode_ppm(frame: &[u32], width: usize, height: usize) -> Vec<u8> {
+    let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for pixel in &frame[..width * height] {
+        image.extend_from_slice(&pixel.to_be_bytes()[1..]);
+    }
+    image
+}
+
+// VRAM bank and index (0 - 383) of the tile at a pixel of `render_tile_data()`
+pub fn tile_at(x: usize, y: usize) -> Option<(u8, usize)> {
+    let bank = x / TILE_DATA_WIDTH;
+    let tile = y / 8 * TILES_PER_ROW + x % TILE_DATA_WIDTH / 8;
+    (bank < 2 && tile < TILES_PER_BANK).then_some((bank as u8, tile))
+}
+
+// Decode a whole 8x8 tile into color indexes
 fn tile_to_vec(tile: &[u8]) -> Vec<u8> {
     let mut vec = Vec::new();
 
-    for i in (0..8) {
+    for i in 0..8 {
         let i = i * 2;
         let byte1 = tile[i];
         let byte2 = tile[i + 1];
@@ -72,11 +552,12 @@ fn tile_to_vec(tile: &[u8]) -> Vec<u8> {
     vec
 }
 
-fn print_tile(tile: &Vec<u8>) {
+// Debug helper to dump a decoded tile to stdout
+#[allow(dead_code)]
+fn print_tile(tile: &[u8]) {
     for i in 0..8 {
         for j in 0..8 {
             let color = tile[i + j * 8];
-            let rgb = byte_to_rgb(color);
             print!("| {:x} |\t", color);
         }
         println!("\n-----------------");
@@ -84,12 +565,85 @@ fn print_tile(tile: &Vec<u8>) {
     println!("........................");
 }
 
-fn byte_to_rgb(byte: u8) -> u32 {
-    match byte {
-        0 => 0x00FFFFFF,
-        1 => 0x00AAAAAA,
-        2 => 0x00555555,
-        3 => 0x00000000,
-        _ => panic!("Invalid byte"),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bgr555_to_rgb() {
+        assert_eq!(bgr555_to_rgb(0x7FFF, ColorCorrection::Off), 0x00FFFFFF);
+        assert_eq!(bgr555_to_rgb(0x001F, ColorCorrection::Off), 0x00FF0000);
+        assert_eq!(bgr555_to_rgb(0x7C00, ColorCorrection::Curves), 0x000000FF);
+        assert_eq!(bgr555_to_rgb(0x0000, ColorCorrection::Lcd), 0x00000000);
+        assert_eq!(bgr555_to_rgb(0x7FFF, ColorCorrection::Lcd), 0x00FFFFFF);
+    }
+
+    #[test]
+    fn test_render_scanlines() {
+        let mut memory = Memory::new(Model::Dmg);
+        // Tile 1 is all color 1 and tile 2 all color 3, the map shows tile 1 in its top left corner
+        for row in 0..8 {
+            memory.write_byte(0x8010 + row * 2, 0xFF);
+            memory.write_byte(0x8020 + row * 2, 0xFF);
+            memory.write_byte(0x8021 + row * 2, 0xFF);
+        }
+        memory.write_byte(0x9800, 0x01);
+        // Sprite 0 over the background at x 16, sprite 1 behind tile 1 at x 0
+        let oam = [16, 24, 2, 0x00, 16, 8, 2, 0x80];
+        for (i, value) in oam.into_iter().enumerate() {
+            memory.write_byte(0xFE00 + i as u16, value);
+        }
+        memory.write_byte(LCDC, 0x93);
+        memory.write_byte(BGP, 0xE4);
+        memory.write_byte(OBP0, 0xE4);
+        let mut gpu = Gpu::new(ColorCorrection::Off, DmgPalette::Grey, Model::Dmg);
+        while !gpu.step(&mut memory, 4) {}
+
+        let colors = DmgPalette::Grey.colors();
+        let frame = gpu.frame();
+        assert_eq!(frame[0], colors[1]);
+        assert_eq!(frame[7 * WIDTH + 7], colors[1]);
+        assert_eq!(frame[8], colors[0]);
+        assert_eq!(frame[16], colors[3]);
+        assert_eq!(frame[7 * WIDTH + 23], colors[3]);
+        assert_eq!(frame[8 * WIDTH + 16], colors[0]);
+        assert_eq!(memory.read_byte(LY), HEIGHT as u8);
+    }
+
+    #[test]
+    fn test_long_step() {
+        let mut memory = Memory::new(Model::Dmg);
+        memory.write_byte(LCDC, 0x91);
+        let mut gpu = Gpu::new(ColorCorrection::Off, DmgPalette::Grey, Model::Dmg);
+        gpu.step(&mut memory, 4);
+
+        // A DMA stall of several lines at once keeps LY and the modes in step
+        assert!(!gpu.step(&mut memory, 3 * SCANLINE_DOTS));
+        assert_eq!(memory.read_byte(LY), 3);
+        assert_eq!(memory.read_byte(STAT) & 0x03, MODE_OAM_SCAN);
+        assert!(gpu.step(&mut memory, (HEIGHT as u32 - 3) * SCANLINE_DOTS));
+        assert_eq!(memory.read_byte(LY), HEIGHT as u8);
+        assert_eq!(memory.read_byte(STAT) & 0x03, MODE_VBLANK);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_render_tile_data() {
+        let mut memory = Memory::new(Model::Dmg);
+        // Tile 17, a line of color 3 on top and color 1 below
+        memory.write_byte(0x8110, 0xFF);
+        memory.write_byte(0x8111, 0xFF);
+        memory.write_byte(0x8112, 0xFF);
+        memory.write_byte(BGP, 0xE4);
+        let gpu = Gpu::new(ColorCorrection::Off, DmgPalette::Grey, Model::Dmg);
+
+        let image = gpu.render_tile_data(&memory);
+        let colors = DmgPalette::Grey.colors();
+        assert_eq!(image.len(), TILE_DATA_WIDTH * TILE_DATA_HEIGHT);
+        assert_eq!(image[8 * TILE_DATA_WIDTH + 8], colors[3]);
+        assert_eq!(image[9 * TILE_DATA_WIDTH + 15], colors[1]);
+        assert_eq!(image[10 * TILE_DATA_WIDTH + 8], colors[0]);
+        assert_eq!(tile_at(8, 15), Some((0, 17)));
+        assert_eq!(tile_at(2 * TILE_DATA_WIDTH - 1, TILE_DATA_HEIGHT - 1), Some((1, 383)));
+        assert_eq!(tile_at(0, TILE_DATA_HEIGHT), None);
+    }
+}
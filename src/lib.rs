@@ -0,0 +1,32 @@
+#![allow(clippy::upper_case_acronyms)]
+
+// Game Boy emulator core. `GameBoy` runs the console and hands out frames,
+// showing them and reading the keyboard is left to the frontend
+
+#[macro_use]
+pub mod logger;
+
+#[macro_use]
+pub mod assembler;
+pub mod call_stack;
+pub mod cdl;
+pub mod compat_palette;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod gameboy;
+pub mod gdb;
+pub mod gpu;
+mod licensee;
+pub mod model;
+pub mod png;
+pub mod rom_info;
+pub mod rewind;
+pub mod rom_reader;
+pub mod save_state;
+pub mod sgb;
+pub mod symbols;
+pub mod trace;
+pub mod y4m;
+
+pub use gameboy::{Button, Config, GameBoy, LoadError};
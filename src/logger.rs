@@ -0,0 +1,86 @@
+// Minimal logging to stderr, filtered by the level set by the frontend (`--log-level`).
+// stdout is left for the output of the commands (info, disasm, serial output of tests)
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match name.to_ascii_lowercase().as_str() {
+            "off" => Some(LogLevel::Off),
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            "trace" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+// The library logs through `warn!`, `info!`, `debug!` and `trace!`, which stay inside the crate
+// so they don't clash with the `log` crate of embedders. The frontend gets the prefixed ones it uses
+#[doc(hidden)]
+#[macro_export]
+macro_rules! gb_log {
+    ($level:expr, $prefix:literal, $($arg:tt)*) => {
+        if $crate::logger::enabled($level) {
+            eprintln!(concat!("[", $prefix, "] {}"), format_args!($($arg)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! gb_error {
+    ($($arg:tt)*) => { $crate::gb_log!($crate::logger::LogLevel::Error, "error", $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! gb_warn {
+    ($($arg:tt)*) => { $crate::gb_log!($crate::logger::LogLevel::Warn, "warn", $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! gb_info {
+    ($($arg:tt)*) => { $crate::gb_log!($crate::logger::LogLevel::Info, "info", $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! gb_trace {
+    ($($arg:tt)*) => { $crate::gb_log!($crate::logger::LogLevel::Trace, "trace", $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::gb_warn!($($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::gb_info!($($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::gb_log!($crate::logger::LogLevel::Debug, "debug", $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::gb_trace!($($arg)*) };
+}
@@ -1,81 +1,625 @@
 use std::env;
 use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-mod rom_reader;
-use rom_reader::{CartridgeType, RomHeader};
+use gameboy_emu::call_stack;
+use gameboy_emu::cdl::CodeDataLog;
+use gameboy_emu::debugger::{Debugger, Reply, Stop};
+use gameboy_emu::disassembler;
+use gameboy_emu::gameboy::CLOCK_SPEED;
+use gameboy_emu::gdb::GdbStub;
+use gameboy_emu::gpu;
+use gameboy_emu::logger;
+use gameboy_emu::rom_info::RomInfo;
+use gameboy_emu::rom_reader::RomHeader;
+use gameboy_emu::png;
+use gameboy_emu::rewind::Rewind;
+use gameboy_emu::symbols::Symbols;
+use gameboy_emu::trace::Trace;
+use gameboy_emu::y4m::VideoWriter;
+use gameboy_emu::{gb_error as error, gb_info as info, gb_trace as trace, gb_warn as warn};
+use gameboy_emu::{Config, GameBoy};
 
-mod cpu;
-use cpu::Cpu;
+mod cli;
+use cli::{CliError, Command, RunOptions};
 
-mod gpu;
-use gpu::Gpu;
+mod pacer;
+use pacer::Pacer;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+mod window;
+use window::{Hotkey, Screen, TileViewer};
 
-    let file_path = &args[1];
+// Range of the speed changed with the hotkeys, in times the normal speed
+const MIN_SPEED: f64 = 0.125;
+const MAX_SPEED: f64 = 8.0;
 
-    println!("Opening file {}", file_path);
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    let rom_vec = fs::read(file_path).expect("Should have been able to read the file");
+    let result = cli::parse(&args).and_then(|cli| {
+        logger::set_level(cli.log_level);
+        match cli.command {
+            Command::Run(options) => run(&options),
+            Command::Info { rom, json } => info(&rom, json),
+            Command::Disasm { rom, start, end, recursive, cdl } => disasm(&rom, start, end, recursive, cdl.as_deref()),
+            Command::Test { options, timeout } => test(&options, timeout),
+            // Without any arguments the usage is shown as for a usage error
+            Command::Help if args.is_empty() => {
+                eprintln!("{}", cli::USAGE);
+                Ok(ExitCode::from(2))
+            }
+            Command::Help => {
+                println!("{}", cli::USAGE);
+                Ok(ExitCode::SUCCESS)
+            }
+        }
+    });
 
-    // To test if the file is read correctly print the lenght of the file in Kbit hex
-    println!(
-        "File size: {} Kbit / 0x{:x} byte [0x0000 -> 0x{:x}] (N.{} Memory Banks)",
-        rom_vec.len() * 8,
-        rom_vec.len(),
-        rom_vec.len() - 1,
-        rom_vec.len() / 0x4000
-    );
+    match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            if error.is_usage() {
+                eprintln!("Run `gameboy-emu help` to see the commands and options");
+                ExitCode::from(2)
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
 
-    // Create a new RomHeader struct from the vector
-    let rom_header = RomHeader::from_vec(&rom_vec);
+fn run(options: &RunOptions) -> Result<ExitCode, CliError> {
+    if options.debug {
+        return debug(options);
+    }
+    if let Some(port) = options.gdb {
+        return gdb(options, port);
+    }
+    let mut gameboy = create_gameboy(options)?;
+    // Labels for the trace log and the profile
+    let symbols = load_symbols(&options.rom);
 
-    // Print the title of the ROM, the ROM size and RAM size
-    println!(
-        "Title: {}",
-        String::from_utf8(rom_header.title.to_vec()).unwrap()
-    );
-    println!("ROM size: {}", rom_header.rom_size);
-    println!("RAM size: {}", rom_header.ram_size);
-    // Print the cartridge type from the enum
-    println!(
-        "Cartridge type: {:?}",
-        CartridgeType::from_u8(rom_header.cartridge_type).unwrap()
-    );
+    // Battery backed RAM is loaded at start and written back when the window is closed
+    let save_path = gameboy.header().has_battery().then(|| save_path(options, "sav"));
+    if let Some(path) = save_path.as_ref().filter(|path| path.exists()) {
+        let ram = fs::read(path).map_err(|error| CliError::Read { path: path.clone(), error })?;
+        gameboy.load_save_data(&ram);
+        info!("Loaded save {}", path.display());
+    }
 
-    // Initialize the CPU
-    let mut cpu = Cpu::new();
+    // Slot used by `--load-state`, `--save-state` and the state hotkeys
+    let mut slot = options.load_state.or(options.save_state).unwrap_or(1);
+    if options.load_state.is_some() {
+        load_state(&mut gameboy, &state_path(options, slot))?;
+    }
 
-    cpu.memory.load_rom(&rom_vec);
+    let mut screen = open_screen(&gameboy, options)?;
+    let mut tile_viewer = match (options.tiles, &screen) {
+        (true, Some(_)) => {
+            // At most 4 times, so both CGB banks still fit on the screen
+            let (width, height) = gameboy.tile_data_size();
+            let viewer = TileViewer::open(width, height, options.scale.min(4))
+                .map_err(|error| CliError::Window(error.to_string()))?;
+            Some(viewer)
+        }
+        (true, None) => {
+            warn!("There is no tile viewer without a window");
+            None
+        }
+        (false, _) => None,
+    };
 
-    let mut gpu = Gpu::new();
+    // Every emulated frame is recorded, whether the window keeps up or not.
+    // There is no sound emulation yet, so there is no audio to record along with it
+    let mut video = match &options.record_video {
+        Some(path) => {
+            let (width, height) = gameboy.screen_size();
+            let file = fs::File::create(path).map_err(|error| CliError::Write { path: path.clone(), error })?;
+            let video = VideoWriter::new(BufWriter::new(file), width, height)
+                .map_err(|error| CliError::Write { path: path.clone(), error })?;
+            info!("Recording video to {}", path.display());
+            Some(video)
+        }
+        None => None,
+    };
 
-    // Run the CPU
-    let mut counter: u64 = 0;
-    loop {
-        // println!("Counter: {} | PC: {:#x}", counter, cpu.registers.pc);
-        cpu.step();
-        if counter%1000 == 0 {
-            println!("Counter: {} | PC: {:#x}", counter, cpu.registers.pc);
+    // Run frame by frame until the window is closed or `--frames` have run. Headless runs
+    // without `--frames` stop after the last screenshot, or go on until the process is stopped
+    let last_frame = match options.frames {
+        Some(frames) => Some(frames),
+        None if options.headless => options.screenshots.iter().map(|&(frame, _)| frame).max(),
+        None => None,
+    };
+    let mut frames: u64 = 0;
+    let mut pacer = Pacer::new();
+    let mut speed = options.speed;
+    let mut paused = false;
+    // Rewinding runs frames again, which would be logged and profiled twice
+    let replays = options.trace.is_some() || options.profile.is_some();
+    let mut rewind = (!options.headless && options.rewind > 0.0 && !replays).then(|| Rewind::new(options.rewind));
+    while screen.as_ref().is_none_or(Screen::is_open) && last_frame.is_none_or(|limit| frames < limit) {
+        // Headless runs go as fast as possible, the window is paced and controlled by the hotkeys
+        let mut advance = true;
+        let mut rewinding = false;
+        let mut pressed = Vec::new();
+        // Closing the tile viewer leaves the game running
+        if let Some(viewer) = &mut tile_viewer {
+            viewer.show(&gameboy.tile_data()).map_err(|error| CliError::Window(error.to_string()))?;
+        }
+        if tile_viewer.as_ref().is_some_and(|viewer| !viewer.is_open()) {
+            tile_viewer = None;
         }
-        // Print the registers
-        // println!("Registers: {:#?}", cpu.registers);
+        if let Some(screen) = &mut screen {
+            let (old_speed, was_paused) = (speed, paused);
+            if screen.hotkey_pressed(Hotkey::Pause) {
+                paused = !paused;
+            }
+            if screen.hotkey_pressed(Hotkey::SlowDown) {
+                speed = (speed / 2.0).max(MIN_SPEED);
+            }
+            if screen.hotkey_pressed(Hotkey::SpeedUp) {
+                speed = (speed * 2.0).min(MAX_SPEED);
+            }
+            if screen.hotkey_pressed(Hotkey::ResetSpeed) {
+                speed = options.speed;
+            }
+            if speed != old_speed || paused != was_paused {
+                screen.set_status(&status(speed, paused));
+            }
 
-        // Renders the screen
-        gpu.render(&cpu.memory);
+            if let Some(selected) = screen.selected_slot() {
+                slot = selected;
+                info!("State slot {}", slot);
+            }
+            if screen.hotkey_pressed(Hotkey::SaveState) {
+                if let Err(error) = save_state(&gameboy, &state_path(options, slot)) {
+                    error!("{}", error);
+                }
+            }
+            if screen.hotkey_pressed(Hotkey::LoadState) {
+                if let Err(error) = load_state(&mut gameboy, &state_path(options, slot)) {
+                    error!("{}", error);
+                }
+            }
+            // Saves the frame on screen, also while paused
+            if screen.hotkey_pressed(Hotkey::Screenshot) {
+                let path = screenshot_path(options);
+                if let Err(error) = save_screenshot(&gameboy, &path, options.screenshot_scale) {
+                    error!("{}", error);
+                }
+            }
 
-        // Sleep for a bit to slow down the execution
-        // std::thread::sleep(std::time::Duration::from_micros(1));
-        counter = counter.wrapping_add(1 as u64);
+            advance = !paused || screen.hotkey_pressed(Hotkey::FrameAdvance);
+            rewinding = screen.rewind_held();
+            pressed = screen.pressed_buttons();
+        }
+
+        // Rewinding goes back one frame per frame shown, at the speed of the game
+        if let (true, Some(rewind), Some(screen)) = (rewinding, &mut rewind, &mut screen) {
+            if let Err(error) = rewind.rewind_frame(&mut gameboy) {
+                warn!("Couldn't rewind: {}", error);
+            }
+            screen.show(gameboy.framebuffer()).map_err(|error| CliError::Window(error.to_string()))?;
+            pacer.wait(speed);
+            continue;
+        }
+        if !advance {
+            if let Some(screen) = &mut screen {
+                screen.show(gameboy.framebuffer()).map_err(|error| CliError::Window(error.to_string()))?;
+            }
+            pacer.wait(1.0);
+            continue;
+        }
+
+        match &mut rewind {
+            Some(rewind) => rewind.run_frame(&mut gameboy, &pressed),
+            None => {
+                gameboy.set_input(&pressed);
+                gameboy.run_frame();
+            }
+        }
+        if frames.is_multiple_of(60) {
+            let pc = gameboy.cpu.registers.pc;
+            let label = symbols.label(gameboy.rom_bank(), pc).map(|label| format!(" ({})", label));
+            trace!("Frame: {} | PC: {:#x}{}", frames, pc, label.unwrap_or_default());
+        }
+
+        frames = frames.wrapping_add(1);
+
+        if let (Some(video), Some(path)) = (&mut video, &options.record_video) {
+            video
+                .write_frame(gameboy.framebuffer())
+                .map_err(|error| CliError::Write { path: path.clone(), error })?;
+        }
+
+        for (_, path) in options.screenshots.iter().filter(|&&(frame, _)| frame == frames) {
+            save_screenshot(&gameboy, path, options.screenshot_scale)?;
+        }
+        if let Some(screen) = &mut screen {
+            screen.show(gameboy.framebuffer()).map_err(|error| CliError::Window(error.to_string()))?;
+
+            // Fast-forward multiplies the speed, a factor of 0 removes the limit
+            let fast_forward = screen.fast_forward_held();
+            pacer.wait(if fast_forward { speed * options.fast_forward } else { speed });
+        }
+    }
+
+    if let (Some(video), Some(path)) = (video, &options.record_video) {
+        let frames = video.frames();
+        video.finish().map_err(|error| CliError::Write { path: path.clone(), error })?;
+        info!("Recorded {} frames to {}", frames, path.display());
+    }
+
+    if let Some(path) = &options.dump_frame {
+        let (width, height) = gameboy.screen_size();
+        let image = gpu::encode_ppm(gameboy.framebuffer(), width, height);
+        fs::write(path, image).map_err(|error| CliError::Write { path: path.clone(), error })?;
+        info!("Wrote the last frame to {}", path.display());
+    }
 
-        // if counter > 5 {
-        //     //wait
-        //     loop {}
-        // }
+    if let Some(slot) = options.save_state {
+        save_state(&gameboy, &state_path(options, slot))?;
     }
+    if let Some(path) = &options.profile {
+        write_profile(&gameboy, path, &symbols)?;
+    }
+    write_cdl(&gameboy, options)?;
+
+    if let (Some(path), Some(ram)) = (save_path, gameboy.save_data()) {
+        fs::write(&path, ram).map_err(|error| CliError::Write { path: path.clone(), error })?;
+        info!("Saved {}", path.display());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Run under the command line debugger, stopped before the first instruction. The window
+// shows the frames while the console runs, and P breaks back into the debugger
+fn debug(options: &RunOptions) -> Result<ExitCode, CliError> {
+    let mut gameboy = create_gameboy(options)?;
+    if let Some(slot) = options.load_state {
+        load_state(&mut gameboy, &state_path(options, slot))?;
+    }
+    let mut screen = open_screen(&gameboy, options)?;
+    let mut debugger = Debugger::new();
+    debugger.set_symbols(load_symbols(&options.rom));
+    if gameboy.cpu.call_stack.is_none() {
+        gameboy.track_calls();
+    }
+    let mut pacer = Pacer::new();
+    println!("{}", debugger.location(&gameboy));
+
+    let mut line = String::new();
+    while screen.as_ref().is_none_or(Screen::is_open) {
+        if debugger.is_running() {
+            if let Some(stop) = debugger.run(&mut gameboy) {
+                println!("{}", stop);
+            } else if let Some(screen) = &mut screen {
+                if screen.hotkey_pressed(Hotkey::Pause) {
+                    debugger.interrupt();
+                    println!("{}", debugger.describe(&gameboy, &Stop::Interrupted));
+                }
+                gameboy.set_input(&screen.pressed_buttons());
+                screen.show(gameboy.framebuffer()).map_err(|error| CliError::Window(error.to_string()))?;
+                pacer.wait(options.speed);
+            }
+            continue;
+        }
+
+        if let Some(screen) = &mut screen {
+            screen.show(gameboy.framebuffer()).map_err(|error| CliError::Window(error.to_string()))?;
+        }
+        print!("(gb) ");
+        let _ = io::stdout().flush();
+        line.clear();
+        if !matches!(io::stdin().read_line(&mut line), Ok(length) if length > 0) {
+            break;
+        }
+        match debugger.execute_line(&mut gameboy, &line) {
+            Ok(Reply::Output(output)) => println!("{}", output),
+            Ok(Reply::Resume) => {}
+            Ok(Reply::Quit) => break,
+            Err(message) => println!("{}", message),
+        }
+    }
+
+    if let Some(slot) = options.save_state {
+        save_state(&gameboy, &state_path(options, slot))?;
+    }
+    if let Some(path) = &options.profile {
+        write_profile(&gameboy, path, debugger.symbols())?;
+    }
+    write_cdl(&gameboy, options)?;
+    Ok(ExitCode::SUCCESS)
 }
 
+// Let GDB debug the console, stopped before the first instruction until it continues
+fn gdb(options: &RunOptions, port: u16) -> Result<ExitCode, CliError> {
+    let mut gameboy = create_gameboy(options)?;
+    if let Some(slot) = options.load_state {
+        load_state(&mut gameboy, &state_path(options, slot))?;
+    }
+    let mut screen = open_screen(&gameboy, options)?;
+    let mut stub = GdbStub::listen(port).map_err(CliError::Gdb)?;
+    let mut pacer = Pacer::new();
+
+    while screen.as_ref().is_none_or(Screen::is_open) {
+        if stub.is_running() {
+            stub.run(&mut gameboy).map_err(CliError::Gdb)?;
+            if let Some(screen) = &mut screen {
+                gameboy.set_input(&screen.pressed_buttons());
+                screen.show(gameboy.framebuffer()).map_err(|error| CliError::Window(error.to_string()))?;
+                if stub.is_running() {
+                    pacer.wait(options.speed);
+                }
+            }
+            continue;
+        }
+
+        if let Some(screen) = &mut screen {
+            screen.show(gameboy.framebuffer()).map_err(|error| CliError::Window(error.to_string()))?;
+        }
+        if !stub.serve(&mut gameboy).map_err(CliError::Gdb)? {
+            info!("GDB detached");
+            break;
+        }
+    }
+
+    if let Some(slot) = options.save_state {
+        save_state(&gameboy, &state_path(options, slot))?;
+    }
+    write_cdl(&gameboy, options)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn info(rom: &Path, json: bool) -> Result<ExitCode, CliError> {
+    let (rom_vec, rom_header) = read_rom(rom)?;
+    let info = RomInfo::new(&rom_header, &rom_vec);
+    if json {
+        print!("{}", info.to_json());
+    } else {
+        print!("{}", info.to_text());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Disassembly of the ROM file, addresses are shown as bank:address
+fn disasm(rom: &Path, start: usize, end: Option<usize>, recursive: bool, cdl: Option<&Path>) -> Result<ExitCode, CliError> {
+    let rom_vec = fs::read(rom).map_err(|error| CliError::Read { path: rom.to_path_buf(), error })?;
+    let end = end.unwrap_or(rom_vec.len()).min(rom_vec.len());
+    let lines = if recursive || cdl.is_some() {
+        let mut entry_points = disassembler::ENTRY_POINTS.to_vec();
+        entry_points.push(start);
+        // The code seen running, e.g. reached through jump tables
+        if let Some(path) = cdl {
+            let flags = fs::read(path).map_err(|error| CliError::Read { path: path.to_path_buf(), error })?;
+            entry_points.extend(CodeDataLog::from_bytes(flags).opcodes());
+        }
+        disassembler::recursive_descent(&rom_vec, start, end, &entry_points)
+    } else {
+        disassembler::linear_sweep(&rom_vec, start, end)
+    };
+    let symbols = load_symbols(rom);
+
+    // Stop quietly when the output is closed, e.g. piped to `head`
+    let mut stdout = std::io::stdout().lock();
+    for line in lines {
+        if writeln!(stdout, "{}", line.with_symbols(&symbols)).is_err() {
+            break;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+// Run a test ROM without a window until it reports a result over serial.
+// Blargg's tests print "Passed" or "Failed", Mooneye's send the Fibonacci numbers or 0x42
+fn test(options: &RunOptions, timeout: u32) -> Result<ExitCode, CliError> {
+    let mut gameboy = create_gameboy(options)?;
+    let code = run_test(&mut gameboy, timeout);
+    if let Some(path) = &options.profile {
+        write_profile(&gameboy, path, &load_symbols(&options.rom))?;
+    }
+    write_cdl(&gameboy, options)?;
+    Ok(code)
+}
+
+fn run_test(gameboy: &mut GameBoy, timeout: u32) -> ExitCode {
+    let mut stdout = std::io::stdout();
+    let mut printed = 0;
+    let mut elapsed: u64 = 0;
+    while elapsed < timeout as u64 * CLOCK_SPEED {
+        let (cycles, _) = gameboy.step();
+        elapsed += cycles as u64;
+
+        let output = gameboy.serial_output();
+        if output.len() > printed {
+            print!("{}", String::from_utf8_lossy(&output[printed..]));
+            let _ = stdout.flush();
+            printed = output.len();
+
+            let text = String::from_utf8_lossy(output);
+            if text.contains("Passed") || output.ends_with(&[3, 5, 8, 13, 21, 34]) {
+                println!("\nTest passed");
+                return ExitCode::SUCCESS;
+            }
+            if text.contains("Failed") || output.ends_with(&[0x42; 6]) {
+                println!("\nTest failed");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    if printed > 0 {
+        println!();
+    }
+    println!("Test timed out after {} seconds", timeout);
+    ExitCode::FAILURE
+}
+
+fn read_rom(path: &Path) -> Result<(Vec<u8>, RomHeader), CliError> {
+    info!("Opening file {}", path.display());
+    let rom_vec = fs::read(path).map_err(|error| CliError::Read { path: path.to_path_buf(), error })?;
+    let rom_header = RomHeader::parse(&rom_vec).map_err(|error| CliError::InvalidHeader { path: path.to_path_buf(), error })?;
+    Ok((rom_vec, rom_header))
+}
+
+// Start the console with the options of run and test
+fn create_gameboy(options: &RunOptions) -> Result<GameBoy, CliError> {
+    let (rom_vec, rom_header) = read_rom(&options.rom)?;
+    // A cut or padded file would run into missing banks or the wrong ones
+    let declared = rom_header.rom_size_bytes();
+    if declared != Some(rom_vec.len()) {
+        return Err(CliError::RomSize { path: options.rom.clone(), length: rom_vec.len(), declared });
+    }
+    let boot_rom = match &options.boot_rom {
+        Some(path) => Some(fs::read(path).map_err(|error| CliError::Read { path: path.clone(), error })?),
+        None => None,
+    };
+
+    let config = Config {
+        model: options.model,
+        boot_rom,
+        compat_palette: options.compat_palette,
+        color_correction: options.color_correction,
+        dmg_palette: options.palette,
+    };
+    let mut gameboy = GameBoy::new(&rom_vec, config).map_err(|error| CliError::Load { path: options.rom.clone(), error })?;
+    if let Some(path) = &options.trace {
+        let file = fs::File::create(path).map_err(|error| CliError::Write { path: path.clone(), error })?;
+        gameboy.set_trace(Some(Trace::new(Box::new(BufWriter::new(file)), options.trace_filter)));
+        info!("Tracing to {}", path.display());
+    }
+    if options.profile.is_some() {
+        gameboy.track_calls();
+    }
+    if let Some(path) = &options.cdl {
+        gameboy.set_cdl(Some(read_cdl(path, rom_vec.len())?));
+    }
+    info!("Title: {}", gameboy.header().title());
+    info!("Model: {:?}", gameboy.model());
+    Ok(gameboy)
+}
+
+// The code/data log saved by the runs before, or a new one
+fn read_cdl(path: &Path, rom_length: usize) -> Result<CodeDataLog, CliError> {
+    match fs::read(path) {
+        Ok(flags) if flags.len() == rom_length => Ok(CodeDataLog::from_bytes(flags)),
+        Ok(_) => {
+            warn!("{} is the code/data log of another ROM, starting a new one", path.display());
+            Ok(CodeDataLog::new(rom_length))
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(CodeDataLog::new(rom_length)),
+        Err(error) => Err(CliError::Read { path: path.to_path_buf(), error }),
+    }
+}
+
+fn write_cdl(gameboy: &GameBoy, options: &RunOptions) -> Result<(), CliError> {
+    let (Some(path), Some(cdl)) = (&options.cdl, gameboy.cdl()) else {
+        return Ok(());
+    };
+    fs::write(path, cdl.as_bytes()).map_err(|error| CliError::Write { path: path.clone(), error })?;
+    info!("Saved the code/data log to {}. {}", path.display(), cdl.summary());
+    Ok(())
+}
+
+// Print the cycles of the functions and write the folded stacks for a flamegraph
+fn write_profile(gameboy: &GameBoy, path: &Path, symbols: &Symbols) -> Result<(), CliError> {
+    let Some(call_stack) = &gameboy.cpu.call_stack else {
+        return Ok(());
+    };
+    print!("{}", call_stack::report(&call_stack.profile(), symbols));
+    fs::write(path, call_stack.folded(symbols)).map_err(|error| CliError::Write { path: path.to_path_buf(), error })?;
+    info!("Saved the profile to {}", path.display());
+    Ok(())
+}
+
+// Labels of the RGBDS .sym file next to the ROM, if there is one
+fn load_symbols(rom: &Path) -> Symbols {
+    let path = rom.with_extension("sym");
+    if !path.exists() {
+        return Symbols::default();
+    }
+    match fs::read_to_string(&path) {
+        Ok(text) => {
+            let symbols = Symbols::parse(&text);
+            info!("Loaded {} symbols from {}", symbols.len(), path.display());
+            symbols
+        }
+        Err(error) => {
+            warn!("Couldn't read {}: {}", path.display(), error);
+            Symbols::default()
+        }
+    }
+}
+
+fn open_screen(gameboy: &GameBoy, options: &RunOptions) -> Result<Option<Screen>, CliError> {
+    if options.headless {
+        return Ok(None);
+    }
+    let (width, height) = gameboy.screen_size();
+    let screen = Screen::open(width, height, options.scale).map_err(|error| CliError::Window(error.to_string()))?;
+    Ok(Some(screen))
+}
+
+// Saves go to `--save-dir` or next to the ROM, with the name of the ROM
+fn save_path(options: &RunOptions, extension: &str) -> PathBuf {
+    let directory = match &options.save_dir {
+        Some(directory) => directory.clone(),
+        None => options.rom.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+    let name = options.rom.file_stem().unwrap_or_default().to_string_lossy();
+    directory.join(format!("{}.{}", name, extension))
+}
+
+fn state_path(options: &RunOptions, slot: u8) -> PathBuf {
+    save_path(options, &format!("ss{}", slot))
+}
+
+fn save_state(gameboy: &GameBoy, path: &Path) -> Result<(), CliError> {
+    fs::write(path, gameboy.save_state()).map_err(|error| CliError::Write { path: path.to_path_buf(), error })?;
+    info!("Saved state {}", path.display());
+    Ok(())
+}
+
+fn load_state(gameboy: &mut GameBoy, path: &Path) -> Result<(), CliError> {
+    let state = fs::read(path).map_err(|error| CliError::Read { path: path.to_path_buf(), error })?;
+    gameboy.load_state(&state).map_err(|error| CliError::State { path: path.to_path_buf(), error })?;
+    info!("Loaded state {}", path.display());
+    Ok(())
+}
+
+// Title of the window for the speed changed with the hotkeys
+fn status(speed: f64, paused: bool) -> String {
+    if paused {
+        "Paused".to_string()
+    } else if speed == 0.0 {
+        "Unlimited speed".to_string()
+    } else if speed == 1.0 {
+        String::new()
+    } else {
+        format!("{}%", speed * 100.0)
+    }
+}
+
+fn save_screenshot(gameboy: &GameBoy, path: &Path, scale: usize) -> Result<(), CliError> {
+    let (width, height) = gameboy.screen_size();
+    let image = png::encode(gameboy.framebuffer(), width, height, scale);
+    fs::write(path, image).map_err(|error| CliError::Write { path: path.to_path_buf(), error })?;
+    info!("Saved screenshot {}", path.display());
+    Ok(())
+}
+
+// Screenshots taken with the hotkey go next to the ROM, numbered so none is overwritten
+fn screenshot_path(options: &RunOptions) -> PathBuf {
+    let directory = options.rom.parent().map(Path::to_path_buf).unwrap_or_default();
+    let name = options.rom.file_stem().unwrap_or_default().to_string_lossy();
+    (1..)
+        .map(|number| directory.join(format!("{}-{}.png", name, number)))
+        .find(|path| !path.exists())
+        .unwrap_or_default()
+}
 
 // Tests
 #[cfg(test)]
@@ -86,11 +630,8 @@ mod tests {
     fn test_rom_header_title() {
         let rom_vec = fs::read("roms/tetris.gb").expect("Should have been able to read the file tetris.gb");
 
-        let rom_header = RomHeader::from_vec(&rom_vec);
-        
-        assert_eq!(
-            String::from_utf8(rom_header.title.to_vec()).unwrap().trim_end_matches(char::from(0)),
-            "TETRIS"
-        );
+        let rom_header = RomHeader::parse(&rom_vec).unwrap();
+
+        assert_eq!(rom_header.title(), "TETRIS");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,39 @@
+// Hardware model that is being emulated
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Model {
+    // Original Game Boy
+    Dmg,
+    // Game Boy Pocket, a DMG with a different value in A after boot
+    Mgb,
+    // Game Boy Color
+    Cgb,
+    // Super Game Boy, a DMG with a border and palettes
+    Sgb,
+}
+
+impl Model {
+    // Pick the model from the CGB flag of the ROM header (0x143).
+    // 0x80 means the game supports CGB functions but also works on a DMG,
+    // 0xC0 means the game only works on a CGB. Anything else is a DMG game.
+    pub fn from_cgb_flag(cgb_flag: u8) -> Model {
+        match cgb_flag {
+            0x80 | 0xC0 => Model::Cgb,
+            _ => Model::Dmg,
+        }
+    }
+
+    // Parse the model from the name used on the command line
+    pub fn from_name(name: &str) -> Option<Model> {
+        match name.to_ascii_lowercase().as_str() {
+            "dmg" => Some(Model::Dmg),
+            "mgb" => Some(Model::Mgb),
+            "cgb" => Some(Model::Cgb),
+            "sgb" => Some(Model::Sgb),
+            _ => None,
+        }
+    }
+
+    pub fn is_cgb(&self) -> bool {
+        *self == Model::Cgb
+    }
+}
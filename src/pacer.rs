@@ -0,0 +1,46 @@
+// Keeps the window at the frame rate of the LCD (59.7275 Hz) times the emulation speed
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gameboy_emu::gpu::FRAME_RATE;
+
+// Sleeping is only accurate to about a millisecond, the rest of the wait is spent spinning
+const SPIN_TIME: Duration = Duration::from_millis(1);
+
+// Further behind than this (e.g. after the window was dragged) the lost time is dropped
+// instead of running frames as fast as possible to catch up
+const MAX_LAG_FRAMES: f64 = 4.0;
+
+pub struct Pacer {
+    next_frame: Instant,
+}
+
+impl Pacer {
+    pub fn new() -> Pacer {
+        Pacer { next_frame: Instant::now() }
+    }
+
+    // Wait until the next frame is due at `speed` times the normal speed, 0 doesn't wait at all
+    pub fn wait(&mut self, speed: f64) {
+        let now = Instant::now();
+        if speed <= 0.0 {
+            self.next_frame = now;
+            return;
+        }
+
+        let frame_time = Duration::from_secs_f64(1.0 / (FRAME_RATE * speed));
+        self.next_frame += frame_time;
+        if now > self.next_frame + frame_time.mul_f64(MAX_LAG_FRAMES) {
+            self.next_frame = now;
+            return;
+        }
+
+        if let Some(sleep) = self.next_frame.checked_duration_since(now).and_then(|wait| wait.checked_sub(SPIN_TIME)) {
+            thread::sleep(sleep);
+        }
+        while Instant::now() < self.next_frame {
+            std::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,240 @@
+// Rewinding, a ring buffer of save states taken every few frames while the game runs.
+//
+// Most of a state doesn't change from one snapshot to the next, so snapshots are grouped:
+// the first state of a group is its keyframe, and every snapshot of the group is stored
+// as the XOR with it. The XOR is run length encoded, which squeezes the runs of zeros
+// left by the unchanged bytes down to a few bytes. The keyframe itself is kept run length
+// encoded until the last snapshot of its group is dropped.
+//
+// The buttons of every frame after a snapshot are recorded with it, so rewinding one frame
+// at a time restores the snapshot before it and replays the frames in between
+
+use std::collections::VecDeque;
+
+use crate::gameboy::{button_bits, Button, GameBoy};
+use crate::gpu::FRAME_RATE;
+use crate::save_state::StateError;
+
+// Frames run between two snapshots
+pub const SNAPSHOT_INTERVAL: usize = 4;
+
+// Snapshots in a group before a new keyframe is taken
+const GROUP_SIZE: usize = 32;
+
+struct Snapshot {
+    // Run length encoded XOR of the state with the keyframe of the group
+    data: Vec<u8>,
+    // Buttons held when the snapshot was taken, and for every frame run after it
+    buttons: u8,
+    inputs: Vec<u8>,
+}
+
+struct Group {
+    keyframe: Vec<u8>,
+    snapshots: VecDeque<Snapshot>,
+    // Snapshots taken in the group, including the ones already dropped
+    taken: usize,
+}
+
+pub struct Rewind {
+    groups: VecDeque<Group>,
+    capacity: usize,
+    // Decoded keyframe of the newest group
+    keyframe: Vec<u8>,
+}
+
+impl Rewind {
+    // Keep enough snapshots to go back `seconds`, one more than that since the newest
+    // snapshot is still filling up
+    pub fn new(seconds: f64) -> Rewind {
+        let frames = (seconds * FRAME_RATE).ceil() as usize;
+        Rewind {
+            groups: VecDeque::new(),
+            capacity: frames.div_ceil(SNAPSHOT_INTERVAL) + 1,
+            keyframe: Vec::new(),
+        }
+    }
+
+    fn snapshots(&self) -> impl Iterator<Item = &Snapshot> {
+        self.groups.iter().flat_map(|group| &group.snapshots)
+    }
+
+    // Frames that can be rewound
+    pub fn frames(&self) -> usize {
+        self.snapshots().map(|snapshot| snapshot.inputs.len()).sum()
+    }
+
+    // Bytes used by the snapshots
+    pub fn memory_used(&self) -> usize {
+        let keyframes: usize = self.groups.iter().map(|group| group.keyframe.len()).sum();
+        let snapshots: usize = self.snapshots().map(|snapshot| snapshot.data.len() + snapshot.inputs.len()).sum();
+        keyframes + snapshots + self.keyframe.len()
+    }
+
+    fn newest(&self) -> Option<&Snapshot> {
+        self.groups.back().and_then(|group| group.snapshots.back())
+    }
+
+    fn newest_mut(&mut self) -> Option<&mut Snapshot> {
+        self.groups.back_mut().and_then(|group| group.snapshots.back_mut())
+    }
+
+    // Run a frame with the buttons held, taking a snapshot when it is due
+    pub fn run_frame(&mut self, gameboy: &mut GameBoy, pressed: &[Button]) {
+        if self.newest().is_none_or(|snapshot| snapshot.inputs.len() >= SNAPSHOT_INTERVAL) {
+            self.take_snapshot(gameboy);
+        }
+
+        let buttons = button_bits(pressed);
+        if let Some(snapshot) = self.newest_mut() {
+            snapshot.inputs.push(buttons);
+        }
+        gameboy.cpu.memory.set_buttons(buttons);
+        gameboy.run_frame();
+    }
+
+    // Go back one frame, returns false when there is nothing left to rewind
+    pub fn rewind_frame(&mut self, gameboy: &mut GameBoy) -> Result<bool, StateError> {
+        // The console is at the newest snapshot plus the frames recorded after it
+        while self.newest().is_some_and(|snapshot| snapshot.inputs.is_empty()) {
+            if self.snapshots().nth(1).is_none() {
+                return Ok(false);
+            }
+            self.remove_newest();
+        }
+        let Some(snapshot) = self.newest_mut() else {
+            return Ok(false);
+        };
+        snapshot.inputs.pop();
+
+        let snapshot = self.newest().unwrap();
+        let state = xor(&run_length_decode(&snapshot.data), &self.keyframe);
+        let held = gameboy.cpu.memory.buttons();
+        gameboy.load_state(&state)?;
+        gameboy.cpu.memory.restore_buttons(snapshot.buttons);
+        for &buttons in &snapshot.inputs {
+            gameboy.cpu.memory.set_buttons(buttons);
+            gameboy.run_frame();
+        }
+        gameboy.cpu.memory.restore_buttons(held);
+        Ok(true)
+    }
+
+    fn take_snapshot(&mut self, gameboy: &GameBoy) {
+        let state = gameboy.save_state();
+        if self.groups.back().is_none_or(|group| group.taken >= GROUP_SIZE) {
+            self.groups.push_back(Group {
+                keyframe: run_length_encode(&state),
+                snapshots: VecDeque::new(),
+                taken: 0,
+            });
+            self.keyframe = state.clone();
+        }
+
+        let data = run_length_encode(&xor(&state, &self.keyframe));
+        let group = self.groups.back_mut().unwrap();
+        group.taken += 1;
+        group.snapshots.push_back(Snapshot {
+            data,
+            buttons: gameboy.cpu.memory.buttons(),
+            inputs: Vec::with_capacity(SNAPSHOT_INTERVAL),
+        });
+
+        if self.snapshots().count() > self.capacity {
+            if let Some(oldest) = self.groups.front_mut() {
+                oldest.snapshots.pop_front();
+                if oldest.snapshots.is_empty() {
+                    self.groups.pop_front();
+                }
+            }
+        }
+    }
+
+    // The previous group becomes the newest once all the snapshots of this one are gone
+    fn remove_newest(&mut self) {
+        let Some(group) = self.groups.back_mut() else { return };
+        group.snapshots.pop_back();
+        group.taken -= 1;
+        if group.snapshots.is_empty() {
+            self.groups.pop_back();
+            self.keyframe = self.groups.back().map_or(Vec::new(), |group| run_length_decode(&group.keyframe));
+        }
+    }
+}
+
+// XOR of two states, the result has the length of `state`
+fn xor(state: &[u8], base: &[u8]) -> Vec<u8> {
+    state.iter().enumerate().map(|(i, byte)| byte ^ base.get(i).copied().unwrap_or(0)).collect()
+}
+
+// Runs of a repeated byte are stored as the byte twice followed by the count of further
+// repeats (up to 255), any other byte is stored as it is
+fn run_length_encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() / 4);
+    let mut i = 0;
+    while i < data.len() {
+        let byte = data[i];
+        let run = data[i..].iter().take(257).take_while(|&&other| other == byte).count();
+        if run >= 2 {
+            encoded.extend_from_slice(&[byte, byte, (run - 2) as u8]);
+        } else {
+            encoded.push(byte);
+        }
+        i += run;
+    }
+    encoded
+}
+
+fn run_length_decode(encoded: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(encoded.len() * 4);
+    let mut i = 0;
+    while i < encoded.len() {
+        let byte = encoded[i];
+        if encoded.get(i + 1) == Some(&byte) {
+            let repeats = encoded.get(i + 2).copied().unwrap_or(0) as usize;
+            data.resize(data.len() + 2 + repeats, byte);
+            i += 3;
+        } else {
+            data.push(byte);
+            i += 1;
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::Config;
+
+    #[test]
+    fn test_run_length() {
+        let data = [[1, 2, 2, 3].as_slice(), &[0; 600], &[4, 4]].concat();
+        let encoded = run_length_encode(&data);
+        assert!(encoded.len() < 20);
+        assert_eq!(run_length_decode(&encoded), data);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let rom = std::fs::read("roms/tetris.gb").expect("Should have been able to read the file tetris.gb");
+        let mut gameboy = GameBoy::new(&rom, Config::default()).unwrap();
+        let mut rewind = Rewind::new(1.0);
+
+        let mut states = Vec::new();
+        for frame in 0..130 {
+            states.push(gameboy.save_state());
+            let pressed: &[Button] = if frame % 20 < 5 { &[Button::Start] } else { &[] };
+            rewind.run_frame(&mut gameboy, pressed);
+        }
+        // One second of snapshots is kept
+        assert!(rewind.frames() >= 60 && rewind.frames() < 70);
+
+        for frame in (100..130).rev() {
+            assert!(rewind.rewind_frame(&mut gameboy).unwrap());
+            assert!(gameboy.save_state() == states[frame], "Frame {} differs", frame);
+        }
+        while rewind.rewind_frame(&mut gameboy).unwrap() {}
+        assert_eq!(rewind.frames(), 0);
+    }
+}
@@ -0,0 +1,221 @@
+// Everything the ROM header says decoded in human terms, with the logo and checksums
+// verified. Printed by the info command as text or as JSON for scripts
+
+use crate::rom_reader::{computed_global_checksum, CartridgeType, RomHeader};
+
+pub struct RomInfo {
+    file_size: usize,
+    title: String,
+    manufacturer_code: Option<String>,
+    cartridge_type: u8,
+    cartridge_name: Option<String>,
+    rom_size: u8,
+    rom_bytes: Option<usize>,
+    ram_size: u8,
+    ram_bytes: Option<usize>,
+    cgb_flag: u8,
+    sgb_flag: u8,
+    destination_code: u8,
+    // "0x01" for the old licensee code, the two characters of the new one otherwise
+    licensee_code: String,
+    licensee: Option<&'static str>,
+    version: u8,
+    logo_valid: bool,
+    header_checksum: u8,
+    computed_header_checksum: u8,
+    global_checksum: u16,
+    computed_global_checksum: u16,
+}
+
+impl RomInfo {
+    pub fn new(header: &RomHeader, rom: &[u8]) -> RomInfo {
+        let licensee_code = if header.old_licensee_code == 0x33 {
+            String::from_utf8_lossy(&header.new_licensee_code).into_owned()
+        } else {
+            format!("0x{:02x}", header.old_licensee_code)
+        };
+
+        RomInfo {
+            file_size: rom.len(),
+            title: header.title(),
+            manufacturer_code: header.manufacturer_code(),
+            cartridge_type: header.cartridge_type,
+            cartridge_name: CartridgeType::try_from(header.cartridge_type)
+                .ok()
+                .map(|cartridge_type| format!("{:?}", cartridge_type)),
+            rom_size: header.rom_size,
+            rom_bytes: header.rom_size_bytes(),
+            ram_size: header.ram_size,
+            ram_bytes: header.ram_size_bytes(),
+            cgb_flag: header.cgb_flag,
+            sgb_flag: header.sgb_flag,
+            destination_code: header.destination_code,
+            licensee_code,
+            licensee: header.licensee(),
+            version: header.mask_rom_version_number,
+            logo_valid: header.logo_valid(),
+            header_checksum: header.header_checksum,
+            computed_header_checksum: header.computed_header_checksum(),
+            global_checksum: u16::from_be_bytes(header.global_checksum),
+            computed_global_checksum: computed_global_checksum(rom),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let rom_size = match self.rom_bytes {
+            Some(bytes) => format!("{} KiB, {} banks of 16 KiB", bytes / 1024, bytes / 0x4000),
+            None => "unknown".to_string(),
+        };
+        let ram_size = match self.ram_bytes {
+            Some(0) => "none".to_string(),
+            Some(bytes) => format!("{} KiB, {} banks of 8 KiB", bytes / 1024, bytes.div_ceil(0x2000)),
+            None => "unknown".to_string(),
+        };
+
+        let lines = [
+            ("File size", format!("{} bytes", self.file_size)),
+            ("Title", self.title.clone()),
+            ("Manufacturer code", self.manufacturer_code.clone().unwrap_or_else(|| "none".to_string())),
+            (
+                "Cartridge type",
+                format!("{} (0x{:02x})", self.cartridge_name.as_deref().unwrap_or("unknown"), self.cartridge_type),
+            ),
+            ("ROM size", format!("{} (0x{:02x})", rom_size, self.rom_size)),
+            ("RAM size", format!("{} (0x{:02x})", ram_size, self.ram_size)),
+            ("CGB", format!("{} (0x{:02x})", cgb_description(self.cgb_flag), self.cgb_flag)),
+            ("SGB", format!("{} (0x{:02x})", sgb_description(self.sgb_flag), self.sgb_flag)),
+            ("Destination", format!("{} (0x{:02x})", destination(self.destination_code), self.destination_code)),
+            ("Licensee", format!("{} ({})", self.licensee.unwrap_or("unknown"), self.licensee_code)),
+            ("Version", self.version.to_string()),
+            ("Nintendo logo", verdict(self.logo_valid).to_string()),
+            (
+                "Header checksum",
+                format!(
+                    "0x{:02x} {} (computed 0x{:02x})",
+                    self.header_checksum,
+                    verdict(self.header_checksum == self.computed_header_checksum),
+                    self.computed_header_checksum
+                ),
+            ),
+            (
+                "Global checksum",
+                format!(
+                    "0x{:04x} {} (computed 0x{:04x})",
+                    self.global_checksum,
+                    verdict(self.global_checksum == self.computed_global_checksum),
+                    self.computed_global_checksum
+                ),
+            ),
+        ];
+
+        lines
+            .iter()
+            .map(|(name, value)| format!("{:<18} {}\n", format!("{}:", name), value))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let fields = [
+            ("file_size", self.file_size.to_string()),
+            ("title", json_string(&self.title)),
+            ("manufacturer_code", json_option(self.manufacturer_code.as_deref())),
+            ("cartridge_type", self.cartridge_type.to_string()),
+            ("cartridge_name", json_option(self.cartridge_name.as_deref())),
+            ("rom_size", self.rom_size.to_string()),
+            ("rom_bytes", self.rom_bytes.map_or("null".to_string(), |bytes| bytes.to_string())),
+            ("rom_banks", self.rom_bytes.map_or("null".to_string(), |bytes| (bytes / 0x4000).to_string())),
+            ("ram_size", self.ram_size.to_string()),
+            ("ram_bytes", self.ram_bytes.map_or("null".to_string(), |bytes| bytes.to_string())),
+            ("ram_banks", self.ram_bytes.map_or("null".to_string(), |bytes| bytes.div_ceil(0x2000).to_string())),
+            ("cgb_flag", self.cgb_flag.to_string()),
+            ("cgb", json_string(cgb_description(self.cgb_flag))),
+            ("sgb_flag", self.sgb_flag.to_string()),
+            ("sgb", (self.sgb_flag == 0x03).to_string()),
+            ("destination_code", self.destination_code.to_string()),
+            ("destination", json_string(destination(self.destination_code))),
+            ("licensee_code", json_string(&self.licensee_code)),
+            ("licensee", json_option(self.licensee)),
+            ("version", self.version.to_string()),
+            ("logo_valid", self.logo_valid.to_string()),
+            ("header_checksum", self.header_checksum.to_string()),
+            ("header_checksum_valid", (self.header_checksum == self.computed_header_checksum).to_string()),
+            ("global_checksum", self.global_checksum.to_string()),
+            ("global_checksum_valid", (self.global_checksum == self.computed_global_checksum).to_string()),
+        ];
+
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|(name, value)| format!("  {}: {}", json_string(name), value))
+            .collect();
+        format!("{{\n{}\n}}\n", fields.join(",\n"))
+    }
+}
+
+fn cgb_description(cgb_flag: u8) -> &'static str {
+    match cgb_flag {
+        0x80 => "CGB enhanced, also works on DMG",
+        0xC0 => "CGB only",
+        _ => "DMG",
+    }
+}
+
+fn sgb_description(sgb_flag: u8) -> &'static str {
+    match sgb_flag {
+        0x03 => "SGB functions",
+        _ => "no SGB functions",
+    }
+}
+
+fn destination(destination_code: u8) -> &'static str {
+    match destination_code {
+        0x00 => "Japan",
+        0x01 => "Overseas",
+        _ => "unknown",
+    }
+}
+
+fn verdict(valid: bool) -> &'static str {
+    if valid { "OK" } else { "BAD" }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for character in value.chars() {
+        match character {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            character if (character as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", character as u32)),
+            character => json.push(character),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_option(value: Option<&str>) -> String {
+    value.map_or("null".to_string(), json_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_tetris_info() {
+        let rom = fs::read("roms/tetris.gb").expect("Should have been able to read the file tetris.gb");
+        let info = RomInfo::new(&RomHeader::parse(&rom).unwrap(), &rom);
+
+        assert!(info.logo_valid);
+        assert_eq!(info.header_checksum, info.computed_header_checksum);
+        assert_eq!(info.global_checksum, info.computed_global_checksum);
+        assert_eq!(info.licensee, Some("Nintendo"));
+        assert!(info.to_json().contains("\"title\": \"TETRIS\""));
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\u{1}"), "\"a\\\"b\\\\c\\u0001\"");
+    }
+}
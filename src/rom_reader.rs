@@ -1,7 +1,41 @@
+use crate::licensee::{new_licensee_name, old_licensee_name};
+
+// The boot ROM refuses to start a cartridge without this logo at 0x104
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+// The header ends at 0x14F, a ROM must at least contain it
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    // The file is shorter than the header, holds the length of the file
+    TooSmall(usize),
+    UnknownCartridgeType(u8),
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HeaderError::TooSmall(length) => {
+                write!(f, "the file is {} bytes long but the header ends at 0x{:x}", length, HEADER_END - 1)
+            }
+            HeaderError::UnknownCartridgeType(value) => write!(f, "unknown cartridge type 0x{:02x}", value),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
 // Struct to hold the ROM header
+#[allow(dead_code)]
 pub struct RomHeader {
     pub entry_point: [u8; 4],
     pub nintendo_logo: [u8; 48],
+    // The raw bytes of 0x134 - 0x142, use `title()` to get the title of the layout the game uses
     pub title: [u8; 15],
     pub manufacturer_code: [u8; 4],
     pub cgb_flag: u8,
@@ -18,45 +52,134 @@ pub struct RomHeader {
 }
 
 impl RomHeader {
-    // Function to create a new RomHeader struct from a vector of bytes that containes the ROM
-    pub fn from_vec(vec: &Vec<u8>) -> RomHeader {
-        let mut entry_point = [0; 4];
-        let mut nintendo_logo = [0; 48];
-        let mut title = [0; 15];
-        let mut manufacturer_code = [0; 4];
-        let mut new_licensee_code = [0; 2];
-        let mut global_checksum = [0; 2];
-
-        // Copy the data from the vector to the arrays
-        entry_point.copy_from_slice(&vec[0x100..0x104]);
-        nintendo_logo.copy_from_slice(&vec[0x104..0x134]);
-        title.copy_from_slice(&vec[0x134..0x143]);
-        manufacturer_code.copy_from_slice(&vec[0x13F..0x143]);
-        new_licensee_code.copy_from_slice(&vec[0x144..0x146]);
-        global_checksum.copy_from_slice(&vec[0x14E..0x150]);
-
-        RomHeader {
-            entry_point,
-            nintendo_logo,
-            title,
-            manufacturer_code,
-            cgb_flag: vec[0x143],
-            new_licensee_code,
-            sgb_flag: vec[0x146],
-            cartridge_type: vec[0x147],
-            rom_size: vec[0x148],
-            ram_size: vec[0x149],
-            destination_code: vec[0x14A],
-            old_licensee_code: vec[0x14B],
-            mask_rom_version_number: vec[0x14C],
-            header_checksum: vec[0x14D],
-            global_checksum,
+    // Read the header of a ROM, the unknown values are kept as they are
+    pub fn parse(rom: &[u8]) -> Result<RomHeader, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::TooSmall(rom.len()));
+        }
+
+        let bytes = |start: usize| &rom[start..];
+        Ok(RomHeader {
+            entry_point: array(bytes(0x100)),
+            nintendo_logo: array(bytes(0x104)),
+            title: array(bytes(0x134)),
+            manufacturer_code: array(bytes(0x13F)),
+            cgb_flag: rom[0x143],
+            new_licensee_code: array(bytes(0x144)),
+            sgb_flag: rom[0x146],
+            cartridge_type: rom[0x147],
+            rom_size: rom[0x148],
+            ram_size: rom[0x149],
+            destination_code: rom[0x14A],
+            old_licensee_code: rom[0x14B],
+            mask_rom_version_number: rom[0x14C],
+            header_checksum: rom[0x14D],
+            global_checksum: array(bytes(0x14E)),
+        })
+    }
+
+    // Games before the CGB use 16 bytes for the title, up to 0x143. CGB games use 0x143
+    // for the CGB flag, and newer ones also cut the title to 11 bytes to fit a 4 letter
+    // manufacturer code. The title ends at the first 0
+    pub fn title(&self) -> String {
+        let mut title = self.title.to_vec();
+        if self.cgb_flag & 0x80 == 0 {
+            title.push(self.cgb_flag);
+        } else if self.manufacturer_code().is_some() {
+            title.truncate(11);
+        }
+
+        let end = title.iter().position(|&byte| byte == 0).unwrap_or(title.len());
+        title[..end]
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '?' })
+            .collect()
+    }
+
+    // The manufacturer code of the new layout, 4 uppercase letters or digits in CGB games
+    pub fn manufacturer_code(&self) -> Option<String> {
+        let valid = self.cgb_flag & 0x80 != 0
+            && self.manufacturer_code.iter().all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit());
+        valid.then(|| String::from_utf8_lossy(&self.manufacturer_code).into_owned())
+    }
+
+    // ROM size in bytes, 32 KiB doubled for every step, with a few odd sizes
+    pub fn rom_size_bytes(&self) -> Option<usize> {
+        match self.rom_size {
+            0x00..=0x08 => Some(0x8000 << self.rom_size),
+            0x52 => Some(72 * 0x4000),
+            0x53 => Some(80 * 0x4000),
+            0x54 => Some(96 * 0x4000),
+            _ => None,
+        }
+    }
+
+    // Cartridge RAM size in bytes. 0x01 (2 KiB) was never used by a released game
+    pub fn ram_size_bytes(&self) -> Option<usize> {
+        match self.ram_size {
+            0x00 => Some(0),
+            0x01 => Some(0x800),
+            0x02 => Some(0x2000),
+            0x03 => Some(0x8000),
+            0x04 => Some(0x20000),
+            0x05 => Some(0x10000),
+            _ => None,
+        }
+    }
+
+    // Name of the publisher, from the new licensee code when the old one is 0x33
+    pub fn licensee(&self) -> Option<&'static str> {
+        if self.old_licensee_code == 0x33 {
+            new_licensee_name(&self.new_licensee_code)
+        } else {
+            old_licensee_name(self.old_licensee_code)
         }
     }
+
+    pub fn logo_valid(&self) -> bool {
+        self.nintendo_logo == NINTENDO_LOGO
+    }
+
+    // Checksum of 0x134 - 0x14C as computed by the boot ROM, which locks up if it doesn't match
+    pub fn computed_header_checksum(&self) -> u8 {
+        let rest = [
+            self.cgb_flag,
+            self.new_licensee_code[0],
+            self.new_licensee_code[1],
+            self.sgb_flag,
+            self.cartridge_type,
+            self.rom_size,
+            self.ram_size,
+            self.destination_code,
+            self.old_licensee_code,
+            self.mask_rom_version_number,
+        ];
+        self.title.iter().chain(&rest).fold(0u8, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1))
+    }
+
+    // Cartridges that keep their RAM with a battery, the RAM is saved to a .sav file
+    pub fn has_battery(&self) -> bool {
+        CartridgeType::try_from(self.cartridge_type).is_ok_and(|cartridge_type| cartridge_type.has_battery())
+    }
+
+    // The SGB only accepts commands from games with the SGB flag set to 0x03
+    // and the old licensee code set to 0x33
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+}
+
+// Sum of every byte of the ROM except the global checksum itself, stored big endian at 0x14E.
+// Nothing checks it on real hardware
+pub fn computed_global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|(address, _)| *address != 0x14E && *address != 0x14F)
+        .fold(0u16, |checksum, (_, &byte)| checksum.wrapping_add(byte as u16))
 }
 
 // Enum that contains the cartridge types
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum CartridgeType {
     RomOnly = 0x00,
     Mbc1 = 0x01,
@@ -72,28 +195,108 @@ pub enum CartridgeType {
     Mbc3TimerBattery = 0x0F,
     Mbc3TimerRamBattery = 0x10,
     Mbc3 = 0x11,
-    //TODO: add the rest of the cartridge types
+    Mbc3Ram = 0x12,
+    Mbc3RamBattery = 0x13,
+    Mbc5 = 0x19,
+    Mbc5Ram = 0x1A,
+    Mbc5RamBattery = 0x1B,
+    Mbc5Rumble = 0x1C,
+    Mbc5RumbleRam = 0x1D,
+    Mbc5RumbleRamBattery = 0x1E,
+    Mbc6 = 0x20,
+    Mbc7SensorRumbleRamBattery = 0x22,
+    PocketCamera = 0xFC,
+    BandaiTama5 = 0xFD,
+    HuC3 = 0xFE,
+    HuC1RamBattery = 0xFF,
 }
 
 impl CartridgeType {
-    // Function to convert a u8 to a CartridgeType
-    pub fn from_u8(value: u8) -> Option<CartridgeType> {
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::Mbc1RamBattery
+                | CartridgeType::Mbc2Battery
+                | CartridgeType::RomRamBattery
+                | CartridgeType::Mmm01RamBattery
+                | CartridgeType::Mbc3TimerBattery
+                | CartridgeType::Mbc3TimerRamBattery
+                | CartridgeType::Mbc3RamBattery
+                | CartridgeType::Mbc5RamBattery
+                | CartridgeType::Mbc5RumbleRamBattery
+                | CartridgeType::Mbc7SensorRumbleRamBattery
+                | CartridgeType::HuC1RamBattery
+        )
+    }
+}
+
+impl TryFrom<u8> for CartridgeType {
+    type Error = HeaderError;
+
+    fn try_from(value: u8) -> Result<CartridgeType, HeaderError> {
         match value {
-            0x00 => Some(CartridgeType::RomOnly),
-            0x01 => Some(CartridgeType::Mbc1),
-            0x02 => Some(CartridgeType::Mbc1Ram),
-            0x03 => Some(CartridgeType::Mbc1RamBattery),
-            0x05 => Some(CartridgeType::Mbc2),
-            0x06 => Some(CartridgeType::Mbc2Battery),
-            0x08 => Some(CartridgeType::RomRam),
-            0x09 => Some(CartridgeType::RomRamBattery),
-            0x0B => Some(CartridgeType::Mmm01),
-            0x0C => Some(CartridgeType::Mmm01Ram),
-            0x0D => Some(CartridgeType::Mmm01RamBattery),
-            0x0F => Some(CartridgeType::Mbc3TimerBattery),
-            0x10 => Some(CartridgeType::Mbc3TimerRamBattery),
-            0x11 => Some(CartridgeType::Mbc3),
-            _ => None,
+            0x00 => Ok(CartridgeType::RomOnly),
+            0x01 => Ok(CartridgeType::Mbc1),
+            0x02 => Ok(CartridgeType::Mbc1Ram),
+            0x03 => Ok(CartridgeType::Mbc1RamBattery),
+            0x05 => Ok(CartridgeType::Mbc2),
+            0x06 => Ok(CartridgeType::Mbc2Battery),
+            0x08 => Ok(CartridgeType::RomRam),
+            0x09 => Ok(CartridgeType::RomRamBattery),
+            0x0B => Ok(CartridgeType::Mmm01),
+            0x0C => Ok(CartridgeType::Mmm01Ram),
+            0x0D => Ok(CartridgeType::Mmm01RamBattery),
+            0x0F => Ok(CartridgeType::Mbc3TimerBattery),
+            0x10 => Ok(CartridgeType::Mbc3TimerRamBattery),
+            0x11 => Ok(CartridgeType::Mbc3),
+            0x12 => Ok(CartridgeType::Mbc3Ram),
+            0x13 => Ok(CartridgeType::Mbc3RamBattery),
+            0x19 => Ok(CartridgeType::Mbc5),
+            0x1A => Ok(CartridgeType::Mbc5Ram),
+            0x1B => Ok(CartridgeType::Mbc5RamBattery),
+            0x1C => Ok(CartridgeType::Mbc5Rumble),
+            0x1D => Ok(CartridgeType::Mbc5RumbleRam),
+            0x1E => Ok(CartridgeType::Mbc5RumbleRamBattery),
+            0x20 => Ok(CartridgeType::Mbc6),
+            0x22 => Ok(CartridgeType::Mbc7SensorRumbleRamBattery),
+            0xFC => Ok(CartridgeType::PocketCamera),
+            0xFD => Ok(CartridgeType::BandaiTama5),
+            0xFE => Ok(CartridgeType::HuC3),
+            0xFF => Ok(CartridgeType::HuC1RamBattery),
+            _ => Err(HeaderError::UnknownCartridgeType(value)),
         }
     }
 }
+
+// Copy the first N bytes of a slice into an array
+fn array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut array = [0; N];
+    array.copy_from_slice(&bytes[..N]);
+    array
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(RomHeader::parse(&[0; 0x14F]).err(), Some(HeaderError::TooSmall(0x14F)));
+        assert_eq!(CartridgeType::try_from(0x04), Err(HeaderError::UnknownCartridgeType(0x04)));
+        assert_eq!(CartridgeType::try_from(0x1B), Ok(CartridgeType::Mbc5RamBattery));
+    }
+
+    #[test]
+    fn test_title_layouts() {
+        let mut rom = vec![0; 0x150];
+        rom[0x134..0x144].copy_from_slice(b"SIXTEEN LETTERS!");
+        assert_eq!(RomHeader::parse(&rom).unwrap().title(), "SIXTEEN LETTERS!");
+
+        // CGB game with a manufacturer code after an 11 letter title
+        rom[0x134..0x143].copy_from_slice(b"POKEMON_SLVAAXE");
+        rom[0x143] = 0x80;
+        let header = RomHeader::parse(&rom).unwrap();
+        assert_eq!(header.title(), "POKEMON_SLV");
+        assert_eq!(header.manufacturer_code().as_deref(), Some("AAXE"));
+    }
+}
@@ -0,0 +1,248 @@
+// Save states, a snapshot of the whole console in a versioned binary format.
+//
+// All numbers are little endian. The file starts with a header:
+//
+//   offset  size  field
+//   0       4     magic "GBST"
+//   4       2     format version, `VERSION`
+//   6       1     model: 0 DMG, 1 MGB, 2 CGB, 3 SGB
+//   7       2     global checksum of the ROM, as stored in the header (0x14E - 0x14F)
+//   9       1     length of the title
+//   10      n     title of the ROM
+//
+// followed by the state of every part in this order: CPU registers and flags, memory
+// (including the CGB banks and palettes, DMA and the boot ROM), LCD, and the SGB when
+// present. The LCD ends with the frame inside the border on the SGB only. Byte arrays of variable length are preceded by their length as a u32.
+//
+// A state is only loaded by the same ROM on the same model. States written by a newer
+// version, or cut short, fail with a `StateError` and leave the console untouched.
+// When the format changes `VERSION` goes up, and the loader keeps reading the older
+// versions it can still make sense of
+
+use std::fmt;
+
+use crate::model::Model;
+
+const MAGIC: &[u8; 4] = b"GBST";
+
+// Version 1 is the first format, version 2 leaves out the SGB frame on the other models
+pub const VERSION: u16 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    // The state was saved by another game, holds its title
+    WrongRom(String),
+    WrongModel(Model),
+    Truncated,
+    // A value that can't be in a state written by this emulator
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "the save state has version {}, this build reads up to version {}",
+                version, VERSION
+            ),
+            StateError::WrongRom(title) => write!(f, "the save state belongs to another game ({})", title),
+            StateError::WrongModel(model) => write!(f, "the save state was made on another model ({:?})", model),
+            StateError::Truncated => write!(f, "the save state is cut short"),
+            StateError::Corrupt(what) => write!(f, "the save state is corrupt: invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// What identifies the game and console a state belongs to
+#[derive(Debug, PartialEq)]
+pub struct StateHeader {
+    pub version: u16,
+    pub model: Model,
+    pub global_checksum: [u8; 2],
+    pub title: String,
+}
+
+impl StateHeader {
+    pub fn write(&self, state: &mut StateWriter) {
+        state.bytes(MAGIC);
+        state.u16(self.version);
+        state.u8(model_to_u8(self.model));
+        state.bytes(&self.global_checksum);
+        let title = &self.title.as_bytes()[..self.title.len().min(0xFF)];
+        state.u8(title.len() as u8);
+        state.bytes(title);
+    }
+
+    pub fn read(state: &mut StateReader) -> Result<StateHeader, StateError> {
+        let mut magic = [0; 4];
+        state.bytes(&mut magic).map_err(|_| StateError::NotAState)?;
+        if &magic != MAGIC {
+            return Err(StateError::NotAState);
+        }
+
+        let version = state.u16()?;
+        if version == 0 || version > VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let model = model_from_u8(state.u8()?).ok_or(StateError::Corrupt("model"))?;
+        let mut global_checksum = [0; 2];
+        state.bytes(&mut global_checksum)?;
+        let mut title = vec![0; state.u8()? as usize];
+        state.bytes(&mut title)?;
+
+        Ok(StateHeader {
+            version,
+            model,
+            global_checksum,
+            title: String::from_utf8_lossy(&title).into_owned(),
+        })
+    }
+}
+
+fn model_to_u8(model: Model) -> u8 {
+    match model {
+        Model::Dmg => 0,
+        Model::Mgb => 1,
+        Model::Cgb => 2,
+        Model::Sgb => 3,
+    }
+}
+
+fn model_from_u8(value: u8) -> Option<Model> {
+    match value {
+        0 => Some(Model::Dmg),
+        1 => Some(Model::Mgb),
+        2 => Some(Model::Cgb),
+        3 => Some(Model::Sgb),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Bytes of a size known to the reader
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // Bytes preceded by their length
+    pub fn vec(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+}
+
+#[derive(Clone)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, position: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position == self.data.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        let bytes = self.data.get(self.position..self.position + length).ok_or(StateError::Truncated)?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt("flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes([self.u8()?, self.u8()?]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+
+    pub fn vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> StateHeader {
+        StateHeader {
+            version: VERSION,
+            model: Model::Cgb,
+            global_checksum: [0x12, 0x34],
+            title: "TETRIS".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let mut writer = StateWriter::new();
+        header().write(&mut writer);
+        let bytes = writer.into_bytes();
+        assert_eq!(StateHeader::read(&mut StateReader::new(&bytes)), Ok(header()));
+
+        assert_eq!(StateHeader::read(&mut StateReader::new(b"PNG")), Err(StateError::NotAState));
+        let mut newer = bytes.clone();
+        newer[4] = 0xFF;
+        assert_eq!(StateHeader::read(&mut StateReader::new(&newer)), Err(StateError::UnsupportedVersion(0x00FF)));
+        assert_eq!(StateHeader::read(&mut StateReader::new(&bytes[..12])), Err(StateError::Truncated));
+    }
+}
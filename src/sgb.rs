@@ -0,0 +1,557 @@
+// Super Game Boy support.
+// The game talks to the SGB by sending 16 byte packets bit by bit through P1,
+// the SGB colors the 160x144 LCD output with 4 palettes picked per 8x8 cell and
+// draws a 256x224 border around it.
+
+use crate::gpu::{bgr555_to_rgb, ColorCorrection};
+use crate::save_state::{StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 224;
+
+// Position of the game screen inside the border
+const GAME_X: usize = 48;
+const GAME_Y: usize = 40;
+const GAME_WIDTH: usize = 160;
+const GAME_HEIGHT: usize = 144;
+
+// The game screen is split in 20x18 cells of 8x8 pixels for the palettes
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+
+// Command codes, stored in the upper 5 bits of the first byte of a command
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+// Colors of the SGB before the game sets its own palettes
+const DEFAULT_PALETTE: [u16; 4] = [0x639E, 0x263A, 0x10D4, 0x2866];
+
+// What MASK_EN shows in place of the game screen
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mask {
+    None,
+    Freeze,
+    Black,
+    Color0,
+}
+
+pub struct Sgb {
+    // The SGB only listens to games that declare SGB support in the header
+    commands_enabled: bool,
+
+    // Packet reception through P1
+    previous_p1: u8,
+    receiving: bool,
+    bit_index: usize,
+    packet: [u8; 16],
+    packets: Vec<[u8; 16]>,
+
+    // MLT_REQ, number of joypads and the one currently selected
+    players: u8,
+    current_player: u8,
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attributes: [u8; CELLS_X * CELLS_Y],
+    mask: Mask,
+    // LCD output shown on screen, kept while the mask freezes the screen
+    shown_shades: Vec<u8>,
+
+    // Border tiles in SNES 4 bits per pixel format, the tile map and palettes 4-7
+    border_tiles: Vec<u8>,
+    border_map: [u16; 32 * 28],
+    border_palettes: [[u16; 16]; 4],
+}
+
+impl Sgb {
+    pub fn new(commands_enabled: bool) -> Sgb {
+        Sgb {
+            commands_enabled,
+            previous_p1: 0x30,
+            receiving: false,
+            bit_index: 0,
+            packet: [0; 16],
+            packets: Vec::new(),
+            players: 1,
+            current_player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![[0; 4]; 512],
+            attributes: [0; CELLS_X * CELLS_Y],
+            mask: Mask::None,
+            shown_shades: vec![0; GAME_WIDTH * GAME_HEIGHT],
+            border_tiles: vec![0; 0x2000],
+            border_map: [0; 32 * 28],
+            border_palettes: [[0; 16]; 4],
+        }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.commands_enabled);
+        state.u8(self.previous_p1);
+        state.bool(self.receiving);
+        state.u8(self.bit_index as u8);
+        state.bytes(&self.packet);
+        state.u8(self.packets.len() as u8);
+        for packet in &self.packets {
+            state.bytes(packet);
+        }
+        state.u8(self.players);
+        state.u8(self.current_player);
+        for color in self.palettes.as_flattened() {
+            state.u16(*color);
+        }
+        for color in self.system_palettes.as_flattened() {
+            state.u16(*color);
+        }
+        state.bytes(&self.attributes);
+        state.u8(self.mask as u8);
+        state.bytes(&self.shown_shades);
+        state.bytes(&self.border_tiles);
+        for entry in &self.border_map {
+            state.u16(*entry);
+        }
+        for color in self.border_palettes.as_flattened() {
+            state.u16(*color);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.commands_enabled = state.bool()?;
+        self.previous_p1 = state.u8()?;
+        self.receiving = state.bool()?;
+        self.bit_index = state.u8()? as usize;
+        state.bytes(&mut self.packet)?;
+        let packets = state.u8()?;
+        if self.bit_index > 128 || packets > 7 {
+            return Err(StateError::Corrupt("SGB packet"));
+        }
+        self.packets = vec![[0; 16]; packets as usize];
+        for packet in &mut self.packets {
+            state.bytes(packet)?;
+        }
+        self.players = state.u8()?;
+        self.current_player = state.u8()?;
+        if !(1..=4).contains(&self.players) || self.current_player >= self.players {
+            return Err(StateError::Corrupt("SGB players"));
+        }
+        for color in self.palettes.as_flattened_mut() {
+            *color = state.u16()?;
+        }
+        for color in self.system_palettes.as_flattened_mut() {
+            *color = state.u16()?;
+        }
+        state.bytes(&mut self.attributes)?;
+        self.mask = match state.u8()? {
+            0 => Mask::None,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            _ => return Err(StateError::Corrupt("SGB mask")),
+        };
+        state.bytes(&mut self.shown_shades)?;
+        state.bytes(&mut self.border_tiles)?;
+        for entry in &mut self.border_map {
+            *entry = state.u16()?;
+        }
+        for color in self.border_palettes.as_flattened_mut() {
+            *color = state.u16()?;
+        }
+        Ok(())
+    }
+
+    // Low nibble of P1 when no button group is selected, 0xF for player 1, 0xE for player 2...
+    pub fn joypad_id(&self) -> u8 {
+        0x0F - self.current_player
+    }
+
+    // Handle a write to P1. Bits 4 and 5 both low start a packet, then every
+    // bit is sent as a low pulse on P15 (1) or P14 (0) followed by both high.
+    // `vram` and `lcdc` are needed by the commands that transfer data through VRAM
+    pub fn write_p1(&mut self, value: u8, vram: &[u8], lcdc: u8) {
+        let select = value & 0x30;
+
+        match select {
+            0x00 => {
+                self.receiving = true;
+                self.bit_index = 0;
+                self.packet = [0; 16];
+            }
+            // The next joypad is selected every time P15 goes back high
+            0x30 if self.players > 1 && self.previous_p1 & 0x20 == 0 => {
+                self.current_player = (self.current_player + 1) % self.players;
+            }
+            0x30 => {}
+            _ if self.receiving && self.previous_p1 == 0x30 => {
+                let bit = select == 0x10;
+                if self.bit_index < 128 {
+                    self.packet[self.bit_index / 8] |= (bit as u8) << (self.bit_index % 8);
+                    self.bit_index += 1;
+                } else {
+                    // Stop bit, the packet is complete
+                    self.receiving = false;
+                    self.receive_packet(vram, lcdc);
+                }
+            }
+            _ => {}
+        }
+
+        self.previous_p1 = select;
+    }
+
+    fn receive_packet(&mut self, vram: &[u8], lcdc: u8) {
+        self.packets.push(self.packet);
+
+        // The first byte of a command holds the number of packets in the lower 3 bits
+        let length = (self.packets[0][0] & 0x07).max(1) as usize;
+        if self.packets.len() < length {
+            return;
+        }
+
+        let data: Vec<u8> = self.packets.drain(..).flatten().collect();
+        if self.commands_enabled {
+            self.execute(&data, vram, lcdc);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8], vram: &[u8], lcdc: u8) {
+        let command = data[0] >> 3;
+        debug!("SGB command 0x{:02x}", command);
+        match command {
+            PAL01 => self.set_palettes(data, 0, 1),
+            PAL23 => self.set_palettes(data, 2, 3),
+            PAL03 => self.set_palettes(data, 0, 3),
+            PAL12 => self.set_palettes(data, 1, 2),
+            ATTR_BLK => self.attribute_blocks(data),
+            ATTR_LIN => self.attribute_lines(data),
+            ATTR_DIV => self.attribute_division(data),
+            ATTR_CHR => self.attribute_characters(data),
+            PAL_SET => {
+                for i in 0..4 {
+                    let index = (data[1 + i * 2] as usize | (data[2 + i * 2] as usize) << 8) & 0x1FF;
+                    self.palettes[i] = self.system_palettes[index];
+                }
+                self.share_color_0(self.palettes[0][0]);
+                if data[9] & 0x40 != 0 {
+                    self.mask = Mask::None;
+                }
+            }
+            PAL_TRN => {
+                let transfer = vram_transfer(vram, lcdc);
+                for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                    *palette = read_colors(&transfer[i * 8..]);
+                }
+            }
+            MLT_REQ => {
+                self.players = match data[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.current_player = 0;
+            }
+            CHR_TRN => {
+                let offset = (data[1] & 0x01) as usize * 0x1000;
+                self.border_tiles[offset..offset + 0x1000].copy_from_slice(&vram_transfer(vram, lcdc));
+            }
+            PCT_TRN => {
+                let transfer = vram_transfer(vram, lcdc);
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = transfer[i * 2] as u16 | (transfer[i * 2 + 1] as u16) << 8;
+                }
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    *palette = read_colors(&transfer[0x800 + i * 32..]);
+                }
+            }
+            MASK_EN => {
+                self.mask = match data[1] & 0x03 {
+                    0x01 => Mask::Freeze,
+                    0x02 => Mask::Black,
+                    0x03 => Mask::Color0,
+                    _ => Mask::None,
+                };
+            }
+            _ => warn!("Unsupported SGB command: 0x{:02x}", command),
+        }
+    }
+
+    // PALxx: color 0 shared by all palettes, then colors 1-3 of both palettes
+    fn set_palettes(&mut self, data: &[u8], first: usize, second: usize) {
+        let colors = read_colors::<7>(&data[1..]);
+        self.palettes[first][1..].copy_from_slice(&colors[1..4]);
+        self.palettes[second][1..].copy_from_slice(&colors[4..7]);
+        self.share_color_0(colors[0]);
+    }
+
+    fn share_color_0(&mut self, color: u16) {
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color;
+        }
+    }
+
+    // ATTR_BLK: up to 18 rectangles, with a palette for the inside, the border and the outside
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let count = (data[1] & 0x1F) as usize;
+        for block in data[2..].chunks_exact(6).take(count) {
+            let control = block[0] & 0x07;
+            let inside_palette = block[1] & 0x03;
+            let border_palette = (block[1] >> 2) & 0x03;
+            let outside_palette = (block[1] >> 4) & 0x03;
+            let (x1, y1, x2, y2) = (
+                (block[2] & 0x1F) as usize,
+                (block[3] & 0x1F) as usize,
+                (block[4] & 0x1F) as usize,
+                (block[5] & 0x1F) as usize,
+            );
+
+            // Changing only the inside or only the outside changes the border with it
+            let border = match control {
+                0x01 => Some(inside_palette),
+                0x04 => Some(outside_palette),
+                _ if control & 0x02 != 0 => Some(border_palette),
+                _ => None,
+            };
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let inside_box = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let on_border = inside_box && (x == x1 || x == x2 || y == y1 || y == y2);
+                    let palette = if on_border {
+                        border
+                    } else if inside_box {
+                        Some(inside_palette).filter(|_| control & 0x01 != 0)
+                    } else {
+                        Some(outside_palette).filter(|_| control & 0x04 != 0)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * CELLS_X + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    // ATTR_LIN: whole rows or columns of cells
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for &line in data[2..].iter().take(count) {
+            let number = (line & 0x1F) as usize;
+            let palette = (line >> 5) & 0x03;
+            if line & 0x80 != 0 {
+                if number < CELLS_Y {
+                    self.attributes[number * CELLS_X..(number + 1) * CELLS_X].fill(palette);
+                }
+            } else if number < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + number] = palette;
+                }
+            }
+        }
+    }
+
+    // ATTR_DIV: split the screen in two at a row or column, with a third palette for the line itself
+    fn attribute_division(&mut self, data: &[u8]) {
+        let after = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on_line = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let coordinate = (data[2] & 0x1F) as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * CELLS_X + x] = match position.cmp(&coordinate) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    // ATTR_CHR: a palette per cell starting at a cell, 4 cells per byte
+    fn attribute_characters(&mut self, data: &[u8]) {
+        let mut x = (data[1] & 0x1F) as usize;
+        let mut y = (data[2] & 0x1F) as usize;
+        let count = data[3] as usize | (data[4] as usize) << 8;
+        let top_to_bottom = data[5] & 0x01 != 0;
+
+        for n in 0..count.min(CELLS_X * CELLS_Y) {
+            let Some(byte) = data.get(6 + n / 4) else { break };
+            if x >= CELLS_X || y >= CELLS_Y {
+                break;
+            }
+            self.attributes[y * CELLS_X + x] = (byte >> (6 - (n % 4) * 2)) & 0x03;
+
+            if top_to_bottom {
+                y += 1;
+                if y == CELLS_Y {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x == CELLS_X {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+
+    // Draw the colored game screen and the border. `shades` is the LCD output, 0-3 per pixel
+    pub fn render(&mut self, shades: &[u8], frame: &mut [u32]) {
+        if self.mask != Mask::Freeze {
+            self.shown_shades.copy_from_slice(shades);
+        }
+        let rgb = |color: u16| bgr555_to_rgb(color, ColorCorrection::Off);
+        let backdrop = self.palettes[0][0];
+
+        for y in 0..GAME_HEIGHT {
+            for x in 0..GAME_WIDTH {
+                let color = match self.mask {
+                    Mask::Black => 0x0000,
+                    Mask::Color0 => backdrop,
+                    Mask::None | Mask::Freeze => {
+                        let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                        self.palettes[palette][self.shown_shades[y * GAME_WIDTH + x] as usize]
+                    }
+                };
+                frame[(y + GAME_Y) * SCREEN_WIDTH + x + GAME_X] = rgb(color);
+            }
+        }
+
+        // The border is drawn over the game screen, its color 0 is transparent
+        for (i, entry) in self.border_map.iter().enumerate() {
+            let tile = (entry & 0xFF) as usize;
+            let palette = &self.border_palettes[((entry >> 10) & 0x03) as usize];
+            let tile_data = &self.border_tiles[tile * 32..tile * 32 + 32];
+
+            for row in 0..8 {
+                for column in 0..8 {
+                    let x = (i % 32) * 8 + column;
+                    let y = (i / 32) * 8 + row;
+                    let row = if entry & 0x8000 != 0 { 7 - row } else { row };
+                    let column = if entry & 0x4000 != 0 { 7 - column } else { column };
+
+                    let planes = [tile_data[row * 2], tile_data[row * 2 + 1], tile_data[16 + row * 2], tile_data[17 + row * 2]];
+                    let color = planes
+                        .iter()
+                        .enumerate()
+                        .fold(0, |color, (plane, bits)| color | ((bits >> (7 - column)) & 0x01) << plane);
+
+                    let in_game = (GAME_X..GAME_X + GAME_WIDTH).contains(&x) && (GAME_Y..GAME_Y + GAME_HEIGHT).contains(&y);
+                    if color != 0 {
+                        frame[y * SCREEN_WIDTH + x] = rgb(palette[color as usize]);
+                    } else if !in_game {
+                        frame[y * SCREEN_WIDTH + x] = rgb(backdrop);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Read N little endian BGR555 colors
+fn read_colors<const N: usize>(data: &[u8]) -> [u16; N] {
+    let mut colors = [0; N];
+    for (i, color) in colors.iter_mut().enumerate() {
+        *color = data[i * 2] as u16 | (data[i * 2 + 1] as u16) << 8;
+    }
+    colors
+}
+
+// The SGB reads 4 KiB from the LCD output of the next frame. Games show tiles 0 to 255
+// in order on the background, so the data is the tile data of the first 256 tiles of the map
+fn vram_transfer(vram: &[u8], lcdc: u8) -> Vec<u8> {
+    let map_base = if lcdc & 0x08 != 0 { 0x1C00 } else { 0x1800 };
+    let mut data = Vec::with_capacity(0x1000);
+    for i in 0..256 {
+        let tile_index = vram[map_base + (i / 20) * 32 + i % 20];
+        let tile_address = if lcdc & 0x10 != 0 {
+            tile_index as usize * 16
+        } else {
+            (0x1000 + tile_index as i8 as i32 * 16) as usize
+        };
+        data.extend_from_slice(&vram[tile_address..tile_address + 16]);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(sgb: &mut Sgb, packet: &[u8; 16]) {
+        let vram = [0; 0x2000];
+        sgb.write_p1(0x00, &vram, 0x91);
+        sgb.write_p1(0x30, &vram, 0x91);
+        for bit in 0..128 {
+            let one = (packet[bit / 8] >> (bit % 8)) & 0x01 != 0;
+            sgb.write_p1(if one { 0x10 } else { 0x20 }, &vram, 0x91);
+            sgb.write_p1(0x30, &vram, 0x91);
+        }
+        sgb.write_p1(0x20, &vram, 0x91);
+        sgb.write_p1(0x30, &vram, 0x91);
+    }
+
+    #[test]
+    fn test_pal01() {
+        let mut sgb = Sgb::new(true);
+        let mut packet = [0; 16];
+        packet[0] = (PAL01 << 3) | 1;
+        for i in 0..7 {
+            packet[1 + i * 2] = i as u8 + 1;
+        }
+        send_packet(&mut sgb, &packet);
+
+        assert_eq!(sgb.palettes[0], [1, 2, 3, 4]);
+        assert_eq!(sgb.palettes[1], [1, 5, 6, 7]);
+        assert_eq!(sgb.palettes[3][0], 1);
+
+        // Games without SGB support in the header are ignored
+        let mut sgb = Sgb::new(false);
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.palettes[0], DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_mlt_req() {
+        let mut sgb = Sgb::new(true);
+        let mut packet = [0; 16];
+        packet[0] = (MLT_REQ << 3) | 1;
+        packet[1] = 0x01;
+        send_packet(&mut sgb, &packet);
+        assert_eq!(sgb.joypad_id(), 0x0F);
+
+        let vram = [0; 0x2000];
+        sgb.write_p1(0x10, &vram, 0x91);
+        sgb.write_p1(0x30, &vram, 0x91);
+        assert_eq!(sgb.joypad_id(), 0x0E);
+    }
+
+    #[test]
+    fn test_attr_div() {
+        let mut sgb = Sgb::new(true);
+        let mut packet = [0; 16];
+        packet[0] = (ATTR_DIV << 3) | 1;
+        packet[1] = 0x40 | (2 << 4) | (1 << 2) | 3;
+        packet[2] = 5;
+        send_packet(&mut sgb, &packet);
+
+        assert_eq!(sgb.attributes[4 * CELLS_X], 1);
+        assert_eq!(sgb.attributes[5 * CELLS_X + 7], 2);
+        assert_eq!(sgb.attributes[17 * CELLS_X + 19], 3);
+    }
+}
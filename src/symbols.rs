@@ -0,0 +1,138 @@
+// Labels of RGBDS .sym files, one `bank:address name` per line like `01:4000 Main.loop`,
+// with comments after a ;. The bank only tells the ROM banks mapped at 0x4000 - 0x7FFF
+// apart, labels elsewhere are found by their address alone
+
+use std::collections::{BTreeMap, HashMap};
+
+const BANK_SIZE: u16 = 0x4000;
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    // Names by address and bank, the first label at an address is the one shown
+    names: BTreeMap<(u16, u16), String>,
+    locations: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    // Lines that aren't labels are skipped with a warning
+    pub fn parse(text: &str) -> Symbols {
+        let mut symbols = Symbols::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let location = line.split_once(char::is_whitespace).and_then(|(location, name)| {
+                let (bank, address) = location.split_once(':')?;
+                let bank = u16::from_str_radix(bank, 16).ok()?;
+                Some((bank, u16::from_str_radix(address, 16).ok()?, name.trim()))
+            });
+            match location {
+                Some((bank, address, name)) => symbols.insert(bank, address, name),
+                None => warn!("Skipped line {} of the symbols: {}", number + 1, line),
+            }
+        }
+        symbols
+    }
+
+    fn insert(&mut self, bank: u16, address: u16, name: &str) {
+        self.names.entry((address, bank)).or_insert_with(|| name.to_string());
+        self.locations.entry(name.to_string()).or_insert((bank, address));
+    }
+
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    // Bank and address of a label
+    pub fn location(&self, name: &str) -> Option<(u16, u16)> {
+        self.locations.get(name).copied()
+    }
+
+    // The label at the address, with `bank` mapped at 0x4000 - 0x7FFF
+    pub fn name(&self, bank: u16, address: u16) -> Option<&str> {
+        if is_switchable(address) {
+            return self.names.get(&(address, bank.max(1))).map(String::as_str);
+        }
+        let mut names = self.names.range((address, 0)..=(address, u16::MAX));
+        names.next().map(|(_, name)| name.as_str())
+    }
+
+    // The label at the address or, in ROM, the one before it with the distance, like `Main+$3`
+    pub fn label(&self, bank: u16, address: u16) -> Option<String> {
+        if let Some(name) = self.name(bank, address) {
+            return Some(name.to_string());
+        }
+        if address >= 2 * BANK_SIZE {
+            return None;
+        }
+        let start = address / BANK_SIZE * BANK_SIZE;
+        let mut before = self.names.range((start, 0)..(address, 0)).rev();
+        let ((label_address, _), name) = before.find(|((_, label_bank), _)| {
+            !is_switchable(address) || *label_bank == bank.max(1)
+        })?;
+        Some(format!("{}+${:x}", name, address - label_address))
+    }
+
+    // Replace the addresses of an instruction that have a label, `jp $0150` becomes `jp Main`
+    pub fn annotate(&self, asm: &str, bank: u16) -> String {
+        if self.is_empty() {
+            return asm.to_string();
+        }
+        let mut annotated = String::new();
+        let mut rest = asm;
+        while let Some(position) = rest.find('$') {
+            annotated.push_str(&rest[..position]);
+            let digits = &rest[position + 1..];
+            let length = digits.chars().take_while(char::is_ascii_hexdigit).count();
+            let name = (length == 4)
+                .then(|| u16::from_str_radix(&digits[..4], 16).ok())
+                .flatten()
+                .and_then(|address| self.name(bank, address));
+            match name {
+                Some(name) => annotated.push_str(name),
+                None => annotated.push_str(&rest[position..position + 1 + length]),
+            }
+            rest = &digits[length..];
+        }
+        annotated.push_str(rest);
+        annotated
+    }
+}
+
+fn is_switchable(address: u16) -> bool {
+    (BANK_SIZE..2 * BANK_SIZE).contains(&address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbols() {
+        let symbols = Symbols::parse(
+            "; File generated by rgblink\n\
+             00:0150 Main\n\
+             00:0153 Main.loop\n\
+             01:4000 Bank1Code\n\
+             02:4000 Bank2Code\n\
+             00:c000 wScore\n\
+             oops\n",
+        );
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.location("Main.loop"), Some((0, 0x153)));
+        assert_eq!(symbols.name(0, 0x4000), Some("Bank1Code"));
+        assert_eq!(symbols.name(2, 0x4000), Some("Bank2Code"));
+        assert_eq!(symbols.name(5, 0xC000), Some("wScore"));
+        assert_eq!(symbols.label(1, 0x155).as_deref(), Some("Main.loop+$2"));
+        assert_eq!(symbols.label(2, 0x4010).as_deref(), Some("Bank2Code+$10"));
+        assert_eq!(symbols.label(1, 0xC001), None);
+        assert_eq!(symbols.annotate("jr nz, $0153", 1), "jr nz, Main.loop");
+        assert_eq!(symbols.annotate("ld [$c000], a", 1), "ld [wScore], a");
+        assert_eq!(symbols.annotate("ldh [$ff44], a", 1), "ldh [$ff44], a");
+    }
+}
@@ -0,0 +1,116 @@
+// Execution trace in the format of Gameboy Doctor, to compare the CPU with reference logs.
+// One line per instruction, before it runs:
+// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+// The reference logs were made with LY reading 0x90, so the console stubs it while tracing
+
+use std::io::{self, Write};
+
+use crate::cpu::memory::Memory;
+use crate::cpu::registers::Registers;
+
+// What LY reads while tracing
+pub const DOCTOR_LY: u8 = 0x90;
+
+// Which instructions are logged, by the range of their address and the ROM bank they are in.
+// Code outside of the ROM doesn't have a bank and isn't logged when one is given
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceFilter {
+    pub start: u16,
+    pub end: u16,
+    pub bank: Option<u16>,
+}
+
+impl Default for TraceFilter {
+    fn default() -> TraceFilter {
+        TraceFilter { start: 0, end: 0xFFFF, bank: None }
+    }
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: u16, rom_bank: u16) -> bool {
+        let bank = match pc {
+            0x0000..=0x3FFF => Some(0),
+            0x4000..=0x7FFF => Some(rom_bank),
+            _ => None,
+        };
+        (self.start..=self.end).contains(&pc) && self.bank.is_none_or(|wanted| bank == Some(wanted))
+    }
+}
+
+pub struct Trace {
+    writer: Box<dyn Write + Send>,
+    filter: TraceFilter,
+}
+
+impl Trace {
+    pub fn new(writer: Box<dyn Write + Send>, filter: TraceFilter) -> Trace {
+        Trace { writer, filter }
+    }
+
+    pub(crate) fn log(&mut self, registers: &Registers, memory: &Memory) -> io::Result<()> {
+        if !self.filter.matches(registers.pc, memory.rom_bank()) {
+            return Ok(());
+        }
+        writeln!(self.writer, "{}", doctor_line(registers, memory))
+    }
+}
+
+pub fn doctor_line(registers: &Registers, memory: &Memory) -> String {
+    let pc = registers.pc;
+    let pcmem: Vec<String> = (0..4).map(|i| format!("{:02X}", memory.peek(pc.wrapping_add(i)))).collect();
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+        registers.a,
+        registers.af() as u8,
+        registers.b,
+        registers.c,
+        registers.d,
+        registers.e,
+        registers.h,
+        registers.l,
+        registers.sp,
+        pc,
+        pcmem.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::GameBoy;
+    use std::sync::{Arc, Mutex};
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut gameboy = GameBoy::from_program("ldh a, [$44]\n loop: jr loop");
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let filter = TraceFilter { start: 0x100, end: 0x101, bank: Some(0) };
+        gameboy.set_trace(Some(Trace::new(Box::new(Shared(output.clone())), filter)));
+
+        for _ in 0..3 {
+            gameboy.step();
+        }
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output,
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:F0,44,18,FE\n"
+        );
+        assert_eq!(gameboy.cpu.registers.a, DOCTOR_LY);
+
+        assert!(TraceFilter { bank: Some(1), ..TraceFilter::default() }.matches(0x4000, 1));
+        assert!(!TraceFilter { bank: Some(2), ..TraceFilter::default() }.matches(0x4000, 1));
+        assert!(!TraceFilter { bank: Some(0), ..TraceFilter::default() }.matches(0xC000, 1));
+    }
+}
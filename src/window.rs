@@ -0,0 +1,188 @@
+// The minifb window of the frontend, shows the frames and reads the keyboard
+
+use minifb::{Key, KeyRepeat, MouseMode, Scale, Window, WindowOptions};
+
+use gameboy_emu::gpu;
+use gameboy_emu::Button;
+
+const TITLE: &str = "Gameboy Emulator";
+const TILES_TITLE: &str = "VRAM Tiles";
+
+// Keys of the emulator itself, acted on once per press
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Hotkey {
+    Screenshot,
+    Pause,
+    FrameAdvance,
+    SlowDown,
+    SpeedUp,
+    ResetSpeed,
+    SaveState,
+    LoadState,
+    SelectSlot(u8),
+}
+
+const HOTKEYS: [(Key, Hotkey); 17] = [
+    (Key::F12, Hotkey::Screenshot),
+    (Key::P, Hotkey::Pause),
+    (Key::N, Hotkey::FrameAdvance),
+    (Key::Minus, Hotkey::SlowDown),
+    (Key::Equal, Hotkey::SpeedUp),
+    (Key::Key0, Hotkey::ResetSpeed),
+    (Key::F5, Hotkey::SaveState),
+    (Key::F8, Hotkey::LoadState),
+    (Key::Key1, Hotkey::SelectSlot(1)),
+    (Key::Key2, Hotkey::SelectSlot(2)),
+    (Key::Key3, Hotkey::SelectSlot(3)),
+    (Key::Key4, Hotkey::SelectSlot(4)),
+    (Key::Key5, Hotkey::SelectSlot(5)),
+    (Key::Key6, Hotkey::SelectSlot(6)),
+    (Key::Key7, Hotkey::SelectSlot(7)),
+    (Key::Key8, Hotkey::SelectSlot(8)),
+    (Key::Key9, Hotkey::SelectSlot(9)),
+];
+
+// Held down to fast-forward or rewind
+const FAST_FORWARD_KEY: Key = Key::Tab;
+const REWIND_KEY: Key = Key::R;
+
+// Arrows for the D-pad, Z and X for A and B, Enter and Backspace for Start and Select
+const KEYS: [(Key, Button); 8] = [
+    (Key::Right, Button::Right),
+    (Key::Left, Button::Left),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::Z, Button::A),
+    (Key::X, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+];
+
+pub struct Screen {
+    window: Window,
+    width: usize,
+    height: usize,
+}
+
+impl Screen {
+    // Open a window for frames of `width` x `height` scaled by 1, 2, 4, 8, 16 or 32 times.
+    // Showing a frame doesn't wait, the frontend paces the frames itself
+    pub fn open(width: usize, height: usize, scale: u8) -> Result<Screen, minifb::Error> {
+        let window = open_window(TITLE, width, height, scale)?;
+        Ok(Screen { window, width, height })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    pub fn pressed_buttons(&self) -> Vec<Button> {
+        KEYS.iter()
+            .filter(|(key, _)| self.window.is_key_down(*key))
+            .map(|&(_, button)| button)
+            .collect()
+    }
+
+    // True once per press of the key
+    pub fn hotkey_pressed(&self, hotkey: Hotkey) -> bool {
+        HOTKEYS
+            .iter()
+            .any(|&(key, bound)| bound == hotkey && self.window.is_key_pressed(key, KeyRepeat::No))
+    }
+
+    // Slot picked with the number keys since the last call
+    pub fn selected_slot(&self) -> Option<u8> {
+        HOTKEYS.iter().find_map(|&(key, hotkey)| match hotkey {
+            Hotkey::SelectSlot(slot) if self.window.is_key_pressed(key, KeyRepeat::No) => Some(slot),
+            _ => None,
+        })
+    }
+
+    pub fn fast_forward_held(&self) -> bool {
+        self.window.is_key_down(FAST_FORWARD_KEY)
+    }
+
+    pub fn rewind_held(&self) -> bool {
+        self.window.is_key_down(REWIND_KEY)
+    }
+
+    // Shown in the title after the name, e.g. the speed or that the emulation is paused
+    pub fn set_status(&mut self, status: &str) {
+        if status.is_empty() {
+            self.window.set_title(TITLE);
+        } else {
+            self.window.set_title(&format!("{} - {}", TITLE, status));
+        }
+    }
+
+    pub fn show(&mut self, frame: &[u32]) -> Result<(), minifb::Error> {
+        self.window.update_with_buffer(frame, self.width, self.height)
+    }
+}
+
+// Second window showing the tile data in VRAM, with the tile under the mouse in the title
+pub struct TileViewer {
+    window: Window,
+    width: usize,
+    height: usize,
+    hovered: Option<(u8, usize)>,
+}
+
+impl TileViewer {
+    pub fn open(width: usize, height: usize, scale: u8) -> Result<TileViewer, minifb::Error> {
+        let window = open_window(TILES_TITLE, width, height, scale)?;
+        Ok(TileViewer { window, width, height, hovered: None })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.is_open()
+    }
+
+    // Show `gpu::render_tile_data()`, the title follows the mouse
+    pub fn show(&mut self, tiles: &[u32]) -> Result<(), minifb::Error> {
+        let hovered = self
+            .window
+            .get_mouse_pos(MouseMode::Discard)
+            .and_then(|(x, y)| gpu::tile_at(x as usize, y as usize));
+        if hovered != self.hovered {
+            self.hovered = hovered;
+            match hovered {
+                Some((bank, tile)) => self.window.set_title(&format!(
+                    "{} - Tile {} (${:03x}) at ${:04x}, bank {}",
+                    TILES_TITLE,
+                    tile,
+                    tile,
+                    0x8000 + tile * 16,
+                    bank
+                )),
+                None => self.window.set_title(TILES_TITLE),
+            }
+        }
+        self.window.update_with_buffer(tiles, self.width, self.height)
+    }
+}
+
+// Scaled by 1, 2, 4, 8, 16 or 32 times, updated without waiting
+fn open_window(title: &str, width: usize, height: usize, scale: u8) -> Result<Window, minifb::Error> {
+    let scale = match scale {
+        1 => Scale::X1,
+        2 => Scale::X2,
+        8 => Scale::X8,
+        16 => Scale::X16,
+        32 => Scale::X32,
+        _ => Scale::X4,
+    };
+
+    let mut window = Window::new(
+        title,
+        width,
+        height,
+        WindowOptions {
+            resize: false,
+            scale,
+            ..WindowOptions::default()
+        },
+    )?;
+    window.limit_update_rate(None);
+    Ok(window)
+}
@@ -0,0 +1,80 @@
+// Video recording as YUV4MPEG2 (.y4m), an uncompressed format most players and encoders
+// read. Frames are stored in 4:4:4 so no color is lost to subsampling, at the exact frame
+// rate of the LCD (4194304 / 70224 Hz, about 59.73)
+
+use std::io::{self, Write};
+
+use crate::gpu::FRAME_DOTS;
+
+pub struct VideoWriter<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+    frames: u64,
+}
+
+impl<W: Write> VideoWriter<W> {
+    pub fn new(mut writer: W, width: usize, height: usize) -> io::Result<VideoWriter<W>> {
+        writeln!(
+            writer,
+            "YUV4MPEG2 W{} H{} F4194304:{} Ip A1:1 C444 XCOLORRANGE=FULL",
+            width, height, FRAME_DOTS
+        )?;
+        Ok(VideoWriter { writer, width, height, frames: 0 })
+    }
+
+    // Append a 0RGB frame, which has to be the size given to `new`
+    pub fn write_frame(&mut self, frame: &[u32]) -> io::Result<()> {
+        let pixels = &frame[..self.width * self.height];
+        let mut planes = vec![0; pixels.len() * 3];
+        for (i, &pixel) in pixels.iter().enumerate() {
+            let (y, u, v) = rgb_to_yuv(pixel);
+            planes[i] = y;
+            planes[pixels.len() + i] = u;
+            planes[pixels.len() * 2 + i] = v;
+        }
+
+        self.writer.write_all(b"FRAME\n")?;
+        self.writer.write_all(&planes)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Full range BT.601, the usual conversion of JPEG
+fn rgb_to_yuv(pixel: u32) -> (u8, u8, u8) {
+    let r = ((pixel >> 16) & 0xFF) as f32;
+    let g = ((pixel >> 8) & 0xFF) as f32;
+    let b = (pixel & 0xFF) as f32;
+
+    let y = 0.299 * r + 0.587 * g + 0.114 * b;
+    let u = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+    let v = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+    (y.round().clamp(0.0, 255.0) as u8, u.round().clamp(0.0, 255.0) as u8, v.round().clamp(0.0, 255.0) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_frame() {
+        let mut video = VideoWriter::new(Vec::new(), 2, 1).unwrap();
+        video.write_frame(&[0x00FFFFFF, 0x00000000]).unwrap();
+        assert_eq!(video.frames(), 1);
+
+        let bytes = video.finish().unwrap();
+        let header = b"YUV4MPEG2 W2 H1 F4194304:70224 Ip A1:1 C444 XCOLORRANGE=FULL\nFRAME\n";
+        assert_eq!(bytes[..header.len()], header[..]);
+        assert_eq!(bytes[header.len()..], [255, 0, 128, 128, 128, 128]);
+    }
+}